@@ -0,0 +1,326 @@
+// Runs strategies over candle history against an emulated broker.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::pnl::{CostMethod, PnlCalculator, PnlReport};
+use crate::rest_client::{RestClient, Result, SandboxEmulator};
+use crate::*;
+
+/// Account value after the strategy has seen the candles of one timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquityPoint {
+    pub time: DateTime<Utc>,
+    /// Cash plus securities at their last close, in the backtest currency.
+    pub equity: Decimal,
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestReport {
+    pub account_id: String,
+    pub equity: Vec<EquityPoint>,
+    /// Executed `Buy` and `Sell` operations in time order.
+    pub trades: Vec<Operation>,
+    /// All operations of the run, including the trades.
+    pub operations: Vec<Operation>,
+    pub portfolio: Portfolio,
+    /// Limit orders still active at the end of the run.
+    pub orders: Orders,
+    pub pnl: PnlReport,
+}
+
+impl BacktestReport {
+    pub fn final_equity(&self) -> Option<Decimal> {
+        self.equity.last().map(|p| p.equity)
+    }
+
+    /// Largest drop from a running peak, as a fraction of the peak.
+    pub fn max_drawdown(&self) -> Decimal {
+        let mut peak = Decimal::ZERO;
+        let mut drawdown = Decimal::ZERO;
+        for point in &self.equity {
+            peak = peak.max(point.equity);
+            if peak > Decimal::ZERO {
+                drawdown = drawdown.max((peak - point.equity) / peak);
+            }
+        }
+        drawdown
+    }
+}
+
+/// Replays candle series through a `SandboxEmulator` and calls a strategy
+/// after each candle with a `BrokerApi`, so the strategy code is the same as
+/// when trading live.
+///
+/// Each candle moves the emulated price from open through low and high
+/// (high first on a falling candle) to close, which fills the resting limit
+/// orders it crosses at their limit price. The strategy then sees the candle,
+/// and its market orders fill at the close with the configured slippage.
+/// `candles` requests return history up to and including the current candle,
+/// and operations are stamped with the candle time.
+pub struct Backtester {
+    emulator: SandboxEmulator,
+    currency: Currency,
+    cash: Decimal,
+    candles: Vec<Candle>,
+}
+
+impl Backtester {
+    pub fn new() -> Self {
+        Self {
+            emulator: SandboxEmulator::new(),
+            currency: Currency::Rub,
+            cash: Decimal::ZERO,
+            candles: vec![],
+        }
+    }
+
+    /// Starting cash, and the currency the equity curve is measured in.
+    /// Defaults to zero rubles.
+    pub fn cash(mut self, currency: Currency, amount: Decimal) -> Self {
+        self.currency = currency;
+        self.cash = amount;
+        self
+    }
+
+    /// Broker commission as a fraction of the trade value.
+    pub fn commission(self, rate: Decimal) -> Self {
+        self.emulator.set_commission(rate);
+        self
+    }
+
+    /// Price move against market orders as a fraction of the price.
+    pub fn slippage(self, rate: Decimal) -> Self {
+        self.emulator.set_slippage(rate);
+        self
+    }
+
+    /// Every instrument the candles are for must be added.
+    pub fn instrument(self, instrument: Instrument) -> Self {
+        self.emulator.add_instrument(instrument);
+        self
+    }
+
+    /// Adds a candle series, e.g. from `RestClient::candles_range` or a `CandleCache`.
+    pub fn candles(mut self, candles: Vec<Candle>) -> Self {
+        self.candles.extend(candles);
+        self
+    }
+
+    /// Reads candles saved as a JSON array, e.g. with `serde_json::to_writer`.
+    pub fn load_candles<P: AsRef<Path>>(path: P) -> io::Result<Vec<Candle>> {
+        let content = fs::read(path)?;
+        serde_json::from_slice(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Runs `strategy` over all candles in time order. It gets the broker, the
+    /// backtest account id and the candle that just closed.
+    pub fn run<F>(mut self, mut strategy: F) -> Result<BacktestReport>
+    where
+        F: FnMut(&dyn BrokerApi, &str, &Candle),
+    {
+        let client = self.emulator.client();
+        let account_id = client.sandbox_register()?.id;
+        client.sandbox_set_currency_balance(&account_id, self.currency.clone(), self.cash)?;
+
+        self.candles.sort_by_key(|c| c.ts);
+        let mut last_prices = HashMap::new();
+        let mut equity = vec![];
+        let mut start = 0;
+        while start < self.candles.len() {
+            let time = self.candles[start].ts;
+            let end = start + self.candles[start..].iter().take_while(|c| c.ts == time).count();
+            let step = &self.candles[start..end];
+            self.emulator.set_time(time);
+            for candle in step {
+                for price in price_path(candle).iter() {
+                    self.emulator.set_price(&candle.figi, *price);
+                }
+                last_prices.insert(candle.figi.clone(), candle.close_price);
+            }
+            self.emulator.add_candles(step.to_vec());
+            for candle in step {
+                strategy(&client, &account_id, candle);
+            }
+            equity.push(EquityPoint {
+                time,
+                equity: self.equity(&client, &account_id, &last_prices)?,
+            });
+            start = end;
+        }
+
+        let operations = self.operations(&client, &account_id)?;
+        let trades = operations
+            .iter()
+            .filter(|o| o.operation_type == OperationType::Buy || o.operation_type == OperationType::Sell)
+            .cloned()
+            .collect();
+        // the emulator books commissions only inside the trades
+        let pnl = PnlCalculator::new(CostMethod::Fifo).trade_commission(true).replay(&operations);
+        Ok(BacktestReport {
+            portfolio: client.portfolio(&account_id)?,
+            orders: client.orders(&account_id)?,
+            account_id,
+            equity,
+            trades,
+            operations,
+            pnl,
+        })
+    }
+
+    fn equity(&self, client: &RestClient, account_id: &str, last_prices: &HashMap<String, Decimal>) -> Result<Decimal> {
+        let portfolio = client.portfolio(account_id)?;
+        let cash: Decimal = portfolio
+            .currencies
+            .currencies
+            .iter()
+            .filter(|c| c.currency == self.currency)
+            .map(|c| c.balance)
+            .sum();
+        let securities: Decimal = portfolio
+            .positions
+            .positions
+            .iter()
+            .filter(|p| p.average_position_price.as_ref().is_none_or(|a| a.currency == self.currency))
+            .map(|p| p.balance * last_prices.get(&p.figi).copied().unwrap_or_default())
+            .sum();
+        Ok(cash + securities)
+    }
+
+    fn operations(&self, client: &RestClient, account_id: &str) -> Result<Vec<Operation>> {
+        let (from, to) = match (self.candles.first(), self.candles.last()) {
+            (Some(first), Some(last)) => (first.ts, last.ts + Duration::seconds(1)),
+            _ => return Ok(vec![]),
+        };
+        let mut operations = client.operations(account_id, from, to, "")?.operations;
+        operations.sort_by_key(|o| o.date_time);
+        Ok(operations)
+    }
+}
+
+impl Default for Backtester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Prices a candle goes through: a rising candle is assumed to touch its low
+// before its high, a falling one the other way round.
+fn price_path(candle: &Candle) -> [Decimal; 4] {
+    if candle.close_price >= candle.open_price {
+        [candle.open_price, candle.low_price, candle.high_price, candle.close_price]
+    } else {
+        [candle.open_price, candle.high_price, candle.low_price, candle.close_price]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn instrument() -> Instrument {
+        Instrument {
+            figi: "FIGI".to_string(),
+            ticker: "TICK".to_string(),
+            isin: String::new(),
+            name: "Stock".to_string(),
+            min_price_increment: dec("0.01"),
+            lot: 1,
+            currency: Currency::Rub,
+            r#type: InstrumentType::Stock,
+        }
+    }
+
+    fn candle(day: u32, open: i64, high: i64, low: i64, close: i64) -> Candle {
+        Candle {
+            figi: "FIGI".to_string(),
+            interval: CandleInterval::Day1,
+            open_price: Decimal::from(open),
+            close_price: Decimal::from(close),
+            high_price: Decimal::from(high),
+            low_price: Decimal::from(low),
+            volume: 1000.0,
+            ts: format!("2021-03-{:02}T07:00:00Z", day).parse().unwrap(),
+        }
+    }
+
+    fn candles() -> Vec<Candle> {
+        // out of order on purpose
+        vec![
+            candle(3, 98, 110, 97, 108),
+            candle(1, 100, 105, 95, 100),
+            candle(2, 100, 102, 90, 98),
+        ]
+    }
+
+    fn backtester() -> Backtester {
+        Backtester::new().cash(Currency::Rub, dec("10000")).instrument(instrument()).candles(candles())
+    }
+
+    #[test]
+    fn limit_orders_fill_on_a_later_candle_at_the_limit() {
+        let report = backtester()
+            .run(|broker, account_id, candle| {
+                if candle.ts == candle_time(1) {
+                    broker.limit_order(account_id, "FIGI", 10, OperationType::Buy, dec("92")).unwrap();
+                }
+            })
+            .unwrap();
+        assert_eq!(report.trades.len(), 1);
+        assert_eq!(report.trades[0].price, dec("92"));
+        assert_eq!(report.trades[0].date_time, candle_time(2));
+        let equity: Vec<Decimal> = report.equity.iter().map(|p| p.equity).collect();
+        assert_eq!(equity, vec![dec("10000"), dec("10060"), dec("10160")]);
+        assert_eq!(report.max_drawdown(), Decimal::ZERO);
+        assert!(report.orders.orders.is_empty());
+    }
+
+    #[test]
+    fn market_orders_fill_at_the_close_with_costs() {
+        let report = backtester()
+            .commission(dec("0.001"))
+            .slippage(dec("0.01"))
+            .run(|broker, account_id, candle| {
+                if candle.ts == candle_time(1) {
+                    broker.market_order(account_id, "FIGI", 10, OperationType::Buy).unwrap();
+                } else if candle.ts == candle_time(3) {
+                    broker.market_order(account_id, "FIGI", 10, OperationType::Sell).unwrap();
+                }
+            })
+            .unwrap();
+        let prices: Vec<Decimal> = report.trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![dec("101"), dec("106.92")]);
+        // 10000 - 1010 - 1.01 commission, then 1069.20 - 1.07 back
+        assert_eq!(report.final_equity(), Some(dec("10057.12")));
+        assert_eq!(report.equity[1].equity, dec("9968.99"));
+        assert_eq!(report.max_drawdown(), dec("20") / dec("9988.99"));
+        assert_eq!(report.pnl.positions["FIGI"].realized, dec("57.12"));
+        assert!(report.portfolio.positions.positions.is_empty());
+    }
+
+    #[test]
+    fn strategy_sees_history_up_to_the_current_candle() {
+        let mut seen = vec![];
+        backtester()
+            .run(|broker, _, candle| {
+                let from = candle_time(1) - Duration::days(30);
+                let history = broker.candles(from, from + Duration::days(60), CandleInterval::Day1, "FIGI").unwrap();
+                assert_eq!(history.last().unwrap().ts, candle.ts);
+                seen.push(history.len());
+            })
+            .unwrap();
+        assert_eq!(seen, vec![1, 2, 3]);
+    }
+
+    fn candle_time(day: u32) -> DateTime<Utc> {
+        candle(day, 0, 0, 0, 0).ts
+    }
+}
@@ -0,0 +1,476 @@
+// Command-line access to the OpenAPI through RestClient, for ad-hoc checks.
+
+mod table;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Parser, Subcommand};
+use invest_openapi_rs_sdk::rest_client::RestClient;
+use invest_openapi_rs_sdk::*;
+use serde::{Deserialize, Serialize};
+
+use table::Table;
+
+const TOKEN_VAR: &str = "TINKOFF_TOKEN";
+const SANDBOX_TOKEN_VAR: &str = "TINKOFF_SANDBOX_TOKEN";
+
+/// Tinkoff Invest OpenAPI from the command line.
+///
+/// The token is read from TINKOFF_TOKEN (TINKOFF_SANDBOX_TOKEN with --sandbox)
+/// or from the config file, a JSON object with "token" and "sandbox_token".
+#[derive(Parser)]
+#[command(name = "invest")]
+struct Cli {
+    /// Use the sandbox API
+    #[arg(long, global = true)]
+    sandbox: bool,
+    /// Print the API responses as JSON instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+    /// Broker account id, the default account if omitted
+    #[arg(long, global = true, default_value = "")]
+    account: String,
+    /// Config file, ~/.config/invest/config.json by default
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List broker accounts
+    Accounts,
+    /// Show positions and currency balances
+    Portfolio,
+    /// Show currency balances
+    Currencies,
+    /// List active orders
+    Orders,
+    /// Place a limit order
+    LimitOrder {
+        figi: String,
+        /// buy or sell
+        #[arg(value_parser = parse_operation)]
+        operation: OperationType,
+        lots: i64,
+        price: Decimal,
+    },
+    /// Place a market order
+    MarketOrder {
+        figi: String,
+        /// buy or sell
+        #[arg(value_parser = parse_operation)]
+        operation: OperationType,
+        lots: i64,
+    },
+    /// Cancel an active order
+    Cancel { order_id: String },
+    /// List operations over a date range
+    Operations {
+        /// Start, as YYYY-MM-DD or RFC 3339
+        #[arg(long, value_parser = parse_time)]
+        from: DateTime<Utc>,
+        /// End, exclusive; a YYYY-MM-DD date includes that whole day. Now if omitted
+        #[arg(long, value_parser = parse_end_time)]
+        to: Option<DateTime<Utc>>,
+        /// Only operations of this instrument
+        #[arg(long, default_value = "")]
+        figi: String,
+    },
+    /// Find instruments by figi or ticker
+    Instrument {
+        #[arg(long, required_unless_present = "ticker", conflicts_with = "ticker")]
+        figi: Option<String>,
+        #[arg(long)]
+        ticker: Option<String>,
+    },
+    /// Show candles of an instrument
+    Candles {
+        figi: String,
+        /// Start, as YYYY-MM-DD or RFC 3339
+        #[arg(long, value_parser = parse_time)]
+        from: DateTime<Utc>,
+        /// End, exclusive; a YYYY-MM-DD date includes that whole day. Now if omitted
+        #[arg(long, value_parser = parse_end_time)]
+        to: Option<DateTime<Utc>>,
+        /// 1min, 2min, 3min, 5min, 10min, 15min, 30min, hour, 2hour, 4hour, day, week or month
+        #[arg(long, default_value = "day")]
+        interval: String,
+    },
+    /// Show the order book of an instrument
+    Orderbook {
+        figi: String,
+        #[arg(long, default_value_t = 10)]
+        depth: i64,
+    },
+}
+
+#[derive(Default, Deserialize)]
+struct Config {
+    token: Option<String>,
+    sandbox_token: Option<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let client = client(&cli)?;
+    let account = cli.account.as_str();
+    let json = cli.json;
+
+    match &cli.command {
+        Command::Accounts => {
+            let accounts = client.accounts()?;
+            output(json, &accounts, |accounts| {
+                let mut table = Table::new(&["ID", "TYPE"]);
+                for account in &accounts.accounts {
+                    table.row(vec![account.id.clone(), account.r#type.to_string()]);
+                }
+                table.print();
+            })?;
+        }
+        Command::Portfolio => {
+            let portfolio = client.portfolio(account)?;
+            output(json, &portfolio, |portfolio| {
+                print_positions(&portfolio.positions);
+                println!();
+                print_currencies(&portfolio.currencies);
+            })?;
+        }
+        Command::Currencies => {
+            let currencies = client.currencies_portfolio(account)?;
+            output(json, &currencies, print_currencies)?;
+        }
+        Command::Orders => {
+            let orders = client.orders(account)?;
+            output(json, &orders, |orders| {
+                let mut table = Table::new(&["ID", "FIGI", "OPERATION", "STATUS", "TYPE", "PRICE", "REQUESTED", "EXECUTED"]);
+                for order in &orders.orders {
+                    table.row(vec![
+                        order.id.clone(),
+                        order.figi.clone(),
+                        order.operation.to_string(),
+                        order.status.to_string(),
+                        order.r#type.to_string(),
+                        order.price.to_string(),
+                        order.requested_lots.to_string(),
+                        order.executed_lots.to_string(),
+                    ]);
+                }
+                table.print();
+            })?;
+        }
+        Command::LimitOrder {
+            figi,
+            operation,
+            lots,
+            price,
+        } => {
+            let placed = client.limit_order(account, figi, *lots, operation.clone(), *price)?;
+            output(json, &placed, print_placed)?;
+        }
+        Command::MarketOrder { figi, operation, lots } => {
+            let placed = client.market_order(account, figi, *lots, operation.clone())?;
+            output(json, &placed, print_placed)?;
+        }
+        Command::Cancel { order_id } => {
+            client.order_cancel(account, order_id)?;
+            output(json, &serde_json::json!({ "orderId": order_id }), |_| {
+                println!("order {} cancelled", order_id)
+            })?;
+        }
+        Command::Operations { from, to, figi } => {
+            let operations = client.operations(account, *from, to.unwrap_or_else(Utc::now), figi)?;
+            output(json, &operations, |operations| {
+                let mut table = Table::new(&[
+                    "DATE", "TYPE", "STATUS", "FIGI", "QUANTITY", "PRICE", "PAYMENT", "COMMISSION", "CURRENCY",
+                ]);
+                for operation in &operations.operations {
+                    table.row(vec![
+                        operation.date_time.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        operation.operation_type.to_string(),
+                        operation.status.to_string(),
+                        operation.figi.clone(),
+                        operation.quantity_executed.to_string(),
+                        operation.price.to_string(),
+                        operation.payment.to_string(),
+                        operation.commission.as_ref().map(|c| c.value.to_string()).unwrap_or_default(),
+                        operation.currency.to_string(),
+                    ]);
+                }
+                table.print();
+            })?;
+        }
+        Command::Instrument { figi, ticker } => match (figi, ticker) {
+            (Some(figi), _) => {
+                let instrument = client.instrument_by_figi(figi)?;
+                output(json, &instrument, |instrument| print_instruments(std::slice::from_ref(instrument)))?;
+            }
+            (None, Some(ticker)) => {
+                let instruments = client.instrument_by_ticker(ticker)?;
+                output(json, &instruments, |instruments| print_instruments(&instruments.instruments))?;
+            }
+            (None, None) => unreachable!("clap requires --figi or --ticker"),
+        },
+        Command::Candles {
+            figi,
+            from,
+            to,
+            interval,
+        } => {
+            let interval = CandleInterval::from(interval.as_str());
+            if let CandleInterval::Unknown(name) = &interval {
+                return Err(anyhow!("unknown candle interval {}", name));
+            }
+            let candles = client.candles_range(*from, to.unwrap_or_else(Utc::now), interval, figi)?;
+            output(json, &candles, |candles| {
+                let mut table = Table::new(&["TIME", "OPEN", "HIGH", "LOW", "CLOSE", "VOLUME"]);
+                for candle in candles {
+                    table.row(vec![
+                        candle.ts.format("%Y-%m-%d %H:%M").to_string(),
+                        candle.open_price.to_string(),
+                        candle.high_price.to_string(),
+                        candle.low_price.to_string(),
+                        candle.close_price.to_string(),
+                        candle.volume.to_string(),
+                    ]);
+                }
+                table.print();
+            })?;
+        }
+        Command::Orderbook { figi, depth } => {
+            let book = client.orderbook(*depth, figi)?;
+            output(json, &book, |book| {
+                println!(
+                    "{}  {}  last {}  close {}",
+                    book.figi, book.trade_status, book.last_price, book.close_price
+                );
+                let mut table = Table::new(&["BID QTY", "BID", "ASK", "ASK QTY"]);
+                for i in 0..book.bids.len().max(book.asks.len()) {
+                    let bid = book.bids.get(i);
+                    let ask = book.asks.get(i);
+                    table.row(vec![
+                        bid.map(|l| l.quantity.to_string()).unwrap_or_default(),
+                        bid.map(|l| l.price.to_string()).unwrap_or_default(),
+                        ask.map(|l| l.price.to_string()).unwrap_or_default(),
+                        ask.map(|l| l.quantity.to_string()).unwrap_or_default(),
+                    ]);
+                }
+                table.print();
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn client(cli: &Cli) -> anyhow::Result<RestClient> {
+    let builder = RestClient::builder(token(cli)?);
+    let builder = if cli.sandbox { builder.sandbox() } else { builder };
+    Ok(builder.build()?)
+}
+
+// The environment variable wins over the config file.
+fn token(cli: &Cli) -> anyhow::Result<String> {
+    let var = if cli.sandbox { SANDBOX_TOKEN_VAR } else { TOKEN_VAR };
+    match env::var(var) {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => {
+            let config = config(cli)?;
+            let token = if cli.sandbox { config.sandbox_token } else { config.token };
+            token.ok_or_else(|| anyhow!("no token: set {} or add it to the config file", var))
+        }
+    }
+}
+
+// A missing default config file is not an error, a missing explicit one is.
+fn config(cli: &Cli) -> anyhow::Result<Config> {
+    let path = match &cli.config {
+        Some(path) => path.clone(),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config/invest/config.json"),
+            None => return Ok(Config::default()),
+        },
+    };
+    let content = match fs::read(&path) {
+        Ok(content) => content,
+        Err(_) if cli.config.is_none() => return Ok(Config::default()),
+        Err(error) => return Err(error).with_context(|| format!("cannot read {}", path.display())),
+    };
+    serde_json::from_slice(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+fn output<T: Serialize, F: FnOnce(&T)>(json: bool, value: &T, table: F) -> anyhow::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        table(value);
+    }
+    Ok(())
+}
+
+fn print_positions(positions: &PositionBalances) {
+    let mut table = Table::new(&["FIGI", "TICKER", "TYPE", "BALANCE", "BLOCKED", "LOTS", "AVG PRICE", "YIELD", "NAME"]);
+    let money = |amount: &Option<MoneyAmount>| {
+        amount
+            .as_ref()
+            .map(|a| format!("{} {}", a.value, a.currency))
+            .unwrap_or_default()
+    };
+    for position in &positions.positions {
+        table.row(vec![
+            position.figi.clone(),
+            position.ticker.clone(),
+            position.instrument_type.to_string(),
+            position.balance.to_string(),
+            position.blocked.to_string(),
+            position.lots.to_string(),
+            money(&position.average_position_price),
+            money(&position.expected_yield),
+            position.name.clone(),
+        ]);
+    }
+    table.print();
+}
+
+fn print_currencies(currencies: &CurrencyBalances) {
+    let mut table = Table::new(&["CURRENCY", "BALANCE", "BLOCKED"]);
+    for currency in &currencies.currencies {
+        table.row(vec![
+            currency.currency.to_string(),
+            currency.balance.to_string(),
+            currency.blocked.to_string(),
+        ]);
+    }
+    table.print();
+}
+
+fn print_placed(placed: &PlacedOrder) {
+    let mut table = Table::new(&["ID", "OPERATION", "STATUS", "REQUESTED", "EXECUTED", "COMMISSION", "MESSAGE"]);
+    let message = if placed.reject_reason.is_empty() {
+        placed.message.clone()
+    } else {
+        placed.reject_reason.clone()
+    };
+    table.row(vec![
+        placed.id.clone(),
+        placed.operation.to_string(),
+        placed.status.to_string(),
+        placed.requested_lots.to_string(),
+        placed.executed_lots.to_string(),
+        placed
+            .commission
+            .as_ref()
+            .map(|c| format!("{} {}", c.value, c.currency))
+            .unwrap_or_default(),
+        message,
+    ]);
+    table.print();
+}
+
+fn print_instruments(instruments: &[Instrument]) {
+    let mut table = Table::new(&["FIGI", "TICKER", "ISIN", "TYPE", "CURRENCY", "LOT", "PRICE STEP", "NAME"]);
+    for instrument in instruments {
+        table.row(vec![
+            instrument.figi.clone(),
+            instrument.ticker.clone(),
+            instrument.isin.clone(),
+            instrument.r#type.to_string(),
+            instrument.currency.to_string(),
+            instrument.lot.to_string(),
+            instrument.min_price_increment.to_string(),
+            instrument.name.clone(),
+        ]);
+    }
+    table.print();
+}
+
+fn parse_operation(value: &str) -> Result<OperationType, String> {
+    match value.to_lowercase().as_str() {
+        "buy" => Ok(OperationType::Buy),
+        "sell" => Ok(OperationType::Sell),
+        _ => Err(format!("expected buy or sell, got {}", value)),
+    }
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    parse_bound(value, 0)
+}
+
+// The API treats `to` as exclusive, so a date ends at the next midnight.
+fn parse_end_time(value: &str) -> Result<DateTime<Utc>, String> {
+    parse_bound(value, 1)
+}
+
+// RFC 3339 times as given, dates as midnight UTC `days` days after them.
+fn parse_bound(value: &str, days: i64) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| DateTime::from_naive_utc_and_offset(date.and_hms_opt(0, 0, 0).unwrap(), Utc))
+        .map(|time| time + chrono::Duration::days(days))
+        .map_err(|_| format!("expected YYYY-MM-DD or an RFC 3339 time, got {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from([&["invest"], args, &["accounts"]].concat())
+    }
+
+    #[test]
+    fn parses_dates_and_times() {
+        assert_eq!(parse_time("2021-03-05").unwrap().to_rfc3339(), "2021-03-05T00:00:00+00:00");
+        assert_eq!(parse_time("2021-03-05T10:30:00+03:00").unwrap().to_rfc3339(), "2021-03-05T07:30:00+00:00");
+        assert!(parse_time("05.03.2021").is_err());
+    }
+
+    #[test]
+    fn end_dates_include_the_whole_day() {
+        assert_eq!(parse_end_time("2021-03-05").unwrap().to_rfc3339(), "2021-03-06T00:00:00+00:00");
+        assert_eq!(parse_end_time("2021-03-05T10:30:00Z").unwrap().to_rfc3339(), "2021-03-05T10:30:00+00:00");
+        let cli = Cli::parse_from(["invest", "operations", "--from", "2021-03-05", "--to", "2021-03-05"]);
+        match cli.command {
+            Command::Operations { from, to, .. } => assert_eq!(to.unwrap() - from, chrono::Duration::days(1)),
+            _ => panic!("expected the operations command"),
+        }
+    }
+
+    #[test]
+    fn parses_operations() {
+        assert_eq!(parse_operation("buy").unwrap(), OperationType::Buy);
+        assert_eq!(parse_operation("SELL").unwrap(), OperationType::Sell);
+        assert!(parse_operation("hold").is_err());
+    }
+
+    // The only test that touches the token variables, so it cannot race with another.
+    #[test]
+    fn environment_token_wins_over_config() {
+        let path = env::temp_dir().join(format!("invest-cli-config-{}.json", std::process::id()));
+        fs::write(&path, r#"{"token": "from-file", "sandbox_token": "sandbox-from-file"}"#).unwrap();
+        let config = path.to_str().unwrap();
+        env::remove_var(TOKEN_VAR);
+        env::remove_var(SANDBOX_TOKEN_VAR);
+
+        assert_eq!(token(&cli(&["--config", config])).unwrap(), "from-file");
+        assert_eq!(token(&cli(&["--config", config, "--sandbox"])).unwrap(), "sandbox-from-file");
+        env::set_var(TOKEN_VAR, "from-env");
+        assert_eq!(token(&cli(&["--config", config])).unwrap(), "from-env");
+        assert_eq!(token(&cli(&["--config", config, "--sandbox"])).unwrap(), "sandbox-from-file");
+        env::set_var(TOKEN_VAR, "");
+        assert_eq!(token(&cli(&["--config", config])).unwrap(), "from-file");
+        env::remove_var(TOKEN_VAR);
+
+        fs::remove_file(&path).unwrap();
+        assert!(token(&cli(&["--config", config])).is_err());
+        fs::write(&path, "{}").unwrap();
+        assert!(token(&cli(&["--config", config])).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
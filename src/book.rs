@@ -0,0 +1,330 @@
+// Order book snapshots with the usual execution metrics computed locally.
+
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::FromPrimitive;
+
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
+/// Quantity in lots available at `price`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Level {
+    pub price: Decimal,
+    pub quantity: Decimal,
+}
+
+/// Expected execution of a market order against the levels in the book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FillEstimate {
+    /// Lots the book can absorb, less than requested if it is too thin.
+    pub filled_lots: Decimal,
+    pub average_price: Decimal,
+    /// Price of the last level the order reaches.
+    pub worst_price: Decimal,
+    /// How much worse than the best price the average price is, always >= 0.
+    pub slippage: Decimal,
+    /// `slippage` as a percentage of the best price, zero if the best price is zero.
+    pub slippage_percent: Decimal,
+}
+
+/// Order book of one instrument, built from streaming `OrderBookEvent`s or
+/// `RestClient::orderbook` responses. Bids are kept best (highest) first,
+/// asks best (lowest) first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalOrderBook {
+    pub figi: String,
+    pub depth: i64,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// Time of the streaming event the snapshot came from.
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl LocalOrderBook {
+    /// Replaces the book with the snapshot in `event` and returns whether it
+    /// did. The streaming API sends full snapshots, so no incremental merging
+    /// is needed; events for another figi or older than the current snapshot
+    /// are ignored, since one connection carries every subscription.
+    pub fn update(&mut self, event: &OrderBookEvent) -> bool {
+        if event.order_book.figi != self.figi || self.time.is_some_and(|time| event.full_event.time < time) {
+            return false;
+        }
+        *self = LocalOrderBook::from(event);
+        true
+    }
+
+    pub fn levels(&self, side: Side) -> &[Level] {
+        match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Level> {
+        self.bids.first().copied()
+    }
+
+    pub fn best_ask(&self) -> Option<Level> {
+        self.asks.first().copied()
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        Some((self.best_ask()?.price + self.best_bid()?.price) / Decimal::TWO)
+    }
+
+    /// Volume-weighted average price of the best `levels` levels of `side`.
+    pub fn weighted_price(&self, side: Side, levels: usize) -> Option<Decimal> {
+        let levels = &self.levels(side)[..levels.min(self.levels(side).len())];
+        let volume: Decimal = levels.iter().map(|l| l.quantity).sum();
+        if volume.is_zero() {
+            return None;
+        }
+        Some(levels.iter().map(|l| l.price * l.quantity).sum::<Decimal>() / volume)
+    }
+
+    /// `(bid volume - ask volume) / (bid volume + ask volume)` over the best
+    /// `levels` levels of each side, from -1 (only asks) to 1 (only bids).
+    pub fn imbalance(&self, levels: usize) -> Option<Decimal> {
+        let bids: Decimal = self.bids.iter().take(levels).map(|l| l.quantity).sum();
+        let asks: Decimal = self.asks.iter().take(levels).map(|l| l.quantity).sum();
+        if (bids + asks).is_zero() {
+            return None;
+        }
+        Some((bids - asks) / (bids + asks))
+    }
+
+    /// Lots on `side` at `price` or better: bids at or above it, asks at or below it.
+    pub fn cumulative_volume(&self, side: Side, price: Decimal) -> Decimal {
+        self.levels(side)
+            .iter()
+            .take_while(|l| match side {
+                Side::Bid => l.price >= price,
+                Side::Ask => l.price <= price,
+            })
+            .map(|l| l.quantity)
+            .sum()
+    }
+
+    /// Walks the book for a market order of `lots` lots: buys take asks, sells
+    /// take bids. Returns `None` if that side is empty or `operation` is neither
+    /// `Buy` nor `Sell`.
+    pub fn estimate_fill(&self, operation: &OperationType, lots: i64) -> Option<FillEstimate> {
+        let side = match operation {
+            OperationType::Buy => Side::Ask,
+            OperationType::Sell => Side::Bid,
+            _ => return None,
+        };
+        let levels = self.levels(side);
+        let best = levels.first()?.price;
+        let mut remaining = Decimal::from(lots.max(0));
+        let mut filled = Decimal::ZERO;
+        let mut cost = Decimal::ZERO;
+        let mut worst_price = best;
+        for level in levels {
+            if remaining.is_zero() {
+                break;
+            }
+            let taken = remaining.min(level.quantity);
+            filled += taken;
+            cost += taken * level.price;
+            remaining -= taken;
+            worst_price = level.price;
+        }
+        if filled.is_zero() {
+            return None;
+        }
+        let average_price = cost / filled;
+        let slippage = (average_price - best).abs();
+        let slippage_percent = if best.is_zero() {
+            Decimal::ZERO
+        } else {
+            slippage / best * Decimal::ONE_HUNDRED
+        };
+        Some(FillEstimate {
+            filled_lots: filled,
+            average_price,
+            worst_price,
+            slippage,
+            slippage_percent,
+        })
+    }
+}
+
+impl From<&OrderBook> for LocalOrderBook {
+    fn from(book: &OrderBook) -> Self {
+        let levels = |levels: &[PriceQuantity]| {
+            levels
+                .iter()
+                .map(|l| level(l.price, l.quantity))
+                .collect::<Vec<_>>()
+        };
+        sorted(LocalOrderBook {
+            figi: book.figi.clone(),
+            depth: book.depth,
+            bids: levels(&book.bids),
+            asks: levels(&book.asks),
+            time: None,
+        })
+    }
+}
+
+impl From<&OrderBookEvent> for LocalOrderBook {
+    fn from(event: &OrderBookEvent) -> Self {
+        LocalOrderBook {
+            time: Some(event.full_event.time),
+            ..LocalOrderBook::from(&event.order_book)
+        }
+    }
+}
+
+impl From<&RestOrderBook> for LocalOrderBook {
+    fn from(book: &RestOrderBook) -> Self {
+        let levels = |levels: &[RestPriceQuantity]| {
+            levels
+                .iter()
+                .map(|l| level(l.price, l.quantity))
+                .collect::<Vec<_>>()
+        };
+        sorted(LocalOrderBook {
+            figi: book.figi.clone(),
+            depth: book.depth,
+            bids: levels(&book.bids),
+            asks: levels(&book.asks),
+            time: None,
+        })
+    }
+}
+
+fn level(price: Decimal, quantity: f64) -> Level {
+    Level {
+        price,
+        quantity: Decimal::from_f64(quantity).unwrap_or_default(),
+    }
+}
+
+// The API already sends levels best first; sorting guards against snapshots
+// assembled by hand.
+fn sorted(mut book: LocalOrderBook) -> LocalOrderBook {
+    book.bids.sort_by_key(|l| std::cmp::Reverse(l.price));
+    book.asks.sort_by_key(|l| l.price);
+    book
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn book(bids: &[(&str, &str)], asks: &[(&str, &str)]) -> LocalOrderBook {
+        let levels = |levels: &[(&str, &str)]| {
+            levels
+                .iter()
+                .map(|(price, quantity)| Level {
+                    price: dec(price),
+                    quantity: dec(quantity),
+                })
+                .collect()
+        };
+        sorted(LocalOrderBook {
+            figi: "FIGI".to_string(),
+            depth: 20,
+            bids: levels(bids),
+            asks: levels(asks),
+            time: None,
+        })
+    }
+
+    #[test]
+    fn spread_mid_and_imbalance() {
+        let book = book(&[("99", "50"), ("100", "10")], &[("101", "5"), ("102", "15")]);
+        assert_eq!(book.best_bid().unwrap().price, dec("100"));
+        assert_eq!(book.spread(), Some(dec("1")));
+        assert_eq!(book.mid_price(), Some(dec("100.5")));
+        // (10 - 5) / 15 at the top, (60 - 20) / 80 over two levels
+        assert_eq!(book.imbalance(1).map(|i| i.round_dp(4)), Some(dec("0.3333")));
+        assert_eq!(book.imbalance(2), Some(dec("0.5")));
+        assert_eq!(book.weighted_price(Side::Ask, 2), Some(dec("101.75")));
+        assert_eq!(book.cumulative_volume(Side::Bid, dec("99")), dec("60"));
+        assert_eq!(book.cumulative_volume(Side::Ask, dec("101.5")), dec("5"));
+    }
+
+    #[test]
+    fn estimate_fill_walks_levels() {
+        let book = book(&[("100", "10")], &[("101", "5"), ("102", "15"), ("104", "10")]);
+        let estimate = book.estimate_fill(&OperationType::Buy, 10).unwrap();
+        assert_eq!(estimate.filled_lots, dec("10"));
+        // 5 at 101 and 5 at 102
+        assert_eq!(estimate.average_price, dec("101.5"));
+        assert_eq!(estimate.worst_price, dec("102"));
+        assert_eq!(estimate.slippage, dec("0.5"));
+        assert_eq!(estimate.slippage_percent.round_dp(4), dec("0.4950"));
+
+        let sell = book.estimate_fill(&OperationType::Sell, 4).unwrap();
+        assert_eq!((sell.average_price, sell.slippage), (dec("100"), dec("0")));
+    }
+
+    #[test]
+    fn estimate_fill_on_thin_and_empty_books() {
+        let book = book(&[], &[("101", "5")]);
+        let estimate = book.estimate_fill(&OperationType::Buy, 8).unwrap();
+        assert_eq!(estimate.filled_lots, dec("5"));
+        assert!(book.estimate_fill(&OperationType::Sell, 1).is_none());
+        assert!(book.estimate_fill(&OperationType::Dividend, 1).is_none());
+        assert_eq!(book.spread(), None);
+    }
+
+    #[test]
+    fn estimate_fill_with_zero_best_price() {
+        let book = book(&[], &[("0", "5"), ("1", "5")]);
+        let estimate = book.estimate_fill(&OperationType::Buy, 10).unwrap();
+        assert_eq!(estimate.average_price, dec("0.5"));
+        assert_eq!(estimate.slippage_percent, Decimal::ZERO);
+    }
+
+    fn event(figi: &str, time: &str, bid: &str) -> OrderBookEvent {
+        OrderBookEvent {
+            full_event: FullEvent {
+                name: "orderbook".to_string(),
+                time: time.parse().unwrap(),
+            },
+            order_book: OrderBook {
+                figi: figi.to_string(),
+                depth: 1,
+                bids: vec![PriceQuantity {
+                    price: dec(bid),
+                    quantity: 1.0,
+                }],
+                asks: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn update_ignores_older_snapshots() {
+        let mut book = LocalOrderBook::from(&event("FIGI", "2021-03-01T10:00:01Z", "100"));
+        assert!(!book.update(&event("FIGI", "2021-03-01T10:00:00Z", "99")));
+        assert_eq!(book.best_bid().unwrap().price, dec("100"));
+        assert!(book.update(&event("FIGI", "2021-03-01T10:00:02Z", "101")));
+        assert_eq!(book.best_bid().unwrap().price, dec("101"));
+    }
+
+    #[test]
+    fn update_ignores_other_instruments() {
+        let mut book = LocalOrderBook::from(&event("FIGI", "2021-03-01T10:00:01Z", "100"));
+        assert!(!book.update(&event("OTHER", "2021-03-01T10:00:02Z", "5")));
+        assert_eq!(book.figi, "FIGI");
+        assert_eq!(book.best_bid().unwrap().price, dec("100"));
+    }
+}
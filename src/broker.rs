@@ -0,0 +1,300 @@
+use chrono::{DateTime, Utc};
+
+use crate::rest_client::{RestClient, Result};
+use crate::*;
+
+/// Trading operations a strategy needs from a broker.
+///
+/// `RestClient` implements it against the OpenAPI; backtesters and paper-trading
+/// engines implement it to run the same strategy code without real orders.
+/// Strategies should take `&dyn BrokerApi` or a generic `B: BrokerApi` instead
+/// of a concrete client.
+pub trait BrokerApi {
+    fn instrument_by_figi(&self, figi: &str) -> Result<Instrument>;
+
+    fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments>;
+
+    fn stocks(&self) -> Result<Instruments>;
+
+    fn bonds(&self) -> Result<Instruments>;
+
+    fn etfs(&self) -> Result<Instruments>;
+
+    fn currencies(&self) -> Result<Instruments>;
+
+    fn candles(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>>;
+
+    fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook>;
+
+    fn portfolio(&self, account_id: &str) -> Result<Portfolio>;
+
+    fn operations(
+        &self,
+        account_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        figi: &str,
+    ) -> Result<Operations>;
+
+    fn orders(&self, account_id: &str) -> Result<Orders>;
+
+    fn limit_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+        price: Decimal,
+    ) -> Result<PlacedOrder>;
+
+    fn market_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+    ) -> Result<PlacedOrder>;
+
+    fn order_cancel(&self, account_id: &str, id: &str) -> Result<()>;
+
+    fn accounts(&self) -> Result<Accounts>;
+}
+
+impl BrokerApi for RestClient {
+    fn instrument_by_figi(&self, figi: &str) -> Result<Instrument> {
+        RestClient::instrument_by_figi(self, figi)
+    }
+
+    fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments> {
+        RestClient::instrument_by_ticker(self, ticker)
+    }
+
+    fn stocks(&self) -> Result<Instruments> {
+        RestClient::stocks(self)
+    }
+
+    fn bonds(&self) -> Result<Instruments> {
+        RestClient::bonds(self)
+    }
+
+    fn etfs(&self) -> Result<Instruments> {
+        RestClient::etfs(self)
+    }
+
+    fn currencies(&self) -> Result<Instruments> {
+        RestClient::currencies(self)
+    }
+
+    fn candles(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        RestClient::candles(self, from, to, interval, figi)
+    }
+
+    fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
+        RestClient::orderbook(self, depth, figi)
+    }
+
+    fn portfolio(&self, account_id: &str) -> Result<Portfolio> {
+        RestClient::portfolio(self, account_id)
+    }
+
+    fn operations(
+        &self,
+        account_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        figi: &str,
+    ) -> Result<Operations> {
+        RestClient::operations(self, account_id, from, to, figi)
+    }
+
+    fn orders(&self, account_id: &str) -> Result<Orders> {
+        RestClient::orders(self, account_id)
+    }
+
+    fn limit_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+        price: Decimal,
+    ) -> Result<PlacedOrder> {
+        RestClient::limit_order(self, account_id, figi, lots, operation, price)
+    }
+
+    fn market_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+    ) -> Result<PlacedOrder> {
+        RestClient::market_order(self, account_id, figi, lots, operation)
+    }
+
+    fn order_cancel(&self, account_id: &str, id: &str) -> Result<()> {
+        RestClient::order_cancel(self, account_id, id)
+    }
+
+    fn accounts(&self) -> Result<Accounts> {
+        RestClient::accounts(self)
+    }
+}
+
+// Lets references and boxes, including `&dyn BrokerApi` and
+// `Box<dyn BrokerApi>`, be passed wherever a `B: BrokerApi` is expected.
+macro_rules! forward_broker_api {
+    ($($target:ty),+) => {$(
+        impl<B: BrokerApi + ?Sized> BrokerApi for $target {
+            fn instrument_by_figi(&self, figi: &str) -> Result<Instrument> {
+                (**self).instrument_by_figi(figi)
+            }
+
+            fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments> {
+                (**self).instrument_by_ticker(ticker)
+            }
+
+            fn stocks(&self) -> Result<Instruments> {
+                (**self).stocks()
+            }
+
+            fn bonds(&self) -> Result<Instruments> {
+                (**self).bonds()
+            }
+
+            fn etfs(&self) -> Result<Instruments> {
+                (**self).etfs()
+            }
+
+            fn currencies(&self) -> Result<Instruments> {
+                (**self).currencies()
+            }
+
+            fn candles(
+                &self,
+                from: DateTime<Utc>,
+                to: DateTime<Utc>,
+                interval: CandleInterval,
+                figi: &str,
+            ) -> Result<Vec<Candle>> {
+                (**self).candles(from, to, interval, figi)
+            }
+
+            fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
+                (**self).orderbook(depth, figi)
+            }
+
+            fn portfolio(&self, account_id: &str) -> Result<Portfolio> {
+                (**self).portfolio(account_id)
+            }
+
+            fn operations(
+                &self,
+                account_id: &str,
+                from: DateTime<Utc>,
+                to: DateTime<Utc>,
+                figi: &str,
+            ) -> Result<Operations> {
+                (**self).operations(account_id, from, to, figi)
+            }
+
+            fn orders(&self, account_id: &str) -> Result<Orders> {
+                (**self).orders(account_id)
+            }
+
+            fn limit_order(
+                &self,
+                account_id: &str,
+                figi: &str,
+                lots: i64,
+                operation: OperationType,
+                price: Decimal,
+            ) -> Result<PlacedOrder> {
+                (**self).limit_order(account_id, figi, lots, operation, price)
+            }
+
+            fn market_order(
+                &self,
+                account_id: &str,
+                figi: &str,
+                lots: i64,
+                operation: OperationType,
+            ) -> Result<PlacedOrder> {
+                (**self).market_order(account_id, figi, lots, operation)
+            }
+
+            fn order_cancel(&self, account_id: &str, id: &str) -> Result<()> {
+                (**self).order_cancel(account_id, id)
+            }
+
+            fn accounts(&self) -> Result<Accounts> {
+                (**self).accounts()
+            }
+        }
+    )+};
+}
+
+forward_broker_api!(&B, Box<B>);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{Method, MockTransport};
+
+    fn client(transport: &MockTransport) -> RestClient {
+        RestClient::builder("token".to_string()).transport(transport.clone()).build().unwrap()
+    }
+
+    fn placed_order() -> serde_json::Value {
+        json!({"orderId": "1", "operation": "Buy", "status": "New", "requestedLots": 2, "executedLots": 0})
+    }
+
+    fn order_count<B: BrokerApi>(broker: B, account_id: &str) -> usize {
+        broker.orders(account_id).unwrap().orders.len()
+    }
+
+    #[test]
+    fn rest_client_forwards_account_ids() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Post, "orders/limit-order", &placed_order());
+        let client = client(&transport);
+        let broker: &dyn BrokerApi = &client;
+        let placed = broker.limit_order("acc", "FIGI", 2, OperationType::Buy, Decimal::new(1005, 1)).unwrap();
+        assert_eq!(placed.id, "1");
+
+        let request = &transport.requests()[0];
+        assert_eq!(request.url.path(), "/openapi/orders/limit-order");
+        assert_eq!(request.url.query(), Some("figi=FIGI&brokerAccountId=acc"));
+    }
+
+    #[test]
+    fn references_and_boxes_are_brokers() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "orders", &json!([]));
+        let client = client(&transport);
+        assert_eq!(order_count(&client, "acc"), 0);
+        let dynamic: &dyn BrokerApi = &client;
+        assert_eq!(order_count(dynamic, "acc"), 0);
+        let boxed: Box<dyn BrokerApi> = Box::new(client);
+        assert_eq!(order_count(&boxed, "acc"), 0);
+        assert_eq!(order_count(boxed, "other"), 0);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].url.query(), Some("brokerAccountId=other"));
+    }
+}
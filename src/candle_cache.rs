@@ -0,0 +1,411 @@
+// Candle history kept on disk between runs, so backtests only download what
+// they have not seen yet.
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::rest_client::candles::candle_windows;
+use crate::*;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the cache directory failed.
+    Io(io::Error),
+    /// A cache file exists but is not valid.
+    Format { path: PathBuf, source: serde_json::Error },
+    /// Fetching missing candles failed. Candles fetched before the failure are kept.
+    Fetch(rest_client::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "candle cache i/o error: {}", error),
+            Error::Format { path, source } => write!(f, "invalid candle cache file {}: {}", path.display(), source),
+            Error::Fetch(error) => write!(f, "failed to fetch candles: {}", error),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Format { source, .. } => Some(source),
+            Error::Fetch(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<rest_client::Error> for Error {
+    fn from(error: rest_client::Error) -> Self {
+        Error::Fetch(error)
+    }
+}
+
+/// Candle store keyed by figi and interval, one JSON file per pair in a directory.
+///
+/// Along with the candles each file records which time ranges have been
+/// downloaded, so `candles` only asks the broker for the gaps. Ranges are never
+/// recorded past the time they were fetched at; the last candle of a range that
+/// ended in the future may still have been forming, drop it with
+/// `invalidate_last` before reading it again.
+pub struct CandleCache {
+    dir: PathBuf,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Series {
+    covered: Vec<Span>,
+    candles: Vec<Candle>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Span {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+impl CandleCache {
+    /// Opens the cache in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Candles in `from`..`to`, sorted by time. Missing parts of the range are
+    /// fetched from `broker` and stored first.
+    pub fn candles<B: BrokerApi + ?Sized>(
+        &self,
+        broker: &B,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        let path = self.path(&interval, figi);
+        let mut series = load(&path)?;
+        let gaps = gaps(&series.covered, from, to);
+        if !gaps.is_empty() {
+            let mut candles: BTreeMap<DateTime<Utc>, Candle> =
+                series.candles.drain(..).map(|c| (c.ts, c)).collect();
+            let mut result = Ok(());
+            'gaps: for (gap_from, gap_to) in gaps {
+                for (window_from, window_to) in candle_windows(gap_from, gap_to, &interval) {
+                    let now = Utc::now();
+                    match broker.candles(window_from, window_to, interval.clone(), figi) {
+                        Ok(fetched) => {
+                            candles.extend(fetched.into_iter().map(|c| (c.ts, c)));
+                            if window_from < now {
+                                add_span(&mut series.covered, window_from, window_to.min(now));
+                            }
+                        }
+                        Err(error) => {
+                            result = Err(error);
+                            break 'gaps;
+                        }
+                    }
+                }
+            }
+            series.candles = candles.into_values().collect();
+            save(&path, &series)?;
+            result?;
+        }
+        Ok(select(series.candles, from, to))
+    }
+
+    /// Candles in `from`..`to` that are already on disk, without fetching anything.
+    pub fn cached(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        let series = load(&self.path(&interval, figi))?;
+        Ok(select(series.candles, from, to))
+    }
+
+    /// Time ranges stored for `figi` and `interval`, sorted and non-overlapping.
+    pub fn coverage(&self, interval: CandleInterval, figi: &str) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+        let series = load(&self.path(&interval, figi))?;
+        Ok(series.covered.iter().map(|s| (s.from, s.to)).collect())
+    }
+
+    /// Parts of `from`..`to` that `candles` would have to fetch.
+    pub fn gaps(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>> {
+        let series = load(&self.path(&interval, figi))?;
+        Ok(gaps(&series.covered, from, to))
+    }
+
+    /// Drops the most recent stored candle and marks its time as not covered,
+    /// so the next `candles` call downloads it again. Use it for the candle
+    /// that was still forming when it was fetched.
+    pub fn invalidate_last(&self, interval: CandleInterval, figi: &str) -> Result<()> {
+        let path = self.path(&interval, figi);
+        let series = load(&path)?;
+        match series.candles.last() {
+            Some(last) => self.invalidate_since(last.ts, interval, figi),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops every stored candle from `since` on and marks that time as not covered.
+    pub fn invalidate_since(&self, since: DateTime<Utc>, interval: CandleInterval, figi: &str) -> Result<()> {
+        let path = self.path(&interval, figi);
+        let mut series = load(&path)?;
+        series.candles.retain(|c| c.ts < since);
+        series.covered.retain(|s| s.from < since);
+        for span in &mut series.covered {
+            span.to = span.to.min(since);
+        }
+        save(&path, &series)
+    }
+
+    /// Removes everything stored for `figi` and `interval`.
+    pub fn clear(&self, interval: CandleInterval, figi: &str) -> Result<()> {
+        match fs::remove_file(self.path(&interval, figi)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, interval: &CandleInterval, figi: &str) -> PathBuf {
+        self.dir.join(format!("{}_{}.json", figi, interval))
+    }
+}
+
+fn load(path: &Path) -> Result<Series> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Series::default()),
+        Err(error) => return Err(error.into()),
+    };
+    serde_json::from_slice(&data).map_err(|source| Error::Format {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+// Writes to a temporary file first so an interrupted write never leaves a
+// truncated cache file behind.
+fn save(path: &Path, series: &Series) -> Result<()> {
+    let data = serde_json::to_vec(series).map_err(|source| Error::Format {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn select(candles: Vec<Candle>, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Candle> {
+    candles.into_iter().filter(|c| c.ts >= from && c.ts < to).collect()
+}
+
+// Inserts from..to into sorted, non-overlapping spans, merging the ones it touches.
+fn add_span(spans: &mut Vec<Span>, from: DateTime<Utc>, to: DateTime<Utc>) {
+    let mut merged = Span { from, to };
+    spans.retain(|s| {
+        if s.to < merged.from || s.from > merged.to {
+            return true;
+        }
+        merged.from = merged.from.min(s.from);
+        merged.to = merged.to.max(s.to);
+        false
+    });
+    let index = spans.iter().position(|s| s.from > merged.from).unwrap_or(spans.len());
+    spans.insert(index, merged);
+}
+
+fn gaps(spans: &[Span], from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut gaps = vec![];
+    let mut start = from;
+    for span in spans {
+        if span.to <= start {
+            continue;
+        }
+        if span.from >= to {
+            break;
+        }
+        if span.from > start {
+            gaps.push((start, span.from));
+        }
+        start = span.to;
+    }
+    if start < to {
+        gaps.push((start, to));
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{HttpResponse, Method, MockTransport, RestClient, RetryPolicy};
+
+    fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 3, day, 0, 0, 0).unwrap()
+    }
+
+    fn candle(ts: DateTime<Utc>) -> Candle {
+        Candle {
+            figi: "FIGI".to_string(),
+            interval: CandleInterval::Hour1,
+            open_price: Decimal::ONE,
+            close_price: Decimal::ONE,
+            high_price: Decimal::ONE,
+            low_price: Decimal::ONE,
+            volume: 1.0,
+            ts,
+        }
+    }
+
+    fn respond(transport: &MockTransport, candles: Vec<Candle>) {
+        let payload = json!({"figi": "FIGI", "interval": "hour", "candles": candles});
+        transport.respond_ok(Method::Get, "market/candles", &payload);
+    }
+
+    fn broker(transport: &MockTransport) -> RestClient {
+        RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    // A fresh cache directory, removed when the test is done with it.
+    struct TempCache(CandleCache);
+
+    impl TempCache {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("invest-candle-cache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(CandleCache::open(dir).unwrap())
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    fn span(from: DateTime<Utc>, to: DateTime<Utc>) -> Span {
+        Span { from, to }
+    }
+
+    fn ranges(spans: &[Span]) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        spans.iter().map(|s| (s.from, s.to)).collect()
+    }
+
+    #[test]
+    fn spans_merge_when_adjacent_or_overlapping() {
+        let mut spans = vec![];
+        add_span(&mut spans, day(5), day(6));
+        add_span(&mut spans, day(1), day(2));
+        add_span(&mut spans, day(3), day(4));
+        assert_eq!(ranges(&spans), vec![(day(1), day(2)), (day(3), day(4)), (day(5), day(6))]);
+
+        add_span(&mut spans, day(2), day(3));
+        assert_eq!(ranges(&spans), vec![(day(1), day(4)), (day(5), day(6))]);
+        add_span(&mut spans, day(3), day(8));
+        assert_eq!(ranges(&spans), vec![(day(1), day(8))]);
+    }
+
+    #[test]
+    fn gaps_are_the_uncovered_parts() {
+        let spans = vec![span(day(2), day(3)), span(day(5), day(6))];
+        assert_eq!(gaps(&spans, day(1), day(7)), vec![(day(1), day(2)), (day(3), day(5)), (day(6), day(7))]);
+        assert_eq!(gaps(&spans, day(2), day(3)), vec![]);
+        assert_eq!(gaps(&spans, day(2), day(4)), vec![(day(3), day(4))]);
+        assert_eq!(gaps(&[], day(1), day(2)), vec![(day(1), day(2))]);
+    }
+
+    #[test]
+    fn fetches_only_missing_ranges() {
+        let cache = TempCache::new("gaps");
+        let transport = MockTransport::new();
+        respond(&transport, vec![candle(day(2)), candle(day(3))]);
+        let candles = cache.0.candles(&broker(&transport), day(2), day(4), CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(transport.requests().len(), 1);
+
+        // day 1 and days 4 to 6 are missing
+        let transport = MockTransport::new();
+        respond(&transport, vec![candle(day(1)), candle(day(4)), candle(day(5))]);
+        let candles = cache.0.candles(&broker(&transport), day(1), day(6), CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(candles.iter().map(|c| c.ts).collect::<Vec<_>>(), vec![day(1), day(2), day(3), day(4), day(5)]);
+        assert_eq!(transport.requests().len(), 2);
+        assert_eq!(cache.0.coverage(CandleInterval::Hour1, "FIGI").unwrap(), vec![(day(1), day(6))]);
+
+        let transport = MockTransport::new();
+        let candles = cache.0.candles(&broker(&transport), day(2), day(5), CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(candles.len(), 3);
+        assert!(transport.requests().is_empty());
+    }
+
+    #[test]
+    fn keeps_what_was_fetched_before_a_failure() {
+        let cache = TempCache::new("failure");
+        let transport = MockTransport::new();
+        respond(&transport, vec![candle(day(1))]);
+        transport.respond(Method::Get, "market/candles", HttpResponse::new(500, "boom"));
+        // two hourly windows of a week each
+        let result = cache.0.candles(&broker(&transport), day(1), day(15), CandleInterval::Hour1, "FIGI");
+        assert!(matches!(result, Err(Error::Fetch(_))));
+        assert_eq!(transport.requests().len(), 2);
+
+        assert_eq!(cache.0.coverage(CandleInterval::Hour1, "FIGI").unwrap(), vec![(day(1), day(8))]);
+        assert_eq!(cache.0.cached(day(1), day(15), CandleInterval::Hour1, "FIGI").unwrap().len(), 1);
+        assert_eq!(cache.0.gaps(day(1), day(15), CandleInterval::Hour1, "FIGI").unwrap(), vec![(day(8), day(15))]);
+    }
+
+    #[test]
+    fn invalidation_trims_candles_and_coverage() {
+        let cache = TempCache::new("invalidate");
+        let transport = MockTransport::new();
+        respond(&transport, vec![candle(day(1)), candle(day(2)), candle(day(3))]);
+        cache.0.candles(&broker(&transport), day(1), day(5), CandleInterval::Hour1, "FIGI").unwrap();
+
+        cache.0.invalidate_last(CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(cache.0.cached(day(1), day(5), CandleInterval::Hour1, "FIGI").unwrap().len(), 2);
+        assert_eq!(cache.0.coverage(CandleInterval::Hour1, "FIGI").unwrap(), vec![(day(1), day(3))]);
+
+        cache.0.invalidate_since(day(2), CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(cache.0.cached(day(1), day(5), CandleInterval::Hour1, "FIGI").unwrap().len(), 1);
+        assert_eq!(cache.0.coverage(CandleInterval::Hour1, "FIGI").unwrap(), vec![(day(1), day(2))]);
+
+        cache.0.invalidate_since(day(1), CandleInterval::Hour1, "FIGI").unwrap();
+        assert!(cache.0.coverage(CandleInterval::Hour1, "FIGI").unwrap().is_empty());
+        cache.0.clear(CandleInterval::Hour1, "FIGI").unwrap();
+        cache.0.clear(CandleInterval::Hour1, "FIGI").unwrap();
+    }
+}
@@ -1,264 +1,368 @@
 #![allow(dead_code)]
 
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
 use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
-
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Prices, money amounts and balances. Decoded straight from the JSON number
+// text, so values like 0.1 survive a round trip exactly.
+pub use rust_decimal::Decimal;
+
+pub use broker::BrokerApi;
+
+pub mod backtest;
+pub mod book;
+pub mod broker;
+pub mod candle_cache;
+pub mod paper;
+pub mod pnl;
+pub mod rebalance;
+pub mod resample;
 pub mod rest_client;
-
-pub type Currency = String;
-
-lazy_static! {
-    pub static ref RUB: Currency = String::from("RUB");
-    pub static ref USD: Currency = String::from("USD");
-    pub static ref EUR: Currency = String::from("EUR");
-    pub static ref TRY: Currency = String::from("TRY");
-    pub static ref JPY: Currency = String::from("JPY");
-    pub static ref CNY: Currency = String::from("CNY");
-    pub static ref CHF: Currency = String::from("CHF");
-    pub static ref GBP: Currency = String::from("GBP");
-    pub static ref HKD: Currency = String::from("HKD");
-}
-
-pub type OperationType = String;
-
-lazy_static! {
-    pub static ref OPERATION_TYPE_BUY: OperationType = String::from("Buy");
-    pub static ref OPERATION_TYPE_SELL: OperationType = String::from("Sell");
-    pub static ref OPERATION_TYPE_BROKER_COMMISSION: OperationType =
-        String::from("BrokerCommission");
-    pub static ref OPERATION_TYPE_EXCHANGE_COMMISSION: OperationType =
-        String::from("ExchangeCommission");
-    pub static ref OPERATION_TYPE_SERVICE_COMMISSION: OperationType =
-        String::from("ServiceCommission");
-    pub static ref OPERATION_TYPE_MARGIN_COMMISSION: OperationType =
-        String::from("MarginCommission");
-    pub static ref OPERATION_TYPE_OTHER_COMMISSION: OperationType = String::from("OtherCommission");
-    pub static ref OPERATION_TYPE_PAY_IN: OperationType = String::from("PayIn");
-    pub static ref OPERATION_TYPE_PAY_OUT: OperationType = String::from("PayOut");
-    pub static ref OPERATION_TYPE_TAX: OperationType = String::from("Tax");
-    pub static ref OPERATION_TYPE_TAX_LUCRE: OperationType = String::from("TaxLucre");
-    pub static ref OPERATION_TYPE_TAX_DIVIDEND: OperationType = String::from("TaxDividend");
-    pub static ref OPERATION_TYPE_TAX_COUPON: OperationType = String::from("TaxCoupon");
-    pub static ref OPERATION_TYPE_TAX_BACK: OperationType = String::from("TaxBack");
-    pub static ref OPERATION_TYPE_REPAYMENT: OperationType = String::from("Repayment");
-    pub static ref OPERATION_TYPE_PART_REPAYMENT: OperationType = String::from("PartRepayment");
-    pub static ref OPERATION_TYPE_COUPON: OperationType = String::from("Coupon");
-    pub static ref OPERATION_TYPE_DIVIDEND: OperationType = String::from("Dividend");
-    pub static ref OPERATION_TYPE_SECURITY_IN: OperationType = String::from("SecurityIn");
-    pub static ref OPERATION_TYPE_SECURITY_OUT: OperationType = String::from("SecurityOut");
-    pub static ref OPERATION_TYPE_BUY_CARD: OperationType = String::from("BuyCard");
-}
-
-pub type OrderStatus = String;
-
-lazy_static! {
-    pub static ref ORDER_STATUS_NEW: OrderStatus = String::from("New");
-    pub static ref ORDER_STATUS_PARTIALLY_FILL: OrderStatus = String::from("PartiallyFill");
-    pub static ref ORDER_STATUS_FILL: OrderStatus = String::from("Fill");
-    pub static ref ORDER_STATUS_CANCELLED: OrderStatus = String::from("Cancelled");
-    pub static ref ORDER_STATUS_REPLACED: OrderStatus = String::from("Replaced");
-    pub static ref ORDER_STATUS_PENDING_CANCEL: OrderStatus = String::from("PendingCancel");
-    pub static ref ORDER_STATUS_REJECTED: OrderStatus = String::from("Rejected");
-    pub static ref ORDER_STATUS_PENDING_REPLACE: OrderStatus = String::from("PendingReplace");
-    pub static ref ORDER_STATUS_PENDING_NEW: OrderStatus = String::from("PendingNew");
-}
-
-pub type OperationStatus = String;
-
-lazy_static! {
-    pub static ref OPERATION_STATUS_DONE: OperationStatus = String::from("Done");
-    pub static ref OPERATION_STATUS_DECLINE: OperationStatus = String::from("Decline");
-    pub static ref OPERATION_STATUS_PROGRESS: OperationStatus = String::from("Progress");
-}
-
-pub type InstrumentType = String;
-
-lazy_static! {
-    pub static ref INSTRUMENT_TYPE_STOCK: InstrumentType = String::from("Stock");
-    pub static ref INSTRUMENT_TYPE_CURRENCY: InstrumentType = String::from("currency");
-    pub static ref INSTRUMENT_TYPE_BOND: InstrumentType = String::from("Bond");
-    pub static ref INSTRUMENT_TYPE_ETF: InstrumentType = String::from("Etf");
-}
-
-pub type OrderType = String;
-
-lazy_static! {
-    pub static ref ORDER_TYPE_LIMIT: OrderType = String::from("Limit");
-    pub static ref ORDER_TYPE_MARKET: OrderType = String::from("Market");
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+pub mod streaming;
+pub mod tax;
+pub mod tracker;
+pub mod validation;
+pub mod valuation;
+
+// Enum over the string codes used by the API. Values the server sends that
+// are not listed end up in `Unknown` and are serialized back unchanged.
+macro_rules! wire_enum {
+    ($name:ident { $($variant:ident => $wire:literal,)+ }) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant,)+
+            Unknown(String),
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $wire,)+
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                match value {
+                    $($wire => $name::$variant,)+
+                    other => $name::Unknown(other.to_string()),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Infallible;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                Ok($name::from(value))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = String::deserialize(deserializer)?;
+                Ok($name::from(value.as_str()))
+            }
+        }
+    };
+}
+
+wire_enum!(Currency {
+    Rub => "RUB",
+    Usd => "USD",
+    Eur => "EUR",
+    Try => "TRY",
+    Jpy => "JPY",
+    Cny => "CNY",
+    Chf => "CHF",
+    Gbp => "GBP",
+    Hkd => "HKD",
+});
+
+wire_enum!(OperationType {
+    Buy => "Buy",
+    Sell => "Sell",
+    BrokerCommission => "BrokerCommission",
+    ExchangeCommission => "ExchangeCommission",
+    ServiceCommission => "ServiceCommission",
+    MarginCommission => "MarginCommission",
+    OtherCommission => "OtherCommission",
+    PayIn => "PayIn",
+    PayOut => "PayOut",
+    Tax => "Tax",
+    TaxLucre => "TaxLucre",
+    TaxDividend => "TaxDividend",
+    TaxCoupon => "TaxCoupon",
+    TaxBack => "TaxBack",
+    Repayment => "Repayment",
+    PartRepayment => "PartRepayment",
+    Coupon => "Coupon",
+    Dividend => "Dividend",
+    SecurityIn => "SecurityIn",
+    SecurityOut => "SecurityOut",
+    BuyCard => "BuyCard",
+});
+
+wire_enum!(OrderStatus {
+    New => "New",
+    PartiallyFill => "PartiallyFill",
+    Fill => "Fill",
+    Cancelled => "Cancelled",
+    Replaced => "Replaced",
+    PendingCancel => "PendingCancel",
+    Rejected => "Rejected",
+    PendingReplace => "PendingReplace",
+    PendingNew => "PendingNew",
+});
+
+wire_enum!(OperationStatus {
+    Done => "Done",
+    Decline => "Decline",
+    Progress => "Progress",
+});
+
+wire_enum!(InstrumentType {
+    Stock => "Stock",
+    Currency => "currency",
+    Bond => "Bond",
+    Etf => "Etf",
+});
+
+wire_enum!(OrderType {
+    Limit => "Limit",
+    Market => "Market",
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PlacedOrder {
-    id: String,
-    operation: OperationType,
-    status: OrderStatus,
-    reject_reason: String,
-    requested_lots: i64,
-    executed_lots: i64,
-    commission: MoneyAmount,
-    message: String,
+    #[serde(rename = "orderId")]
+    pub id: String,
+    pub operation: OperationType,
+    pub status: OrderStatus,
+    #[serde(default)]
+    pub reject_reason: String,
+    pub requested_lots: i64,
+    pub executed_lots: i64,
+    #[serde(default)]
+    pub commission: Option<MoneyAmount>,
+    #[serde(default)]
+    pub message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Order {
-    id: String,
-    figi: String,
-    operation: OperationType,
-    status: OrderStatus,
-    requested_lots: i64,
-    executed_lots: i64,
-    r#type: OrderType,
-    price: f64,
+    #[serde(rename = "orderId")]
+    pub id: String,
+    pub figi: String,
+    pub operation: OperationType,
+    pub status: OrderStatus,
+    pub requested_lots: i64,
+    pub executed_lots: i64,
+    #[serde(rename = "type")]
+    pub r#type: OrderType,
+    pub price: Decimal,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// the orders endpoint returns a bare array as its payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Orders {
-    orders: Vec<Order>
+    pub orders: Vec<Order>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Portfolio {
     pub positions: PositionBalances,
     pub currencies: CurrencyBalances,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CurrencyBalance {
-    currency: Currency,
-    balance: f64,
+    pub currency: Currency,
+    pub balance: Decimal,
     #[serde(default)]
-    blocked: f64,
+    pub blocked: Decimal,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CurrencyBalances {
-    currencies: Vec<CurrencyBalance>
+    pub currencies: Vec<CurrencyBalance>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PositionBalance {
-    figi: String,
-    ticker: String,
-    isin: String,
-    instrument_type: InstrumentType,
-    balance: f64,
-    blocked: f64,
-    lots: i64,
-    expected_yield: MoneyAmount,
-    average_position_price: MoneyAmount,
-    average_position_price_no_nkd: MoneyAmount,
-    name: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+    pub figi: String,
+    pub ticker: String,
+    #[serde(default)]
+    pub isin: String,
+    pub instrument_type: InstrumentType,
+    pub balance: Decimal,
+    #[serde(default)]
+    pub blocked: Decimal,
+    pub lots: i64,
+    #[serde(default)]
+    pub expected_yield: Option<MoneyAmount>,
+    #[serde(default)]
+    pub average_position_price: Option<MoneyAmount>,
+    #[serde(default)]
+    pub average_position_price_no_nkd: Option<MoneyAmount>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PositionBalances {
-    positions: Vec<PositionBalance>
+    pub positions: Vec<PositionBalance>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MoneyAmount {
-    currency: Currency,
-    value: f64,
+    pub currency: Currency,
+    pub value: Decimal,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instrument {
-    figi: String,
-    ticker: String,
+    pub figi: String,
+    pub ticker: String,
     #[serde(default)]
-    isin: String,
-    name: String,
+    pub isin: String,
+    pub name: String,
     #[serde(default, rename = "minPriceIncrement")]
-    min_price_increment: f64,
-    lot: i64,
-    currency: Currency,
+    pub min_price_increment: Decimal,
+    pub lot: i64,
+    pub currency: Currency,
     #[serde(rename = "type")]
-    r#type: InstrumentType,
+    pub r#type: InstrumentType,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instruments {
-    instruments: Vec<Instrument>,
+    pub instruments: Vec<Instrument>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Operation {
-    id: String,
-    status: OperationStatus,
-    trades: Vec<Trade>,
-    // array
-    commission: MoneyAmount,
-    currency: Currency,
-    payment: f64,
-    price: f64,
-    quantity: i64,
-    #[serde(rename = "quantityExecuted")]
-    quantity_executed: i64,
-    figi: String,
-    #[serde(rename = "instrumentType")]
-    instrument_type: InstrumentType,
+    pub id: String,
+    pub status: OperationStatus,
+    #[serde(default)]
+    pub trades: Vec<Trade>,
+    #[serde(default)]
+    pub commission: Option<MoneyAmount>,
+    pub currency: Currency,
+    pub payment: Decimal,
+    #[serde(default)]
+    pub price: Decimal,
+    #[serde(default)]
+    pub quantity: i64,
+    #[serde(default, rename = "quantityExecuted")]
+    pub quantity_executed: i64,
+    #[serde(default)]
+    pub figi: String,
+    #[serde(default, rename = "instrumentType")]
+    pub instrument_type: Option<InstrumentType>,
     #[serde(rename = "isMarginCall")]
-    is_margin_call: bool,
+    pub is_margin_call: bool,
     #[serde(rename = "date")]
-    date_time: DateTime<Utc>,
+    pub date_time: DateTime<Utc>,
     // https://docs.rs/chrono/0.4.0/chrono/struct.DateTime.html
     #[serde(rename = "operationType")]
-    operation_type: OperationType,
+    pub operation_type: OperationType,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Operations {
-    operations: Vec<Operation>,
+    pub operations: Vec<Operation>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trade {
-    id: String,
+    #[serde(rename = "tradeId")]
+    pub id: String,
     #[serde(rename = "date")]
-    date_time: DateTime<Utc>,
+    pub date_time: DateTime<Utc>,
     // https://docs.rs/chrono/0.4.0/chrono/struct.DateTime.html
-    price: f64,
-    quantity: i64,
+    pub price: Decimal,
+    pub quantity: i64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RestPriceQuantity {
-    price: f64,
-    quantity: f64,
-}
-
-pub type TradingStatus = String;
-
+    pub price: Decimal,
+    pub quantity: f64,
+}
+
+wire_enum!(TradingStatus {
+    BreakInTrading => "break_in_trading",
+    NormalTrading => "normal_trading",
+    NotAvailableForTrading => "not_available_for_trading",
+    ClosingAuction => "closing_auction",
+    ClosingPeriod => "closing_period",
+    DarkPoolAuction => "dark_pool_auction",
+    DiscreteAuction => "discrete_auction",
+    OpeningPeriod => "opening_period",
+    OpeningAuctionPeriod => "opening_auction_period",
+    TradingAtClosingAuctionPrice => "trading_at_closing_auction_price",
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RestOrderBook {
-    figi: String,
-    depth: i64,
-    bids: Vec<RestPriceQuantity>,
-    // array
-    asks: Vec<RestPriceQuantity>,
-    // array
-    trade_status: TradingStatus,
-    min_price_increment: f64,
-    last_price: f64,
-    close_price: f64,
-    limit_up: f64,
-    limit_down: f64,
-    face_value: f64,
-}
-
-pub type AccountType = String;
-
-lazy_static! {
-    pub static ref ACCOUNT_TINKOFF: AccountType = String::from("Tinkoff");
-    pub static ref ACCOUNT_TINKOFF_IIS: AccountType = String::from("TinkoffIis");
+    pub figi: String,
+    pub depth: i64,
+    pub bids: Vec<RestPriceQuantity>,
+    pub asks: Vec<RestPriceQuantity>,
+    pub trade_status: TradingStatus,
+    pub min_price_increment: Decimal,
+    #[serde(default)]
+    pub last_price: Decimal,
+    #[serde(default)]
+    pub close_price: Decimal,
+    #[serde(default)]
+    pub limit_up: Decimal,
+    #[serde(default)]
+    pub limit_down: Decimal,
+    #[serde(default)]
+    pub face_value: Decimal,
 }
 
+wire_enum!(AccountType {
+    Tinkoff => "Tinkoff",
+    TinkoffIis => "TinkoffIis",
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Account {
-    r#type: AccountType,
-    id: String,
+    #[serde(rename = "brokerAccountType")]
+    pub r#type: AccountType,
+    #[serde(rename = "brokerAccountId")]
+    pub id: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Accounts {
-    accounts: Vec<Account>
+    pub accounts: Vec<Account>,
 }
 
 lazy_static! {
@@ -269,131 +373,180 @@ lazy_static! {
 
 pub static MAX_ORDERBOOK_DEPTH: i64 = 20;
 
-pub type CandleInterval = String;
-
-lazy_static! {
-pub static ref CandleInterval1Min:   CandleInterval = "1min";
-pub static ref CandleInterval2Min:   CandleInterval = "2min";
-pub static ref CandleInterval3Min:   CandleInterval = "3min";
-pub static ref CandleInterval5Min:   CandleInterval = "5min";
-pub static ref CandleInterval10Min:  CandleInterval = "10min";
-pub static ref CandleInterval15Min:  CandleInterval = "15min";
-pub static ref CandleInterval30Min:  CandleInterval = "30min";
-pub static ref CandleInterval1Hour:  CandleInterval = "hour";
-pub static ref CandleInterval2Hour:  CandleInterval = "2hour";
-pub static ref CandleInterval4Hour:  CandleInterval = "4hour";
-pub static ref CandleInterval1Day:   CandleInterval = "day";
-pub static ref CandleInterval1Week:  CandleInterval = "week";
-pub static ref CandleInterval1Month: CandleInterval = "month";
-}
-
-lazy_static! {
-pub static ref BreakInTrading:               TradingStatus = "break_in_trading";
-pub static ref NormalTrading:                TradingStatus = "normal_trading";
-pub static ref NotAvailableForTrading:       TradingStatus = "not_available_for_trading";
-pub static ref ClosingAuction:               TradingStatus = "closing_auction";
-pub static ref ClosingPeriod:                TradingStatus = "closing_period";
-pub static ref DarkPoolAuction:              TradingStatus = "dark_pool_auction";
-pub static ref DiscreteAuction:              TradingStatus = "discrete_auction";
-pub static ref OpeningPeriod:                TradingStatus = "opening_period";
-pub static ref OpeningAuctionPeriod:         TradingStatus = "opening_auction_period";
-pub static ref TradingAtClosingAuctionPrice: TradingStatus = "trading_at_closing_auction_price";
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+wire_enum!(CandleInterval {
+    Min1 => "1min",
+    Min2 => "2min",
+    Min3 => "3min",
+    Min5 => "5min",
+    Min10 => "10min",
+    Min15 => "15min",
+    Min30 => "30min",
+    Hour1 => "hour",
+    Hour2 => "2hour",
+    Hour4 => "4hour",
+    Day1 => "day",
+    Week1 => "week",
+    Month1 => "month",
+});
+
+impl CandleInterval {
+    /// Longest `from`..`to` range the candles endpoint accepts in one request
+    /// for this interval.
+    pub fn max_request_range(&self) -> chrono::Duration {
+        match self {
+            CandleInterval::Hour1 | CandleInterval::Hour2 | CandleInterval::Hour4 => chrono::Duration::days(7),
+            CandleInterval::Day1 => chrono::Duration::days(365),
+            CandleInterval::Week1 => chrono::Duration::days(2 * 365),
+            CandleInterval::Month1 => chrono::Duration::days(10 * 365),
+            _ => chrono::Duration::days(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     #[serde(rename = "event")]
-    name: String
+    pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FullEvent {
     #[serde(rename = "event")]
-    name: String,
-    time: DateTime<Utc>,
+    pub name: String,
+    pub time: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CandleEvent {
-    full_event: FullEvent,
+    #[serde(flatten)]
+    pub full_event: FullEvent,
     #[serde(rename = "payload")]
-    candle: Candle,
+    pub candle: Candle,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Candle {
-    figi: String,
-    interval: CandleInterval,
+    pub figi: String,
+    pub interval: CandleInterval,
     #[serde(rename = "o")]
-    open_price: f64,
+    pub open_price: Decimal,
     #[serde(rename = "c")]
-    close_price: f64,
+    pub close_price: Decimal,
     #[serde(rename = "h")]
-    high_price: f64,
+    pub high_price: Decimal,
     #[serde(rename = "l")]
-    low_price: f64,
+    pub low_price: Decimal,
     #[serde(rename = "v")]
-    volume: f64,
+    pub volume: f64,
     #[serde(rename = "time")]
-    ts: DateTime<Utc>,
+    pub ts: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Candles {
-    candles: Vec<Candle>
+    pub candles: Vec<Candle>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBookEvent {
-    full_event: FullEvent,
+    #[serde(flatten)]
+    pub full_event: FullEvent,
     #[serde(rename = "payload")]
-    order_book: OrderBook,
+    pub order_book: OrderBook,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// bids and asks come as [price, quantity] pairs in the streaming API
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBook {
-    figi: String,
-    depth: i64,
-    bids: Vec<PriceQuantity>,
-    asks: Vec<PriceQuantity>,
+    pub figi: String,
+    pub depth: i64,
+    pub bids: Vec<PriceQuantity>,
+    pub asks: Vec<PriceQuantity>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PriceQuantity {
-    price: f64,
-    quantity: f64
+    pub price: Decimal,
+    pub quantity: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstrumentInfoEvent {
-    full_event: FullEvent,
+    #[serde(flatten)]
+    pub full_event: FullEvent,
     #[serde(rename = "payload")]
-    info: InstrumentInfo,
+    pub info: InstrumentInfo,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstrumentInfo {
-    figi: String,
-    trade_status: TradingStatus,
-    min_price_increment: f64,
-    lot: f64,
-    #[serde(skip_deserializing)]
-    accrued_interest: f64,
-    #[serde(skip_deserializing)]
-    limit_up: f64,
-    #[serde(skip_deserializing)]
-    limit_down: f64,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+    pub figi: String,
+    pub trade_status: TradingStatus,
+    pub min_price_increment: Decimal,
+    pub lot: f64,
+    #[serde(default)]
+    pub accrued_interest: Decimal,
+    #[serde(default)]
+    pub limit_up: Decimal,
+    #[serde(default)]
+    pub limit_down: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorEvent {
-    full_event: FullEvent,
+    #[serde(flatten)]
+    pub full_event: FullEvent,
     #[serde(rename = "payload")]
-    error: Error,
+    pub error: Error,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Error {
-    request_id: String,
-    #[serde(skip_deserializing)]
-    error: String,
+    #[serde(default)]
+    pub request_id: String,
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_enums_round_trip_exactly() {
+        assert_eq!(serde_json::from_str::<OperationType>("\"Buy\"").unwrap(), OperationType::Buy);
+        assert_eq!(serde_json::to_string(&OperationType::Buy).unwrap(), "\"Buy\"");
+        assert_eq!(serde_json::from_str::<InstrumentType>("\"currency\"").unwrap(), InstrumentType::Currency);
+        assert_eq!(serde_json::to_string(&InstrumentType::Currency).unwrap(), "\"currency\"");
+
+        let unknown: OperationType = serde_json::from_str("\"SomethingNew\"").unwrap();
+        assert_eq!(unknown, OperationType::Unknown("SomethingNew".to_string()));
+        assert_eq!(serde_json::to_string(&unknown).unwrap(), "\"SomethingNew\"");
+    }
+
+    #[test]
+    fn wire_enums_parse_and_display_wire_values() {
+        assert_eq!("Buy".parse::<OperationType>().unwrap(), OperationType::Buy);
+        assert_eq!(OperationType::Buy.to_string(), "Buy");
+        assert_eq!("currency".parse::<InstrumentType>().unwrap(), InstrumentType::Currency);
+        assert_eq!(InstrumentType::Currency.to_string(), "currency");
+        assert_eq!("hour".parse::<CandleInterval>().unwrap(), CandleInterval::Hour1);
+        assert_eq!(CandleInterval::Hour1.to_string(), CandleInterval::Hour1.as_str());
+        assert_eq!("Currency".parse::<InstrumentType>().unwrap(), InstrumentType::Unknown("Currency".to_string()));
+        assert_eq!(InstrumentType::Unknown("Etf2".to_string()).to_string(), "Etf2");
+    }
+
+    #[test]
+    fn decimals_decode_without_float_rounding() {
+        let a: MoneyAmount = serde_json::from_str(r#"{"currency": "RUB", "value": 0.1}"#).unwrap();
+        let b: MoneyAmount = serde_json::from_str(r#"{"currency": "RUB", "value": 0.2}"#).unwrap();
+        assert_eq!(a.value + b.value, "0.3".parse::<Decimal>().unwrap());
+    }
+
+    #[test]
+    fn decimals_encode_exactly() {
+        let text = r#"{"currency":"USD","value":123.4500000001}"#;
+        let amount: MoneyAmount = serde_json::from_str(text).unwrap();
+        assert_eq!(amount.value, "123.4500000001".parse::<Decimal>().unwrap());
+        assert_eq!(serde_json::to_string(&amount).unwrap(), text);
+    }
 }
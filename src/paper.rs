@@ -0,0 +1,320 @@
+// Forward testing: live market data with orders filled in a local ledger.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+use crate::book::LocalOrderBook;
+use crate::rest_client::{RestClient, Result, SandboxEmulator};
+use crate::*;
+
+/// A `BrokerApi` that reads market data from a real broker and simulates
+/// orders locally against its order books, so strategies can be forward
+/// tested without touching a real account.
+///
+/// Market data calls go to the wrapped broker. Account calls, orders and
+/// operations are answered by a `SandboxEmulator` that holds one paper account,
+/// so they return the usual `PlacedOrder`, `Order` and `Operation` values.
+/// Orders are matched against the latest order book of their instrument:
+/// streaming `OrderBookEvent`s passed to `on_orderbook`, books fetched through
+/// `orderbook`, or a book of `depth` levels polled before an order when the
+/// latest one is older than `max_book_age`. An order takes at most the
+/// visible quantity, and what it takes is gone from the book until the next
+/// snapshot. See `SandboxEmulator` for the fill rules.
+pub struct PaperBroker<B: BrokerApi> {
+    market: B,
+    emulator: SandboxEmulator,
+    client: RestClient,
+    account_id: String,
+    depth: i64,
+    max_book_age: Duration,
+    // instruments registered with the emulator, and when each got its latest order book
+    known: Mutex<HashSet<String>>,
+    booked: Mutex<HashMap<String, Instant>>,
+}
+
+impl<B: BrokerApi> PaperBroker<B> {
+    /// Opens an empty paper account that trades on `market`'s data.
+    pub fn new(market: B) -> Result<Self> {
+        let emulator = SandboxEmulator::new();
+        let client = emulator.client();
+        let account_id = client.sandbox_register()?.id;
+        Ok(Self {
+            market,
+            emulator,
+            client,
+            account_id,
+            depth: MAX_ORDERBOOK_DEPTH,
+            max_book_age: Duration::from_secs(1),
+            known: Mutex::new(HashSet::new()),
+            booked: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Depth of the books polled before orders. Defaults to `MAX_ORDERBOOK_DEPTH`.
+    pub fn depth(mut self, depth: i64) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Age after which an order book is polled again before an order. Defaults
+    /// to one second; zero polls a fresh book for every order.
+    pub fn max_book_age(mut self, age: Duration) -> Self {
+        self.max_book_age = age;
+        self
+    }
+
+    /// Id of the paper account, also returned by `accounts`.
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    /// Sets the paper balance of a currency.
+    pub fn set_balance(&self, currency: Currency, balance: Decimal) -> Result<()> {
+        self.client.sandbox_set_currency_balance(&self.account_id, currency, balance)
+    }
+
+    /// Sets the paper position in an instrument, in pieces.
+    pub fn set_position(&self, figi: &str, balance: Decimal) -> Result<()> {
+        self.register(figi)?;
+        self.client.sandbox_set_position_balance(&self.account_id, figi, balance)
+    }
+
+    /// Matches orders against a streaming order book snapshot.
+    pub fn on_orderbook(&self, event: &OrderBookEvent) -> Result<()> {
+        self.set_orderbook(LocalOrderBook::from(event))
+    }
+
+    /// Fetches the order book of `figi` from the market and matches orders against it.
+    pub fn refresh(&self, figi: &str) -> Result<RestOrderBook> {
+        self.orderbook(self.depth, figi)
+    }
+
+    pub fn market(&self) -> &B {
+        &self.market
+    }
+
+    fn set_orderbook(&self, book: LocalOrderBook) -> Result<()> {
+        self.register(&book.figi)?;
+        self.booked.lock().unwrap().insert(book.figi.clone(), Instant::now());
+        self.emulator.set_orderbook(book);
+        Ok(())
+    }
+
+    fn register(&self, figi: &str) -> Result<()> {
+        if self.known.lock().unwrap().contains(figi) {
+            return Ok(());
+        }
+        let instrument = self.market.instrument_by_figi(figi)?;
+        self.emulator.add_instrument(instrument);
+        self.known.lock().unwrap().insert(figi.to_string());
+        Ok(())
+    }
+
+    // Polls a book for instruments that have none or a stale one, so orders trade against current prices.
+    fn prepare_order(&self, figi: &str) -> Result<()> {
+        let updated = self.booked.lock().unwrap().get(figi).copied();
+        if updated.is_none_or(|updated| updated.elapsed() >= self.max_book_age) {
+            self.refresh(figi)?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: BrokerApi> BrokerApi for PaperBroker<B> {
+    fn instrument_by_figi(&self, figi: &str) -> Result<Instrument> {
+        self.market.instrument_by_figi(figi)
+    }
+
+    fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments> {
+        self.market.instrument_by_ticker(ticker)
+    }
+
+    fn stocks(&self) -> Result<Instruments> {
+        self.market.stocks()
+    }
+
+    fn bonds(&self) -> Result<Instruments> {
+        self.market.bonds()
+    }
+
+    fn etfs(&self) -> Result<Instruments> {
+        self.market.etfs()
+    }
+
+    fn currencies(&self) -> Result<Instruments> {
+        self.market.currencies()
+    }
+
+    fn candles(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        self.market.candles(from, to, interval, figi)
+    }
+
+    fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
+        let book = self.market.orderbook(depth, figi)?;
+        self.set_orderbook(LocalOrderBook::from(&book))?;
+        Ok(book)
+    }
+
+    fn portfolio(&self, account_id: &str) -> Result<Portfolio> {
+        self.client.portfolio(account_id)
+    }
+
+    fn operations(
+        &self,
+        account_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        figi: &str,
+    ) -> Result<Operations> {
+        self.client.operations(account_id, from, to, figi)
+    }
+
+    fn orders(&self, account_id: &str) -> Result<Orders> {
+        self.client.orders(account_id)
+    }
+
+    fn limit_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+        price: Decimal,
+    ) -> Result<PlacedOrder> {
+        self.prepare_order(figi)?;
+        self.client.limit_order(account_id, figi, lots, operation, price)
+    }
+
+    fn market_order(&self, account_id: &str, figi: &str, lots: i64, operation: OperationType) -> Result<PlacedOrder> {
+        self.prepare_order(figi)?;
+        self.client.market_order(account_id, figi, lots, operation)
+    }
+
+    fn order_cancel(&self, account_id: &str, id: &str) -> Result<()> {
+        self.client.order_cancel(account_id, id)
+    }
+
+    fn accounts(&self) -> Result<Accounts> {
+        self.client.accounts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::Level;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    // A market whose order book of "FIGI" offers `asks` and bids 1 lot at 90.
+    fn market(asks: &[(&str, &str)]) -> SandboxEmulator {
+        let market = SandboxEmulator::new();
+        market.add_instrument(Instrument {
+            figi: "FIGI".to_string(),
+            ticker: "FIGI".to_string(),
+            isin: String::new(),
+            name: "FIGI".to_string(),
+            min_price_increment: Decimal::new(1, 2),
+            lot: 1,
+            currency: Currency::Rub,
+            r#type: InstrumentType::Stock,
+        });
+        set_asks(&market, asks);
+        market
+    }
+
+    fn set_asks(market: &SandboxEmulator, asks: &[(&str, &str)]) {
+        let level = |(price, quantity): &(&str, &str)| Level {
+            price: dec(price),
+            quantity: dec(quantity),
+        };
+        market.set_orderbook(LocalOrderBook {
+            figi: "FIGI".to_string(),
+            depth: MAX_ORDERBOOK_DEPTH,
+            bids: vec![level(&("90", "1"))],
+            asks: asks.iter().map(level).collect(),
+            time: None,
+        });
+    }
+
+    fn broker(market: &SandboxEmulator, max_book_age: Duration) -> PaperBroker<RestClient> {
+        let broker = PaperBroker::new(market.client()).unwrap().max_book_age(max_book_age);
+        broker.set_balance(Currency::Rub, dec("1000000")).unwrap();
+        broker
+    }
+
+    #[test]
+    fn market_orders_fill_only_visible_depth() {
+        let market = market(&[("100", "3"), ("101", "2")]);
+        let broker = broker(&market, Duration::from_secs(3600));
+        let account = broker.account_id().to_string();
+
+        let placed = broker.market_order(&account, "FIGI", 10_000, OperationType::Buy).unwrap();
+        assert_eq!(placed.status, OrderStatus::PartiallyFill);
+        assert_eq!(placed.executed_lots, 5);
+        assert!(broker.orders(&account).unwrap().orders.is_empty());
+        let rub = broker.portfolio(&account).unwrap().currencies.currencies;
+        assert_eq!(rub[0].balance, dec("1000000") - dec("502"));
+        assert_eq!(rub[0].blocked, Decimal::ZERO);
+    }
+
+    #[test]
+    fn orders_consume_the_book_until_it_is_refreshed() {
+        let market = market(&[("100", "3"), ("101", "2")]);
+        let broker = broker(&market, Duration::from_secs(3600));
+        let account = broker.account_id().to_string();
+
+        assert_eq!(broker.market_order(&account, "FIGI", 4, OperationType::Buy).unwrap().executed_lots, 4);
+        assert_eq!(broker.market_order(&account, "FIGI", 4, OperationType::Buy).unwrap().executed_lots, 1);
+
+        broker.refresh("FIGI").unwrap();
+        assert_eq!(broker.market_order(&account, "FIGI", 4, OperationType::Buy).unwrap().executed_lots, 4);
+    }
+
+    #[test]
+    fn stale_books_are_polled_before_orders() {
+        let market = market(&[("100", "3")]);
+        let broker = broker(&market, Duration::ZERO);
+        let account = broker.account_id().to_string();
+
+        assert_eq!(broker.market_order(&account, "FIGI", 3, OperationType::Buy).unwrap().executed_lots, 3);
+        set_asks(&market, &[("105", "3")]);
+        let placed = broker.market_order(&account, "FIGI", 3, OperationType::Buy).unwrap();
+        assert_eq!(placed.executed_lots, 3);
+        let positions = broker.portfolio(&account).unwrap().positions.positions;
+        assert_eq!(positions[0].balance, dec("6"));
+        let rub = broker.portfolio(&account).unwrap().currencies.currencies;
+        assert_eq!(rub[0].balance, dec("1000000") - dec("300") - dec("315"));
+    }
+
+    #[test]
+    fn limit_orders_rest_until_later_books_reach_them() {
+        let market = market(&[("100", "2"), ("102", "5")]);
+        let broker = broker(&market, Duration::from_secs(3600));
+        let account = broker.account_id().to_string();
+
+        let placed = broker.limit_order(&account, "FIGI", 5, OperationType::Buy, dec("101")).unwrap();
+        assert_eq!(placed.status, OrderStatus::PartiallyFill);
+        assert_eq!(placed.executed_lots, 2);
+
+        set_asks(&market, &[("101", "10")]);
+        broker.refresh("FIGI").unwrap();
+        assert!(broker.orders(&account).unwrap().orders.is_empty());
+        let positions = broker.portfolio(&account).unwrap().positions.positions;
+        assert_eq!(positions[0].balance, dec("5"));
+        let rub = broker.portfolio(&account).unwrap().currencies.currencies;
+        assert_eq!(rub[0].balance, dec("1000000") - dec("200") - dec("303"));
+        assert_eq!(rub[0].blocked, Decimal::ZERO);
+    }
+}
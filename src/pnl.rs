@@ -0,0 +1,515 @@
+// Profit and loss from an account's operation history.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+
+use crate::*;
+
+/// How sells are matched against earlier buys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CostMethod {
+    /// Oldest lots are closed first.
+    Fifo,
+    /// All open securities share one average price.
+    AverageCost,
+}
+
+/// Open securities bought (or sold short) together at one price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lot {
+    /// Negative for a short lot.
+    pub quantity: i64,
+    /// Cost per piece, including the trade commission when that is counted.
+    pub price: Decimal,
+    pub opened: DateTime<Utc>,
+}
+
+/// Part of a lot that was closed by an opposite trade or a repayment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosedLot {
+    /// Negative when a short lot was closed.
+    pub quantity: i64,
+    pub open_price: Decimal,
+    /// Proceeds per piece, net of the trade commission when that is counted.
+    pub close_price: Decimal,
+    pub opened: DateTime<Utc>,
+    pub closed: DateTime<Utc>,
+    pub pnl: Decimal,
+    /// Closed by a `SecurityOut` transfer rather than a trade.
+    pub transfer: bool,
+}
+
+/// Result of replaying the operations of one instrument.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionPnl {
+    pub figi: String,
+    pub currency: Currency,
+    pub open_lots: Vec<Lot>,
+    pub closed_lots: Vec<ClosedLot>,
+    /// Sum of `pnl` over the closed lots.
+    pub realized: Decimal,
+    /// Dividends and coupons received, before tax.
+    pub income: Decimal,
+    /// Commission operations charged for the instrument, as a positive amount.
+    pub commissions: Decimal,
+    /// Taxes withheld for the instrument, as a positive amount.
+    pub taxes: Decimal,
+}
+
+impl PositionPnl {
+    fn new(figi: &str, currency: Currency) -> Self {
+        Self {
+            figi: figi.to_string(),
+            currency,
+            open_lots: vec![],
+            closed_lots: vec![],
+            realized: Decimal::ZERO,
+            income: Decimal::ZERO,
+            commissions: Decimal::ZERO,
+            taxes: Decimal::ZERO,
+        }
+    }
+
+    /// Pieces held, negative for a short position.
+    pub fn quantity(&self) -> i64 {
+        self.open_lots.iter().map(|l| l.quantity).sum()
+    }
+
+    pub fn cost_basis(&self) -> Decimal {
+        self.open_lots.iter().map(|l| l.price * Decimal::from(l.quantity)).sum()
+    }
+
+    pub fn average_price(&self) -> Option<Decimal> {
+        match self.quantity() {
+            0 => None,
+            quantity => Some(self.cost_basis() / Decimal::from(quantity)),
+        }
+    }
+
+    /// P&L of the open lots if they were closed at `price`.
+    pub fn unrealized(&self, price: Decimal) -> Decimal {
+        self.open_lots
+            .iter()
+            .map(|l| (price - l.price) * Decimal::from(l.quantity))
+            .sum()
+    }
+
+    /// Realized and unrealized P&L plus income, minus commissions and taxes.
+    pub fn total(&self, price: Decimal) -> Decimal {
+        self.realized + self.unrealized(price) + self.income - self.commissions - self.taxes
+    }
+}
+
+/// P&L of every instrument found in the operations.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PnlReport {
+    pub positions: BTreeMap<String, PositionPnl>,
+    /// Commissions and taxes not tied to an instrument, e.g. service fees, as positive amounts.
+    pub account_charges: HashMap<Currency, Decimal>,
+}
+
+impl PnlReport {
+    pub fn position(&self, figi: &str) -> Option<&PositionPnl> {
+        self.positions.get(figi)
+    }
+}
+
+/// Replays operations into a `PnlReport`.
+///
+/// The API books a trade's commission twice: in the `commission` field of the
+/// `Buy`/`Sell` operation and as a separate `BrokerCommission` operation. By
+/// default the separate operations are counted in `PositionPnl::commissions`
+/// and lot prices are the bare trade prices. Sources that only fill in the
+/// `commission` field, such as the sandbox emulator, should enable
+/// `trade_commission`, which folds it into lot prices instead.
+pub struct PnlCalculator {
+    method: CostMethod,
+    trade_commission: bool,
+}
+
+impl PnlCalculator {
+    pub fn new(method: CostMethod) -> Self {
+        Self {
+            method,
+            trade_commission: false,
+        }
+    }
+
+    pub fn trade_commission(mut self, trade_commission: bool) -> Self {
+        self.trade_commission = trade_commission;
+        self
+    }
+
+    /// Operations may come in any order; only completed ones are counted.
+    pub fn replay(&self, operations: &[Operation]) -> PnlReport {
+        let mut operations: Vec<&Operation> =
+            operations.iter().filter(|o| o.status == OperationStatus::Done).collect();
+        operations.sort_by_key(|o| o.date_time);
+
+        let mut report = PnlReport::default();
+        for operation in operations {
+            if operation.figi.is_empty() {
+                let charge = match &operation.operation_type {
+                    t if is_commission(t) || is_tax(t) => operation.payment.abs(),
+                    OperationType::TaxBack => -operation.payment.abs(),
+                    _ => continue,
+                };
+                *report.account_charges.entry(operation.currency.clone()).or_default() += charge;
+                continue;
+            }
+            let position = report
+                .positions
+                .entry(operation.figi.clone())
+                .or_insert_with(|| PositionPnl::new(&operation.figi, operation.currency.clone()));
+            self.apply(position, operation);
+        }
+        report
+    }
+
+    fn apply(&self, position: &mut PositionPnl, operation: &Operation) {
+        let time = operation.date_time;
+        match &operation.operation_type {
+            OperationType::Buy | OperationType::BuyCard | OperationType::Sell => {
+                let sign = if operation.operation_type == OperationType::Sell { -1 } else { 1 };
+                let fills = fills(operation);
+                let quantity: i64 = fills.iter().map(|(_, q)| q).sum();
+                let commission = match &operation.commission {
+                    Some(commission) if self.trade_commission && quantity > 0 => commission.value.abs(),
+                    _ => Decimal::ZERO,
+                };
+                for (price, fill_quantity) in fills {
+                    // spread the commission over the pieces, making buys dearer and sells cheaper
+                    let per_piece = commission / Decimal::from(quantity);
+                    let price = price + per_piece * Decimal::from(sign);
+                    self.trade(position, sign * fill_quantity, price, time);
+                }
+            }
+            OperationType::SecurityIn => {
+                self.trade(position, transfer_quantity(operation), operation.price, time);
+            }
+            OperationType::SecurityOut => {
+                // transferred away at cost, so nothing is realized
+                let quantity = transfer_quantity(operation);
+                let closed = self.close(position, quantity, None, time);
+                position.closed_lots.extend(closed);
+            }
+            OperationType::Dividend | OperationType::Coupon => position.income += operation.payment,
+            OperationType::PartRepayment => {
+                // returned principal lowers the cost of the pieces still held
+                let quantity = position.quantity();
+                if quantity != 0 {
+                    let per_piece = operation.payment / Decimal::from(quantity);
+                    for lot in &mut position.open_lots {
+                        lot.price -= per_piece;
+                    }
+                }
+            }
+            OperationType::Repayment => {
+                let quantity = position.quantity();
+                if quantity > 0 {
+                    let price = operation.payment / Decimal::from(quantity);
+                    self.trade(position, -quantity, price, time);
+                }
+            }
+            t if is_commission(t) => position.commissions += operation.payment.abs(),
+            t if is_tax(t) => position.taxes += operation.payment.abs(),
+            OperationType::TaxBack => position.taxes -= operation.payment.abs(),
+            _ => {}
+        }
+    }
+
+    // Books a signed trade: it first closes lots of the opposite sign, the rest opens a new lot.
+    fn trade(&self, position: &mut PositionPnl, quantity: i64, price: Decimal, time: DateTime<Utc>) {
+        let held = position.quantity();
+        let closing = if held.signum() == -quantity.signum() {
+            quantity.abs().min(held.abs())
+        } else {
+            0
+        };
+        if closing > 0 {
+            let closed = self.close(position, closing, Some(price), time);
+            position.realized += closed.iter().map(|c| c.pnl).sum::<Decimal>();
+            position.closed_lots.extend(closed);
+        }
+        let opening = quantity - quantity.signum() * closing;
+        if opening == 0 {
+            return;
+        }
+        position.open_lots.push(Lot {
+            quantity: opening,
+            price,
+            opened: time,
+        });
+        if self.method == CostMethod::AverageCost && position.open_lots.len() > 1 {
+            let average = position.average_price().unwrap_or(price);
+            let opened = position.open_lots[0].opened;
+            position.open_lots = vec![Lot {
+                quantity: position.quantity(),
+                price: average,
+                opened,
+            }];
+        }
+    }
+
+    // Takes `quantity` pieces out of the open lots, oldest first. Without a
+    // close price the pieces leave at cost.
+    fn close(
+        &self,
+        position: &mut PositionPnl,
+        quantity: i64,
+        price: Option<Decimal>,
+        time: DateTime<Utc>,
+    ) -> Vec<ClosedLot> {
+        let mut closed = vec![];
+        let mut remaining = quantity;
+        let mut lots: VecDeque<Lot> = position.open_lots.drain(..).collect();
+        while remaining > 0 {
+            let mut lot = match lots.pop_front() {
+                Some(lot) => lot,
+                None => break,
+            };
+            let taken = remaining.min(lot.quantity.abs());
+            let signed = taken * lot.quantity.signum();
+            let close_price = price.unwrap_or(lot.price);
+            closed.push(ClosedLot {
+                quantity: signed,
+                open_price: lot.price,
+                close_price,
+                opened: lot.opened,
+                closed: time,
+                pnl: (close_price - lot.price) * Decimal::from(signed),
+                transfer: price.is_none(),
+            });
+            remaining -= taken;
+            lot.quantity -= signed;
+            if lot.quantity != 0 {
+                lots.push_front(lot);
+            }
+        }
+        position.open_lots = lots.into();
+        closed
+    }
+}
+
+// Price and quantity of each fill. Operations without trades count as one
+// fill of the executed quantity; the requested quantity may never have traded.
+fn fills(operation: &Operation) -> Vec<(Decimal, i64)> {
+    if operation.trades.is_empty() {
+        if operation.quantity_executed == 0 {
+            return vec![];
+        }
+        return vec![(operation.price, operation.quantity_executed)];
+    }
+    operation.trades.iter().map(|t| (t.price, t.quantity)).collect()
+}
+
+// Transfers often leave the executed quantity empty, so fall back to the requested one.
+fn transfer_quantity(operation: &Operation) -> i64 {
+    if operation.quantity_executed != 0 {
+        operation.quantity_executed
+    } else {
+        operation.quantity
+    }
+}
+
+fn is_commission(operation_type: &OperationType) -> bool {
+    matches!(
+        operation_type,
+        OperationType::BrokerCommission
+            | OperationType::ExchangeCommission
+            | OperationType::ServiceCommission
+            | OperationType::MarginCommission
+            | OperationType::OtherCommission
+    )
+}
+
+fn is_tax(operation_type: &OperationType) -> bool {
+    matches!(
+        operation_type,
+        OperationType::Tax | OperationType::TaxLucre | OperationType::TaxDividend | OperationType::TaxCoupon
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn operation(day: u32, operation_type: OperationType, quantity: i64, price: &str) -> Operation {
+        let date_time = format!("2021-03-{:02}T10:00:00Z", day).parse().unwrap();
+        let price = dec(price);
+        let trades = match operation_type {
+            OperationType::Buy | OperationType::Sell => vec![Trade {
+                id: format!("t{}", day),
+                date_time,
+                price,
+                quantity,
+            }],
+            _ => vec![],
+        };
+        let sign = if operation_type == OperationType::Buy { -1 } else { 1 };
+        Operation {
+            id: format!("o{}", day),
+            status: OperationStatus::Done,
+            trades,
+            commission: None,
+            currency: Currency::Rub,
+            payment: price * Decimal::from(quantity * sign),
+            price,
+            quantity,
+            quantity_executed: quantity,
+            figi: "FIGI".to_string(),
+            instrument_type: Some(InstrumentType::Stock),
+            is_margin_call: false,
+            date_time,
+            operation_type,
+        }
+    }
+
+    fn payment(day: u32, operation_type: OperationType, amount: &str) -> Operation {
+        Operation {
+            payment: dec(amount),
+            quantity: 0,
+            quantity_executed: 0,
+            ..operation(day, operation_type, 0, "0")
+        }
+    }
+
+    fn fifo(operations: &[Operation]) -> PositionPnl {
+        PnlCalculator::new(CostMethod::Fifo).replay(operations).positions["FIGI"].clone()
+    }
+
+    #[test]
+    fn fifo_closes_oldest_lots_first() {
+        let position = fifo(&[
+            operation(1, OperationType::Buy, 10, "100"),
+            operation(2, OperationType::Buy, 10, "110"),
+            operation(3, OperationType::Sell, 15, "120"),
+        ]);
+        assert_eq!(position.closed_lots.len(), 2);
+        assert_eq!((position.closed_lots[0].quantity, position.closed_lots[0].pnl), (10, dec("200")));
+        assert_eq!((position.closed_lots[1].quantity, position.closed_lots[1].pnl), (5, dec("50")));
+        assert_eq!(position.realized, dec("250"));
+        assert_eq!(position.quantity(), 5);
+        assert_eq!(position.average_price(), Some(dec("110")));
+        assert_eq!(position.unrealized(dec("100")), dec("-50"));
+    }
+
+    #[test]
+    fn short_positions_open_and_close() {
+        let position = fifo(&[
+            operation(1, OperationType::Sell, 10, "100"),
+            operation(2, OperationType::Buy, 4, "90"),
+        ]);
+        assert_eq!(position.closed_lots[0].quantity, -4);
+        assert_eq!(position.realized, dec("40"));
+        assert_eq!(position.quantity(), -6);
+        assert_eq!(position.unrealized(dec("110")), dec("-60"));
+
+        // a buy larger than the short closes it and opens a long lot
+        let position = fifo(&[
+            operation(1, OperationType::Sell, 10, "100"),
+            operation(2, OperationType::Buy, 15, "90"),
+        ]);
+        assert_eq!(position.realized, dec("100"));
+        assert_eq!(position.open_lots.len(), 1);
+        assert_eq!((position.open_lots[0].quantity, position.open_lots[0].price), (5, dec("90")));
+    }
+
+    #[test]
+    fn average_cost_merges_lots() {
+        let operations = [
+            operation(1, OperationType::Buy, 10, "100"),
+            operation(2, OperationType::Buy, 30, "120"),
+            operation(3, OperationType::Sell, 20, "130"),
+        ];
+        let report = PnlCalculator::new(CostMethod::AverageCost).replay(&operations);
+        let position = &report.positions["FIGI"];
+        assert_eq!(position.closed_lots.len(), 1);
+        assert_eq!(position.closed_lots[0].open_price, dec("115"));
+        assert_eq!(position.realized, dec("300"));
+        assert_eq!(position.open_lots.len(), 1);
+        assert_eq!((position.quantity(), position.average_price()), (20, Some(dec("115"))));
+    }
+
+    #[test]
+    fn part_repayment_lowers_cost() {
+        let position = fifo(&[
+            operation(1, OperationType::Buy, 10, "1000"),
+            payment(2, OperationType::PartRepayment, "2500"),
+            payment(3, OperationType::Coupon, "300"),
+            operation(4, OperationType::Sell, 10, "800"),
+        ]);
+        assert_eq!(position.closed_lots[0].open_price, dec("750"));
+        assert_eq!(position.realized, dec("500"));
+        assert_eq!(position.income, dec("300"));
+    }
+
+    #[test]
+    fn security_out_realizes_nothing() {
+        let transfer = Operation {
+            quantity_executed: 0,
+            ..operation(2, OperationType::SecurityOut, 4, "0")
+        };
+        let position = fifo(&[operation(1, OperationType::Buy, 10, "100"), transfer]);
+        assert_eq!(position.realized, Decimal::ZERO);
+        assert_eq!(position.quantity(), 6);
+        assert!(position.closed_lots[0].transfer);
+        assert_eq!(position.closed_lots[0].close_price, dec("100"));
+    }
+
+    #[test]
+    fn trade_commission_is_folded_into_prices() {
+        let mut buy = operation(1, OperationType::Buy, 10, "100");
+        buy.commission = Some(MoneyAmount {
+            currency: Currency::Rub,
+            value: dec("-5"),
+        });
+        let mut sell = operation(2, OperationType::Sell, 10, "110");
+        sell.commission = Some(MoneyAmount {
+            currency: Currency::Rub,
+            value: dec("-5"),
+        });
+        let commission = payment(2, OperationType::BrokerCommission, "-5");
+        let operations = [buy, sell, commission];
+
+        let folded = PnlCalculator::new(CostMethod::Fifo).trade_commission(true).replay(&operations);
+        let position = &folded.positions["FIGI"];
+        assert_eq!(position.closed_lots[0].open_price, dec("100.5"));
+        assert_eq!(position.closed_lots[0].close_price, dec("109.5"));
+        assert_eq!(position.realized, dec("90"));
+        // the separate commission operation is still counted as booked
+        assert_eq!(position.total(Decimal::ZERO), dec("85"));
+
+        let separate = fifo(&operations);
+        assert_eq!(separate.realized, dec("100"));
+        assert_eq!(separate.commissions, dec("5"));
+    }
+
+    #[test]
+    fn trades_without_fills_use_the_executed_quantity() {
+        let mut partial = operation(1, OperationType::Buy, 10, "100");
+        partial.trades.clear();
+        partial.quantity_executed = 4;
+        let mut unfilled = operation(2, OperationType::Buy, 10, "100");
+        unfilled.trades.clear();
+        unfilled.quantity_executed = 0;
+        let position = fifo(&[partial, unfilled]);
+        assert_eq!(position.quantity(), 4);
+    }
+
+    #[test]
+    fn account_charges_and_declined_operations() {
+        let mut fee = payment(1, OperationType::ServiceCommission, "-99");
+        fee.figi.clear();
+        let mut declined = operation(2, OperationType::Buy, 10, "100");
+        declined.status = OperationStatus::Decline;
+        let report = PnlCalculator::new(CostMethod::Fifo).replay(&[fee, declined]);
+        assert_eq!(report.account_charges[&Currency::Rub], dec("99"));
+        assert!(report.position("FIGI").is_none());
+    }
+}
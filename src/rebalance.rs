@@ -0,0 +1,389 @@
+// Orders that move a portfolio towards target weights.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+use rust_decimal::prelude::ToPrimitive;
+
+use crate::*;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A target key matches neither the figi nor the ticker of any instrument.
+    UnknownTarget(String),
+    /// A held security is missing from the instruments, so its value is unknown.
+    MissingInstrument(String),
+    /// A held or targeted instrument has no positive price.
+    NoPrice(String),
+    /// A targeted instrument trades in another currency than the planner's.
+    WrongCurrency { figi: String, currency: Currency },
+    InvalidWeights(Decimal),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownTarget(key) => write!(f, "no instrument with figi or ticker {}", key),
+            Error::MissingInstrument(figi) => write!(f, "no instrument for held figi {}", figi),
+            Error::NoPrice(figi) => write!(f, "no price for {}", figi),
+            Error::WrongCurrency { figi, currency } => write!(f, "{} trades in {}", figi, currency),
+            Error::InvalidWeights(sum) => write!(f, "target weights must be non-negative and sum to at most 1, got {}", sum),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+/// An order to place with `limit_order` or `market_order`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRequest {
+    pub figi: String,
+    pub ticker: String,
+    pub operation: OperationType,
+    pub lots: i64,
+    /// The price the plan was computed with.
+    pub price: Decimal,
+}
+
+impl OrderRequest {
+    pub fn place_limit<B: BrokerApi + ?Sized>(&self, broker: &B, account_id: &str) -> rest_client::Result<PlacedOrder> {
+        broker.limit_order(account_id, &self.figi, self.lots, self.operation.clone(), self.price)
+    }
+
+    pub fn place_market<B: BrokerApi + ?Sized>(&self, broker: &B, account_id: &str) -> rest_client::Result<PlacedOrder> {
+        broker.market_order(account_id, &self.figi, self.lots, self.operation.clone())
+    }
+}
+
+/// Weights of one targeted instrument before and after the plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub figi: String,
+    pub ticker: String,
+    pub target: Decimal,
+    pub current: Decimal,
+    pub planned: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// Sells first, so their proceeds are available to the buys.
+    pub orders: Vec<OrderRequest>,
+    pub allocations: Vec<Allocation>,
+    /// Cash plus holdings in the planner's currency.
+    pub total: Decimal,
+    pub cash_before: Decimal,
+    pub cash_after: Decimal,
+}
+
+/// Plans whole-lot orders that bring the holdings of one currency closest to
+/// target weights.
+///
+/// Weights are fractions of the free cash in the currency plus the value of
+/// every security held in it. Whatever the weights leave unassigned stays in
+/// cash. Held securities without a target are counted in the total but not
+/// traded. Each targeted instrument is rounded to the nearest whole lot; buys
+/// are then cut down to the cash left after the sells, largest shortfall first.
+pub struct RebalancePlanner {
+    currency: Currency,
+    min_trade: Decimal,
+}
+
+impl RebalancePlanner {
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            currency,
+            min_trade: Decimal::ZERO,
+        }
+    }
+
+    /// Orders worth less than `min_trade` are dropped.
+    pub fn min_trade(mut self, min_trade: Decimal) -> Self {
+        self.min_trade = min_trade;
+        self
+    }
+
+    /// `targets` maps a figi or ticker to a weight. `instruments` must include
+    /// every targeted and held instrument, and `prices` has a price by figi
+    /// for each of them.
+    pub fn plan(
+        &self,
+        portfolio: &Portfolio,
+        targets: &HashMap<String, Decimal>,
+        instruments: &[Instrument],
+        prices: &HashMap<String, Decimal>,
+    ) -> Result<Plan> {
+        let weight_sum: Decimal = targets.values().sum();
+        if weight_sum > Decimal::ONE || targets.values().any(|w| w.is_sign_negative()) {
+            return Err(Error::InvalidWeights(weight_sum));
+        }
+        let price_of = |figi: &str| {
+            prices
+                .get(figi)
+                .copied()
+                .filter(|price| *price > Decimal::ZERO)
+                .ok_or_else(|| Error::NoPrice(figi.to_string()))
+        };
+
+        let cash_before: Decimal = portfolio
+            .currencies
+            .currencies
+            .iter()
+            .filter(|c| c.currency == self.currency)
+            .map(|c| c.balance - c.blocked)
+            .sum();
+        let mut total = cash_before;
+        for position in &portfolio.positions.positions {
+            if position.instrument_type == InstrumentType::Currency {
+                continue;
+            }
+            let instrument = instruments
+                .iter()
+                .find(|i| i.figi == position.figi)
+                .ok_or_else(|| Error::MissingInstrument(position.figi.clone()))?;
+            if instrument.currency == self.currency {
+                total += price_of(&position.figi)? * position.balance;
+            }
+        }
+
+        let mut legs = vec![];
+        for (key, weight) in targets {
+            let instrument = instruments
+                .iter()
+                .find(|i| i.figi == *key)
+                .or_else(|| instruments.iter().find(|i| i.ticker == *key))
+                .ok_or_else(|| Error::UnknownTarget(key.clone()))?;
+            if instrument.currency != self.currency {
+                return Err(Error::WrongCurrency {
+                    figi: instrument.figi.clone(),
+                    currency: instrument.currency.clone(),
+                });
+            }
+            let held = portfolio.positions.positions.iter().find(|p| p.figi == instrument.figi);
+            let lot_value = price_of(&instrument.figi)? * Decimal::from(instrument.lot.max(1));
+            let (balance, free) = held.map_or((Decimal::ZERO, Decimal::ZERO), |p| (p.balance, p.balance - p.blocked));
+            let lot = Decimal::from(instrument.lot.max(1));
+            let wanted = (*weight * total / lot_value).round().to_i64().unwrap_or(0);
+            let held_lots = (balance / lot).floor().to_i64().unwrap_or(0);
+            let free_lots = (free / lot).floor().to_i64().unwrap_or(0);
+            legs.push(Leg {
+                instrument,
+                target: *weight,
+                balance,
+                lot_value,
+                // only free whole lots can be sold
+                delta: (wanted - held_lots).max(-free_lots),
+            });
+        }
+
+        legs.sort_by(|a, b| a.instrument.figi.cmp(&b.instrument.figi));
+
+        let mut cash = cash_before;
+        let mut orders = vec![];
+        for leg in legs.iter_mut().filter(|l| l.delta < 0) {
+            let value = leg.lot_value * Decimal::from(-leg.delta);
+            if value < self.min_trade {
+                leg.delta = 0;
+                continue;
+            }
+            cash += value;
+            orders.push(leg.order(OperationType::Sell, -leg.delta));
+        }
+        let mut buys: Vec<&mut Leg> = legs.iter_mut().filter(|l| l.delta > 0).collect();
+        buys.sort_by_key(|l| std::cmp::Reverse(l.lot_value * Decimal::from(l.delta)));
+        for leg in buys {
+            let affordable = (cash / leg.lot_value).floor().to_i64().unwrap_or(0);
+            leg.delta = leg.delta.min(affordable);
+            let value = leg.lot_value * Decimal::from(leg.delta);
+            if leg.delta == 0 || value < self.min_trade {
+                leg.delta = 0;
+                continue;
+            }
+            cash -= value;
+            orders.push(leg.order(OperationType::Buy, leg.delta));
+        }
+
+        let weight = |value: Decimal| if total.is_zero() { Decimal::ZERO } else { value / total };
+        let allocations = legs
+            .iter()
+            .map(|leg| {
+                let price = leg.lot_value / Decimal::from(leg.instrument.lot.max(1));
+                Allocation {
+                    figi: leg.instrument.figi.clone(),
+                    ticker: leg.instrument.ticker.clone(),
+                    target: leg.target,
+                    current: weight(leg.balance * price),
+                    planned: weight(leg.balance * price + leg.lot_value * Decimal::from(leg.delta)),
+                }
+            })
+            .collect();
+
+        Ok(Plan {
+            orders,
+            allocations,
+            total,
+            cash_before,
+            cash_after: cash,
+        })
+    }
+}
+
+struct Leg<'a> {
+    instrument: &'a Instrument,
+    target: Decimal,
+    balance: Decimal,
+    lot_value: Decimal,
+    // lots to buy, negative to sell
+    delta: i64,
+}
+
+impl Leg<'_> {
+    fn order(&self, operation: OperationType, lots: i64) -> OrderRequest {
+        OrderRequest {
+            figi: self.instrument.figi.clone(),
+            ticker: self.instrument.ticker.clone(),
+            operation,
+            lots,
+            price: self.lot_value / Decimal::from(self.instrument.lot.max(1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn instrument(figi: &str, lot: i64) -> Instrument {
+        Instrument {
+            figi: figi.to_string(),
+            ticker: format!("{}-TICKER", figi),
+            isin: String::new(),
+            name: figi.to_string(),
+            min_price_increment: dec("0.01"),
+            lot,
+            currency: Currency::Rub,
+            r#type: InstrumentType::Stock,
+        }
+    }
+
+    fn instruments() -> Vec<Instrument> {
+        vec![instrument("A", 10), instrument("B", 1)]
+    }
+
+    fn prices() -> HashMap<String, Decimal> {
+        vec![("A".to_string(), dec("100")), ("B".to_string(), dec("333"))].into_iter().collect()
+    }
+
+    fn portfolio(cash: &str, positions: &[(&str, &str, &str)]) -> Portfolio {
+        Portfolio {
+            positions: PositionBalances {
+                positions: positions
+                    .iter()
+                    .map(|(figi, balance, blocked)| PositionBalance {
+                        figi: figi.to_string(),
+                        ticker: figi.to_string(),
+                        isin: String::new(),
+                        instrument_type: InstrumentType::Stock,
+                        balance: dec(balance),
+                        blocked: dec(blocked),
+                        lots: 0,
+                        expected_yield: None,
+                        average_position_price: None,
+                        average_position_price_no_nkd: None,
+                        name: figi.to_string(),
+                    })
+                    .collect(),
+            },
+            currencies: CurrencyBalances {
+                currencies: vec![CurrencyBalance {
+                    currency: Currency::Rub,
+                    balance: dec(cash),
+                    blocked: Decimal::ZERO,
+                }],
+            },
+        }
+    }
+
+    fn targets(targets: &[(&str, &str)]) -> HashMap<String, Decimal> {
+        targets.iter().map(|(key, weight)| (key.to_string(), dec(weight))).collect()
+    }
+
+    fn orders(plan: &Plan) -> Vec<(String, OperationType, i64)> {
+        plan.orders.iter().map(|o| (o.figi.clone(), o.operation.clone(), o.lots)).collect()
+    }
+
+    #[test]
+    fn rounds_to_whole_lots_and_caps_buys_at_cash() {
+        // A wants 5.6 lots of 1000 and is rounded to 6, B wants 13.2 pieces of 333
+        let targets = targets(&[("A", "0.56"), ("B-TICKER", "0.44")]);
+        let plan = RebalancePlanner::new(Currency::Rub)
+            .plan(&portfolio("10000", &[]), &targets, &instruments(), &prices())
+            .unwrap();
+        // the larger buy goes first and B only gets what is left
+        assert_eq!(
+            orders(&plan),
+            vec![("A".to_string(), OperationType::Buy, 6), ("B".to_string(), OperationType::Buy, 12)]
+        );
+        assert_eq!(plan.cash_after, dec("4"));
+        let a = plan.allocations.iter().find(|a| a.figi == "A").unwrap();
+        assert_eq!((a.current, a.planned), (Decimal::ZERO, dec("0.6")));
+    }
+
+    #[test]
+    fn sells_come_first_and_fund_buys() {
+        let targets = targets(&[("A", "0.5"), ("B", "0.5")]);
+        let plan = RebalancePlanner::new(Currency::Rub)
+            .plan(&portfolio("0", &[("A", "100", "0")]), &targets, &instruments(), &prices())
+            .unwrap();
+        assert_eq!(plan.total, dec("10000"));
+        assert_eq!(
+            orders(&plan),
+            vec![("A".to_string(), OperationType::Sell, 5), ("B".to_string(), OperationType::Buy, 15)]
+        );
+        assert_eq!(plan.cash_after, dec("5"));
+    }
+
+    #[test]
+    fn min_trade_drops_small_orders() {
+        let targets = targets(&[("A", "0.5"), ("B", "0.5")]);
+        let plan = RebalancePlanner::new(Currency::Rub)
+            .min_trade(dec("5000"))
+            .plan(&portfolio("0", &[("A", "100", "0")]), &targets, &instruments(), &prices())
+            .unwrap();
+        // the sell is worth exactly 5000, the buy only 4995
+        assert_eq!(orders(&plan), vec![("A".to_string(), OperationType::Sell, 5)]);
+        assert_eq!(plan.cash_after, dec("5000"));
+    }
+
+    #[test]
+    fn only_free_lots_are_sold() {
+        let targets = targets(&[("A", "0")]);
+        let plan = RebalancePlanner::new(Currency::Rub)
+            .plan(&portfolio("0", &[("A", "100", "55")]), &targets, &instruments(), &prices())
+            .unwrap();
+        assert_eq!(orders(&plan), vec![("A".to_string(), OperationType::Sell, 4)]);
+    }
+
+    #[test]
+    fn rejects_incomplete_inputs() {
+        let planner = RebalancePlanner::new(Currency::Rub);
+        let held = portfolio("1000", &[("X", "10", "0")]);
+        let error = planner.plan(&held, &targets(&[("A", "1")]), &instruments(), &prices()).unwrap_err();
+        assert_eq!(error, Error::MissingInstrument("X".to_string()));
+
+        let empty = portfolio("1000", &[]);
+        let error = planner.plan(&empty, &targets(&[("C", "1")]), &instruments(), &prices()).unwrap_err();
+        assert_eq!(error, Error::UnknownTarget("C".to_string()));
+        let error = planner.plan(&empty, &targets(&[("A", "0.7"), ("B", "0.4")]), &instruments(), &prices());
+        assert_eq!(error.unwrap_err(), Error::InvalidWeights(dec("1.1")));
+        let error = planner.plan(&empty, &targets(&[("A", "1")]), &instruments(), &HashMap::new()).unwrap_err();
+        assert_eq!(error, Error::NoPrice("A".to_string()));
+    }
+}
@@ -0,0 +1,310 @@
+// Aggregation of candles into coarser intervals, including ones the API does
+// not offer such as 45 minutes or 2 days.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use log::warn;
+
+use crate::*;
+
+// Moscow has been on UTC+3 all year round since 2014.
+pub(crate) const MOSCOW_OFFSET_HOURS: i64 = 3;
+
+/// Size of a resampled candle. Days, weeks and months follow the Moscow calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Timeframe {
+    Minutes(u32),
+    Days(u32),
+    /// Weeks starting on Monday.
+    Weeks(u32),
+    Months(u32),
+}
+
+impl Timeframe {
+    /// The interval stored in resampled candles: the matching `CandleInterval`
+    /// when the API has one, otherwise `CandleInterval::Unknown` with a name in
+    /// the same style, e.g. `"45min"` or `"2day"`.
+    pub fn interval(&self) -> CandleInterval {
+        let name = match *self {
+            Timeframe::Minutes(60) => "hour".to_string(),
+            Timeframe::Minutes(n) if n % 60 == 0 => format!("{}hour", n / 60),
+            Timeframe::Minutes(n) => format!("{}min", n),
+            Timeframe::Days(1) => "day".to_string(),
+            Timeframe::Days(n) => format!("{}day", n),
+            Timeframe::Weeks(1) => "week".to_string(),
+            Timeframe::Weeks(n) => format!("{}week", n),
+            Timeframe::Months(1) => "month".to_string(),
+            Timeframe::Months(n) => format!("{}month", n),
+        };
+        CandleInterval::from(name.as_str())
+    }
+}
+
+/// A `CandleInterval::Unknown` has no `Timeframe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownInterval(pub String);
+
+impl fmt::Display for UnknownInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no timeframe for candle interval {}", self.0)
+    }
+}
+
+impl StdError for UnknownInterval {}
+
+impl TryFrom<CandleInterval> for Timeframe {
+    type Error = UnknownInterval;
+
+    fn try_from(interval: CandleInterval) -> Result<Self, Self::Error> {
+        let timeframe = match interval {
+            CandleInterval::Min1 => Timeframe::Minutes(1),
+            CandleInterval::Min2 => Timeframe::Minutes(2),
+            CandleInterval::Min3 => Timeframe::Minutes(3),
+            CandleInterval::Min5 => Timeframe::Minutes(5),
+            CandleInterval::Min10 => Timeframe::Minutes(10),
+            CandleInterval::Min15 => Timeframe::Minutes(15),
+            CandleInterval::Min30 => Timeframe::Minutes(30),
+            CandleInterval::Hour1 => Timeframe::Minutes(60),
+            CandleInterval::Hour2 => Timeframe::Minutes(120),
+            CandleInterval::Hour4 => Timeframe::Minutes(240),
+            CandleInterval::Day1 => Timeframe::Days(1),
+            CandleInterval::Week1 => Timeframe::Weeks(1),
+            CandleInterval::Month1 => Timeframe::Months(1),
+            CandleInterval::Unknown(name) => return Err(UnknownInterval(name)),
+        };
+        Ok(timeframe)
+    }
+}
+
+impl fmt::Display for Timeframe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.interval().as_str())
+    }
+}
+
+/// Builds candles of a coarser `Timeframe` from a stream of finer candles of one
+/// instrument.
+///
+/// Intraday candles are aligned to the session start in Moscow time, 10:00 by
+/// default, so a 45 minute series has candles at 10:00, 10:45, 11:30 and so on
+/// every day. Days, weeks and months start at Moscow midnight.
+///
+/// Source candles may arrive more than once, as streaming candle events do
+/// while a candle is forming; a candle with the same time replaces the earlier one.
+pub struct Resampler {
+    timeframe: Timeframe,
+    session_start: NaiveTime,
+    bucket: Option<DateTime<Utc>>,
+    sources: BTreeMap<DateTime<Utc>, Candle>,
+}
+
+impl Resampler {
+    pub fn new(timeframe: Timeframe) -> Self {
+        Self {
+            timeframe,
+            session_start: NaiveTime::from_hms_opt(10, 0, 0).unwrap(),
+            bucket: None,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Moscow time that intraday candles are aligned to.
+    pub fn session_start(mut self, session_start: NaiveTime) -> Self {
+        self.session_start = session_start;
+        self
+    }
+
+    /// Adds a source candle. Returns the previous resampled candle once `candle`
+    /// starts a new one. Candles older than the one being built are ignored.
+    pub fn push(&mut self, candle: &Candle) -> Option<Candle> {
+        let bucket = self.bucket_start(candle.ts);
+        let completed = match self.bucket {
+            Some(current) if bucket < current => {
+                warn!("ignoring candle at {} older than the current {} candle", candle.ts, self.timeframe);
+                return None;
+            }
+            Some(current) if bucket > current => self.flush(),
+            _ => None,
+        };
+        self.bucket = Some(bucket);
+        self.sources.insert(candle.ts, candle.clone());
+        completed
+    }
+
+    /// The resampled candle built so far, which may still change.
+    pub fn current(&self) -> Option<Candle> {
+        let bucket = self.bucket?;
+        let mut sources = self.sources.values();
+        let first = sources.next()?;
+        let mut candle = Candle {
+            figi: first.figi.clone(),
+            interval: self.timeframe.interval(),
+            open_price: first.open_price,
+            close_price: first.close_price,
+            high_price: first.high_price,
+            low_price: first.low_price,
+            volume: first.volume,
+            ts: bucket,
+        };
+        for source in sources {
+            candle.close_price = source.close_price;
+            candle.high_price = candle.high_price.max(source.high_price);
+            candle.low_price = candle.low_price.min(source.low_price);
+            candle.volume += source.volume;
+        }
+        Some(candle)
+    }
+
+    /// Returns the candle being built and starts over, e.g. at the end of a series.
+    pub fn flush(&mut self) -> Option<Candle> {
+        let candle = self.current();
+        self.bucket = None;
+        self.sources.clear();
+        candle
+    }
+
+    /// Start of the resampled candle that contains `ts`.
+    pub fn bucket_start(&self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        let local = ts.naive_utc() + Duration::hours(MOSCOW_OFFSET_HOURS);
+        let date = local.date();
+        let start = match self.timeframe {
+            Timeframe::Minutes(n) => {
+                let n = i64::from(n.max(1));
+                let session = date.and_time(self.session_start);
+                let minutes = (local - session).num_minutes();
+                session + Duration::minutes(minutes.div_euclid(n) * n)
+            }
+            Timeframe::Days(n) => {
+                let day = i64::from(date.num_days_from_ce());
+                midnight_from_ce(day - day.rem_euclid(i64::from(n.max(1))))
+            }
+            Timeframe::Weeks(n) => {
+                // num_days_from_ce is 1 on Monday, January 1 of year 1
+                let week = (i64::from(date.num_days_from_ce()) - 1).div_euclid(7);
+                let week = week - week.rem_euclid(i64::from(n.max(1)));
+                midnight_from_ce(week * 7 + 1)
+            }
+            Timeframe::Months(n) => {
+                let month = i64::from(date.year()) * 12 + i64::from(date.month0());
+                let month = month - month.rem_euclid(i64::from(n.max(1)));
+                let date = NaiveDate::from_ymd_opt(month.div_euclid(12) as i32, month.rem_euclid(12) as u32 + 1, 1);
+                date.unwrap().and_hms_opt(0, 0, 0).unwrap()
+            }
+        };
+        Utc.from_utc_datetime(&(start - Duration::hours(MOSCOW_OFFSET_HOURS)))
+    }
+}
+
+/// Resamples a whole series of one instrument's candles into `timeframe`.
+/// The input does not have to be sorted.
+pub fn resample(candles: &[Candle], timeframe: Timeframe) -> Vec<Candle> {
+    let mut sorted: Vec<&Candle> = candles.iter().collect();
+    sorted.sort_by_key(|c| c.ts);
+    let mut resampler = Resampler::new(timeframe);
+    let mut resampled: Vec<Candle> = sorted.into_iter().filter_map(|c| resampler.push(c)).collect();
+    resampled.extend(resampler.flush());
+    resampled
+}
+
+fn midnight_from_ce(days: i64) -> NaiveDateTime {
+    NaiveDate::from_num_days_from_ce_opt(days as i32)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candle(ts: &str, open: i64, high: i64, low: i64, close: i64, volume: f64) -> Candle {
+        Candle {
+            figi: "FIGI".to_string(),
+            interval: CandleInterval::Min15,
+            open_price: Decimal::from(open),
+            close_price: Decimal::from(close),
+            high_price: Decimal::from(high),
+            low_price: Decimal::from(low),
+            volume,
+            ts: ts.parse().unwrap(),
+        }
+    }
+
+    fn utc(ts: &str) -> DateTime<Utc> {
+        ts.parse().unwrap()
+    }
+
+    #[test]
+    fn intraday_buckets_align_to_moscow_session_start() {
+        let resampler = Resampler::new(Timeframe::Minutes(45));
+        // 10:00 Moscow is 07:00 UTC
+        assert_eq!(resampler.bucket_start(utc("2021-03-01T07:00:00Z")), utc("2021-03-01T07:00:00Z"));
+        assert_eq!(resampler.bucket_start(utc("2021-03-01T07:44:59Z")), utc("2021-03-01T07:00:00Z"));
+        assert_eq!(resampler.bucket_start(utc("2021-03-01T07:45:00Z")), utc("2021-03-01T07:45:00Z"));
+        assert_eq!(resampler.bucket_start(utc("2021-03-01T08:40:00Z")), utc("2021-03-01T08:30:00Z"));
+        // before the session the grid extends backwards from 10:00
+        assert_eq!(resampler.bucket_start(utc("2021-03-01T06:59:00Z")), utc("2021-03-01T06:15:00Z"));
+    }
+
+    #[test]
+    fn days_weeks_and_months_start_at_moscow_midnight() {
+        // 22:30 UTC on Sunday is already 01:30 Monday in Moscow
+        let ts = utc("2021-02-28T22:30:00Z");
+        let start = |timeframe| Resampler::new(timeframe).bucket_start(ts);
+        assert_eq!(start(Timeframe::Days(1)), utc("2021-02-28T21:00:00Z"));
+        assert_eq!(start(Timeframe::Weeks(1)), utc("2021-02-28T21:00:00Z"));
+        assert_eq!(start(Timeframe::Months(1)), utc("2021-02-28T21:00:00Z"));
+        assert_eq!(start(Timeframe::Months(3)), utc("2020-12-31T21:00:00Z"));
+        // 20:59 UTC is still Sunday in Moscow
+        let sunday = Resampler::new(Timeframe::Weeks(1)).bucket_start(utc("2021-02-28T20:59:00Z"));
+        assert_eq!(sunday, utc("2021-02-21T21:00:00Z"));
+    }
+
+    #[test]
+    fn aggregates_prices_and_volume() {
+        let candles = vec![
+            candle("2021-03-01T07:30:00Z", 103, 106, 102, 105, 3.0),
+            candle("2021-03-01T07:00:00Z", 100, 104, 99, 101, 1.0),
+            candle("2021-03-01T07:15:00Z", 101, 102, 98, 103, 2.0),
+            candle("2021-03-01T07:45:00Z", 105, 107, 104, 106, 4.0),
+        ];
+        let resampled = resample(&candles, Timeframe::Minutes(45));
+        assert_eq!(resampled.len(), 2);
+        let first = &resampled[0];
+        assert_eq!(first.ts, utc("2021-03-01T07:00:00Z"));
+        assert_eq!(first.interval, CandleInterval::Unknown("45min".to_string()));
+        assert_eq!(
+            (first.open_price, first.high_price, first.low_price, first.close_price),
+            (Decimal::from(100), Decimal::from(106), Decimal::from(98), Decimal::from(105))
+        );
+        assert_eq!(first.volume, 6.0);
+        assert_eq!(resampled[1].ts, utc("2021-03-01T07:45:00Z"));
+    }
+
+    #[test]
+    fn repeated_candles_replace_earlier_ones() {
+        let mut resampler = Resampler::new(Timeframe::Minutes(60));
+        assert!(resampler.push(&candle("2021-03-01T07:00:00Z", 100, 101, 99, 100, 1.0)).is_none());
+        assert!(resampler.push(&candle("2021-03-01T07:00:00Z", 100, 103, 99, 102, 2.0)).is_none());
+        let completed = resampler.push(&candle("2021-03-01T08:00:00Z", 102, 102, 102, 102, 1.0)).unwrap();
+        assert_eq!(completed.interval, CandleInterval::Hour1);
+        assert_eq!(completed.high_price, Decimal::from(103));
+        assert_eq!(completed.volume, 2.0);
+        // older than the candle being built
+        assert!(resampler.push(&candle("2021-03-01T07:30:00Z", 1, 1, 1, 1, 1.0)).is_none());
+        assert_eq!(resampler.current().unwrap().low_price, Decimal::from(102));
+    }
+
+    #[test]
+    fn timeframe_from_interval() {
+        assert_eq!(Timeframe::try_from(CandleInterval::Hour4), Ok(Timeframe::Minutes(240)));
+        assert_eq!(Timeframe::try_from(CandleInterval::Week1), Ok(Timeframe::Weeks(1)));
+        let unknown = CandleInterval::from("45min");
+        assert_eq!(Timeframe::try_from(unknown), Err(UnknownInterval("45min".to_string())));
+        assert_eq!(Timeframe::Minutes(120).interval(), CandleInterval::Hour2);
+    }
+}
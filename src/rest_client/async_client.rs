@@ -0,0 +1,341 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::candles::{candle_windows, dedup_candles};
+use super::request::{decode_response, ApiRequest, CandlesPayload, Requests};
+use super::retry::parse_retry_after;
+use super::{Error, Method, RateLimiter, RestClientBuilder, Result, RetryPolicy};
+use crate::*;
+
+/// Async counterpart of `RestClient` built on reqwest, available with the `async` feature.
+/// Requests and responses are the same as for the blocking client.
+pub struct AsyncRestClient {
+    pub(super) client: reqwest::Client,
+    // checked when a request is sent, like the blocking client's headers
+    pub(super) authorization: String,
+    pub(super) requests: Requests,
+    pub(super) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(super) retry_policy: RetryPolicy,
+}
+
+impl AsyncRestClient {
+    /// Client for the production API. As with `RestClient::new`, a malformed
+    /// token fails requests with `Error::Validation` instead of panicking here.
+    pub fn new(token: String) -> Self {
+        RestClientBuilder::new(token).build_async().expect("default client configuration is valid")
+    }
+
+    pub fn new_sandbox(token: String) -> Self {
+        RestClientBuilder::new(token).sandbox().build_async().expect("default client configuration is valid")
+    }
+
+    pub async fn instrument_by_figi(&self, figi: &str) -> Result<Instrument> {
+        self.send(self.requests.instrument_by_figi(figi)?).await
+    }
+
+    pub async fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments> {
+        self.send(self.requests.instrument_by_ticker(ticker)?).await
+    }
+
+    pub async fn currencies(&self) -> Result<Instruments> {
+        self.send(self.requests.currencies()?).await
+    }
+
+    pub async fn etfs(&self) -> Result<Instruments> {
+        self.send(self.requests.etfs()?).await
+    }
+
+    pub async fn bonds(&self) -> Result<Instruments> {
+        self.send(self.requests.bonds()?).await
+    }
+
+    pub async fn stocks(&self) -> Result<Instruments> {
+        self.send(self.requests.stocks()?).await
+    }
+
+    pub async fn operations(
+        &self,
+        account_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        figi: &str,
+    ) -> Result<Operations> {
+        self.send(self.requests.operations(account_id, from, to, figi)?).await
+    }
+
+    pub async fn portfolio(&self, account_id: &str) -> Result<Portfolio> {
+        let positions = self.positions_portfolio(account_id).await?;
+        let currencies = self.currencies_portfolio(account_id).await?;
+        Ok(Portfolio {
+            currencies,
+            positions,
+        })
+    }
+
+    pub async fn positions_portfolio(&self, account_id: &str) -> Result<PositionBalances> {
+        self.send(self.requests.positions_portfolio(account_id)?).await
+    }
+
+    pub async fn currencies_portfolio(&self, account_id: &str) -> Result<CurrencyBalances> {
+        self.send(self.requests.currencies_portfolio(account_id)?).await
+    }
+
+    pub async fn order_cancel(&self, account_id: &str, id: &str) -> Result<()> {
+        let _: Value = self.send(self.requests.order_cancel(account_id, id)?).await?;
+        Ok(())
+    }
+
+    pub async fn limit_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+        price: Decimal,
+    ) -> Result<PlacedOrder> {
+        let request = self.requests.limit_order(account_id, figi, lots, operation, price)?;
+        self.send(request).await
+    }
+
+    pub async fn market_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+    ) -> Result<PlacedOrder> {
+        let request = self.requests.market_order(account_id, figi, lots, operation)?;
+        self.send(request).await
+    }
+
+    pub async fn orders(&self, account_id: &str) -> Result<Orders> {
+        self.send(self.requests.orders(account_id)?).await
+    }
+
+    pub async fn candles(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        let request = self.requests.candles(from, to, interval, figi)?;
+        let v: CandlesPayload = self.send(request).await?;
+        Ok(v.candles)
+    }
+
+    /// Candles for a range of any length, see `RestClient::candles_range`.
+    pub async fn candles_range(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        let mut candles = vec![];
+        let mut last_time = None;
+        for (from, to) in candle_windows(from, to, &interval) {
+            let chunk = self.candles(from, to, interval.clone(), figi).await?;
+            candles.extend(dedup_candles(chunk, &mut last_time));
+        }
+        Ok(candles)
+    }
+
+    pub async fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
+        self.send(self.requests.orderbook(depth, figi)?).await
+    }
+
+    pub async fn accounts(&self) -> Result<Accounts> {
+        self.send(self.requests.accounts()?).await
+    }
+
+    pub async fn sandbox_register(&self) -> Result<Account> {
+        self.send(self.requests.sandbox_register()?).await
+    }
+
+    pub async fn sandbox_clear(&self, account_id: &str) -> Result<()> {
+        let _: Value = self.send(self.requests.sandbox_clear(account_id)?).await?;
+        Ok(())
+    }
+
+    pub async fn sandbox_remove(&self, account_id: &str) -> Result<()> {
+        let _: Value = self.send(self.requests.sandbox_remove(account_id)?).await?;
+        Ok(())
+    }
+
+    pub async fn sandbox_set_currency_balance(
+        &self,
+        account_id: &str,
+        currency: Currency,
+        balance: Decimal,
+    ) -> Result<()> {
+        let request = self.requests.sandbox_set_currency_balance(account_id, currency, balance)?;
+        let _: Value = self.send(request).await?;
+        Ok(())
+    }
+
+    pub async fn sandbox_set_position_balance(
+        &self,
+        account_id: &str,
+        figi: &str,
+        balance: Decimal,
+    ) -> Result<()> {
+        let request = self.requests.sandbox_set_position_balance(account_id, figi, balance)?;
+        let _: Value = self.send(request).await?;
+        Ok(())
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: ApiRequest) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            let (result, retry_after) = self.send_once(&request).await;
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            match self.retry_policy.next_delay(&request, &error, attempt, retry_after) {
+                Some(delay) => {
+                    log::debug!("retrying {} in {:?} after: {}", request.url, delay, error);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    async fn send_once<T: DeserializeOwned>(
+        &self,
+        request: &ApiRequest,
+    ) -> (Result<T>, Option<Duration>) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            match rate_limiter.reserve(request.group) {
+                Ok(wait) if wait > Duration::from_secs(0) => tokio::time::sleep(wait).await,
+                Ok(_) => {}
+                Err(error) => return (Err(error), None),
+            }
+        }
+        let authorization = match HeaderValue::from_str(&self.authorization) {
+            Ok(authorization) => authorization,
+            Err(error) => return (Err(Error::Validation(error.to_string())), None),
+        };
+        let builder = match request.method {
+            Method::Get => self.client.get(request.url.clone()),
+            Method::Post => self.client.post(request.url.clone()),
+        };
+        let response = match builder
+            .header(AUTHORIZATION, authorization)
+            .header("Content-Type", "application/json")
+            .body(request.body.clone())
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(error) => return (Err(Error::transport(error)), None),
+        };
+        let status = response.status().as_u16();
+        let retry_after = response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let result = match response.text().await {
+            Ok(body) => decode_response(status, body),
+            Err(error) => Err(Error::transport(error)),
+        };
+        (result, retry_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{EndpointGroup, HttpResponse, Quota, RateLimitMode};
+
+    // Serves `responses` one per connection on a local port and returns the
+    // head of every request it answered.
+    fn serve(responses: Vec<HttpResponse>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/openapi/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                requests.push(head);
+                let mut stream = reader.into_inner();
+                write!(stream, "HTTP/1.1 {} X\r\nConnection: close\r\n", response.status).unwrap();
+                for (name, value) in &response.headers {
+                    write!(stream, "{}: {}\r\n", name, value).unwrap();
+                }
+                write!(stream, "Content-Length: {}\r\n\r\n", response.body.len()).unwrap();
+                stream.write_all(&response.body).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn client(url: &str) -> RestClientBuilder {
+        RestClientBuilder::new("token".to_string())
+            .api_url(url)
+            .retry_policy(RetryPolicy::default().backoff(Duration::from_millis(1), Duration::from_millis(1)))
+    }
+
+    fn instruments() -> HttpResponse {
+        HttpResponse::ok(&json!({"total": 0, "instruments": []}))
+    }
+
+    #[tokio::test]
+    async fn sends_token_and_maps_api_errors() {
+        let (url, server) = serve(vec![HttpResponse::api_error(400, "VALIDATION_ERROR", "bad figi")]);
+        let error = client(&url).build_async().unwrap().stocks().await.unwrap_err();
+        assert!(matches!(error, Error::Api { ref code, .. } if code == "VALIDATION_ERROR"), "{:?}", error);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /openapi/market/stocks "));
+        assert!(requests[0].to_lowercase().contains("authorization: bearer token\r\n"));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_errors() {
+        let (url, server) = serve(vec![
+            HttpResponse::new(503, "unavailable"),
+            HttpResponse::api_error(429, "TooManyRequests", "slow down").with_header("Retry-After", "0"),
+            instruments(),
+        ]);
+        let stocks = client(&url).build_async().unwrap().stocks().await.unwrap();
+        assert!(stocks.instruments.is_empty());
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_rate_limiter_rejects_without_sending() {
+        let (url, server) = serve(vec![instruments()]);
+        let limiter = RateLimiter::new(RateLimitMode::FailFast).quota(EndpointGroup::MarketData, Quota::per_minute(1));
+        let client = client(&url).rate_limiter(Arc::new(limiter)).build_async().unwrap();
+        client.stocks().await.unwrap();
+        let error = client.stocks().await.unwrap_err();
+        assert!(matches!(error, Error::RateLimited { group: EndpointGroup::MarketData, .. }), "{:?}", error);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_tokens_fail_requests_instead_of_construction() {
+        let client = AsyncRestClient::new("token\n".to_string());
+        assert!(matches!(client.stocks().await, Err(Error::Validation(_))));
+    }
+}
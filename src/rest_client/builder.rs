@@ -0,0 +1,292 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use attohttpc::{ProxySettings, Session};
+use url::Url;
+
+#[cfg(feature = "async")]
+use super::AsyncRestClient;
+use super::request::Requests;
+#[cfg(feature = "async")]
+use super::Error;
+use super::{AttohttpcTransport, RateLimiter, RecordingTransport, RestClient, Result, RetryPolicy, Transport};
+
+const API_URL: &str = "https://api-invest.tinkoff.ru/openapi/";
+const SANDBOX_API_URL: &str = "https://api-invest.tinkoff.ru/openapi/sandbox/";
+
+/// Configures a `RestClient`. `RestClient::new` and `RestClient::new_sandbox`
+/// are shortcuts for a builder with the defaults below.
+pub struct RestClientBuilder {
+    token: String,
+    api_url: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    user_agent: Option<String>,
+    proxy: Option<String>,
+    account_id: String,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    retry_policy: RetryPolicy,
+    transport: Option<Box<dyn Transport>>,
+    cassette: Option<PathBuf>,
+}
+
+impl RestClientBuilder {
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            api_url: API_URL.to_string(),
+            timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
+            user_agent: None,
+            proxy: None,
+            account_id: String::new(),
+            rate_limiter: None,
+            retry_policy: RetryPolicy::default(),
+            transport: None,
+            cassette: None,
+        }
+    }
+
+    pub fn sandbox(mut self) -> Self {
+        self.api_url = SANDBOX_API_URL.to_string();
+        self
+    }
+
+    pub fn api_url(mut self, api_url: &str) -> Self {
+        self.api_url = api_url.to_string();
+        self
+    }
+
+    /// Total time allowed for a single request.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// Proxy used for both http and https requests, e.g. `http://proxy.local:3128`.
+    /// Without it the `HTTP_PROXY`/`HTTPS_PROXY` environment variables apply.
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_string());
+        self
+    }
+
+    /// Account used by methods that are called with `DEFAULT_ACCOUNT`.
+    pub fn account_id(mut self, account_id: &str) -> Self {
+        self.account_id = account_id.to_string();
+        self
+    }
+
+    /// Meters requests with `rate_limiter`, which may be shared with other clients.
+    pub fn rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Defaults to `RetryPolicy::default()`; use `RetryPolicy::none()` to disable retries.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sends requests through `transport` instead of attohttpc. The timeout and
+    /// proxy settings only apply to the default transport and are ignored then.
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    /// Records every request and response to a cassette file at `path`, to be
+    /// replayed later with a `ReplayTransport`. See `RecordingTransport`.
+    /// Only `build` supports recording; `build_async` fails when it is set.
+    pub fn record<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cassette = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<RestClient> {
+        let requests = self.requests()?;
+
+        let mut headers = vec![("Authorization".to_string(), format!("Bearer {}", self.token))];
+        if let Some(user_agent) = &self.user_agent {
+            headers.push(("User-Agent".to_string(), user_agent.clone()));
+        }
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => Box::new(AttohttpcTransport::new(self.session()?)),
+        };
+        let transport: Box<dyn Transport> = match self.cassette {
+            Some(path) => Box::new(RecordingTransport::boxed(transport, path)),
+            None => transport,
+        };
+
+        Ok(RestClient {
+            transport,
+            headers,
+            requests,
+            rate_limiter: self.rate_limiter,
+            retry_policy: self.retry_policy,
+        })
+    }
+
+    fn session(&self) -> Result<Session> {
+        let mut session = Session::new();
+        if let Some(timeout) = self.timeout {
+            session.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            session.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            session.read_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            let proxy = Url::parse(proxy)?;
+            session.proxy_settings(
+                ProxySettings::builder()
+                    .http_proxy(proxy.clone())
+                    .https_proxy(proxy)
+                    .build(),
+            );
+        }
+        Ok(session)
+    }
+
+    #[cfg(feature = "async")]
+    pub fn build_async(self) -> Result<AsyncRestClient> {
+        if self.cassette.is_some() {
+            return Err(Error::Validation("the async client cannot record a cassette".to_string()));
+        }
+        let requests = self.requests()?;
+
+        let mut builder = reqwest::Client::builder();
+        if let Some(user_agent) = self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(proxy) = self.proxy {
+            let proxy = reqwest::Proxy::all(&proxy).map_err(|e| Error::Validation(e.to_string()))?;
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build().map_err(Error::transport)?;
+
+        Ok(AsyncRestClient {
+            client,
+            authorization: format!("Bearer {}", self.token),
+            requests,
+            rate_limiter: self.rate_limiter,
+            retry_policy: self.retry_policy,
+        })
+    }
+
+    fn requests(&self) -> Result<Requests> {
+        let mut api_url = self.api_url.clone();
+        // Url::join drops the last path segment unless the base ends with a slash
+        if !api_url.ends_with('/') {
+            api_url.push('/');
+        }
+        Ok(Requests {
+            api_url: Url::parse(&api_url)?,
+            account_id: self.account_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{Error, Method, MockTransport};
+    use crate::*;
+
+    fn orders() -> serde_json::Value {
+        json!([])
+    }
+
+    #[test]
+    fn api_url_account_and_transport_reach_the_client() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "orders", &orders());
+        let client = RestClientBuilder::new("token".to_string())
+            .api_url("http://localhost/api")
+            .account_id("acc")
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        client.orders(&DEFAULT_ACCOUNT).unwrap();
+        client.orders("other").unwrap();
+
+        let requests = transport.requests();
+        assert_eq!(requests[0].url.as_str(), "http://localhost/api/orders?brokerAccountId=acc");
+        assert_eq!(requests[1].url.query(), Some("brokerAccountId=other"));
+    }
+
+    #[test]
+    fn sandbox_uses_the_sandbox_url() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "orders", &orders());
+        let client = RestClientBuilder::new("token".to_string())
+            .sandbox()
+            .transport(transport.clone())
+            .build()
+            .unwrap();
+        client.orders(&DEFAULT_ACCOUNT).unwrap();
+        assert_eq!(transport.requests()[0].url.as_str(), "https://api-invest.tinkoff.ru/openapi/sandbox/orders");
+    }
+
+    #[test]
+    fn record_writes_a_cassette() {
+        let path = std::env::temp_dir().join(format!("invest-builder-record-{}.json", std::process::id()));
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "orders", &orders());
+        let client = RestClientBuilder::new("token".to_string())
+            .transport(transport)
+            .record(path.clone())
+            .build()
+            .unwrap();
+        client.orders(&DEFAULT_ACCOUNT).unwrap();
+
+        let cassette: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cassette["interactions"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn malformed_tokens_fail_requests_instead_of_construction() {
+        let client = RestClient::new("token\n".to_string());
+        assert!(matches!(client.stocks(), Err(Error::Validation(_))));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_client_cannot_record() {
+        let result = RestClientBuilder::new("token".to_string()).record("cassette.json").build_async();
+        assert!(matches!(result, Err(Error::Validation(_))));
+    }
+}
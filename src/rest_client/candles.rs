@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+
+use super::{RestClient, Result};
+use crate::*;
+
+/// Iterator over the candles of a long range, one request window at a time.
+/// Returned by `RestClient::candles_chunks`.
+///
+/// Each item holds the candles of one window sorted by time, without the ones
+/// already returned by previous windows. Iteration stops after the first error.
+pub struct CandleChunks<'a> {
+    client: &'a RestClient,
+    figi: String,
+    interval: CandleInterval,
+    windows: VecDeque<(DateTime<Utc>, DateTime<Utc>)>,
+    last_time: Option<DateTime<Utc>>,
+}
+
+impl<'a> CandleChunks<'a> {
+    pub(super) fn new(
+        client: &'a RestClient,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Self {
+        Self {
+            client,
+            figi: figi.to_string(),
+            windows: candle_windows(from, to, &interval).into(),
+            interval,
+            last_time: None,
+        }
+    }
+}
+
+impl Iterator for CandleChunks<'_> {
+    type Item = Result<Vec<Candle>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (from, to) = self.windows.pop_front()?;
+        match self.client.candles(from, to, self.interval.clone(), &self.figi) {
+            Ok(candles) => Some(Ok(dedup_candles(candles, &mut self.last_time))),
+            Err(error) => {
+                self.windows.clear();
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Splits `from`..`to` into consecutive windows no longer than the interval allows.
+pub(crate) fn candle_windows(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    interval: &CandleInterval,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let max_range = interval.max_request_range();
+    let mut windows = vec![];
+    let mut start = from;
+    while start < to {
+        let end = std::cmp::min(start + max_range, to);
+        windows.push((start, end));
+        start = end;
+    }
+    windows
+}
+
+/// Sorts `candles` and keeps those later than `last_time`, which is then moved
+/// to the last kept candle. Candles on a window boundary can come back from both
+/// windows; this drops the second copy.
+pub(crate) fn dedup_candles(mut candles: Vec<Candle>, last_time: &mut Option<DateTime<Utc>>) -> Vec<Candle> {
+    candles.sort_by_key(|c| c.ts);
+    candles.dedup_by_key(|c| c.ts);
+    if let Some(last_time) = *last_time {
+        candles.retain(|c| c.ts > last_time);
+    }
+    if let Some(last) = candles.last() {
+        *last_time = Some(last.ts);
+    }
+    candles
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{HttpResponse, Method, MockTransport, RetryPolicy};
+
+    fn time(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 3, day, hour, 0, 0).unwrap()
+    }
+
+    fn candle(ts: DateTime<Utc>) -> Candle {
+        Candle {
+            figi: "FIGI".to_string(),
+            interval: CandleInterval::Hour1,
+            open_price: Decimal::ONE,
+            close_price: Decimal::ONE,
+            high_price: Decimal::ONE,
+            low_price: Decimal::ONE,
+            volume: 1.0,
+            ts,
+        }
+    }
+
+    fn times(candles: &[Candle]) -> Vec<DateTime<Utc>> {
+        candles.iter().map(|c| c.ts).collect()
+    }
+
+    #[test]
+    fn windows_follow_the_interval_limit() {
+        let from = time(1, 0);
+        for (interval, days) in [(CandleInterval::Min1, 1), (CandleInterval::Hour1, 7), (CandleInterval::Day1, 365)] {
+            let to = from + Duration::days(days * 3);
+            let windows = candle_windows(from, to, &interval);
+            assert_eq!(windows.len(), 3, "{}", interval);
+            assert_eq!(windows[0], (from, from + Duration::days(days)));
+            assert_eq!(windows[2], (from + Duration::days(days * 2), to));
+        }
+    }
+
+    #[test]
+    fn last_window_is_cut_at_the_end_of_the_range() {
+        let from = time(1, 0);
+        let to = from + Duration::days(2) + Duration::hours(5);
+        let windows = candle_windows(from, to, &CandleInterval::Min5);
+        assert_eq!(windows.len(), 3);
+        assert_eq!(windows[2], (from + Duration::days(2), to));
+        // consecutive windows share their boundary
+        assert_eq!(windows[0].1, windows[1].0);
+    }
+
+    #[test]
+    fn empty_ranges_have_no_windows() {
+        assert!(candle_windows(time(2, 0), time(2, 0), &CandleInterval::Hour1).is_empty());
+        assert!(candle_windows(time(2, 0), time(1, 0), &CandleInterval::Hour1).is_empty());
+    }
+
+    #[test]
+    fn dedup_drops_boundary_candles_already_returned() {
+        let mut last_time = None;
+        let first = dedup_candles(vec![candle(time(1, 1)), candle(time(1, 0)), candle(time(1, 1))], &mut last_time);
+        assert_eq!(times(&first), vec![time(1, 0), time(1, 1)]);
+        assert_eq!(last_time, Some(time(1, 1)));
+
+        let second = dedup_candles(vec![candle(time(1, 2)), candle(time(1, 1))], &mut last_time);
+        assert_eq!(times(&second), vec![time(1, 2)]);
+        assert!(dedup_candles(vec![], &mut last_time).is_empty());
+        assert_eq!(last_time, Some(time(1, 2)));
+    }
+
+    #[test]
+    fn candles_range_joins_windows_in_order() {
+        let payload = |candles: Vec<Candle>| json!({"figi": "FIGI", "interval": "hour", "candles": candles});
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "market/candles", &payload(vec![candle(time(7, 23)), candle(time(1, 0))]));
+        transport.respond_ok(Method::Get, "market/candles", &payload(vec![candle(time(9, 0)), candle(time(7, 23))]));
+        let client = RestClient::builder("token".to_string()).transport(transport.clone()).build().unwrap();
+
+        let candles = client.candles_range(time(1, 0), time(10, 0), CandleInterval::Hour1, "FIGI").unwrap();
+        assert_eq!(times(&candles), vec![time(1, 0), time(7, 23), time(9, 0)]);
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn chunks_stop_after_an_error() {
+        let transport = MockTransport::new();
+        transport.respond(Method::Get, "market/candles", HttpResponse::new(500, "boom"));
+        let client = RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        let mut chunks = client.candles_chunks(time(1, 0), time(3, 0), CandleInterval::Min1, "FIGI");
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+        assert_eq!(transport.requests().len(), 1);
+    }
+}
@@ -0,0 +1,244 @@
+// Recorded HTTP sessions for regression tests: one transport writes every
+// request/response pair to a cassette file, the other answers from it.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use url::{Position, Url};
+
+use super::{Error, HttpRequest, HttpResponse, Method, Result, Transport};
+
+const REDACTED: &str = "Bearer <redacted>";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Cassette {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+/// Sends requests through another transport and appends every exchange to a
+/// cassette file, rewritten after each response so an interrupted session
+/// keeps what it recorded. The `Authorization` header is saved redacted.
+///
+/// Requests that fail without a response are not recorded. If the cassette
+/// cannot be written the request fails with `Error::Cassette`, which is not
+/// retried, so the request is not sent again.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    path: PathBuf,
+    cassette: Mutex<Cassette>,
+}
+
+impl RecordingTransport {
+    /// Starts a new cassette at `path`, replacing any file already there.
+    pub fn new<T: Transport + 'static, P: AsRef<Path>>(inner: T, path: P) -> Self {
+        Self::boxed(Box::new(inner), path.as_ref().to_path_buf())
+    }
+
+    pub(crate) fn boxed(inner: Box<dyn Transport>, path: PathBuf) -> Self {
+        Self {
+            inner,
+            path,
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    fn save(&self, cassette: &Cassette) -> io::Result<()> {
+        let content = serde_json::to_vec_pretty(cassette)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let response = self.inner.send(request)?;
+        let headers = request
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if name.eq_ignore_ascii_case("Authorization") { REDACTED } else { value };
+                (name.clone(), value.to_string())
+            })
+            .collect();
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.interactions.push(Interaction {
+            request: RecordedRequest {
+                method: method_name(request.method).to_string(),
+                url: request.url.to_string(),
+                headers,
+                body: request.text(),
+            },
+            response: RecordedResponse {
+                status: response.status,
+                headers: response.headers.clone(),
+                body: response.text(),
+            },
+        });
+        self.save(&cassette).map_err(|e| Error::Cassette(Box::new(e)))?;
+        Ok(response)
+    }
+}
+
+/// Answers requests from a cassette written by `RecordingTransport`.
+///
+/// A request matches a recorded one with the same method, path, query and
+/// body; the host is ignored, so a cassette can be replayed against any
+/// `api_url` with the same path. Each recorded exchange is served once, in
+/// recording order among equal requests. A request without an unused match
+/// fails with `Error::Cassette`.
+///
+/// Requests built from the current time, such as `operations` up to now,
+/// only match if the test passes the recorded times. Clones share the cassette.
+#[derive(Clone)]
+pub struct ReplayTransport {
+    interactions: Arc<Mutex<Vec<Option<Interaction>>>>,
+}
+
+impl ReplayTransport {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let cassette: Cassette =
+            serde_json::from_slice(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            interactions: Arc::new(Mutex::new(cassette.interactions.into_iter().map(Some).collect())),
+        })
+    }
+
+    /// Recorded exchanges not served yet, useful to check that a test made
+    /// every request it recorded.
+    pub fn remaining(&self) -> usize {
+        self.interactions.lock().unwrap().iter().filter(|i| i.is_some()).count()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let method = method_name(request.method);
+        let target = &request.url[Position::BeforePath..];
+        let body = request.text();
+        let mut interactions = self.interactions.lock().unwrap();
+        let slot = interactions.iter_mut().find(|slot| {
+            slot.as_ref().is_some_and(|i| {
+                let recorded = &i.request;
+                recorded.method == method
+                    && recorded.body == body
+                    && Url::parse(&recorded.url).is_ok_and(|url| &url[Position::BeforePath..] == target)
+            })
+        });
+        let interaction = match slot.and_then(Option::take) {
+            Some(interaction) => interaction,
+            None => {
+                let message = format!("no recorded response for {} {}", method, target);
+                return Err(Error::Cassette(message.into()));
+            }
+        };
+        let response = interaction.response;
+        Ok(HttpResponse {
+            status: response.status,
+            headers: response.headers,
+            body: response.body.into_bytes(),
+        })
+    }
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Post => "POST",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::rest_client::{MockTransport, RestClient, RetryPolicy};
+
+    fn cassette_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("invest-cassette-{}-{}.json", name, std::process::id()))
+    }
+
+    fn recording_client(transport: &MockTransport, path: PathBuf) -> RestClient {
+        RestClient::builder("secret".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::default().backoff(Duration::from_millis(1), Duration::from_millis(1)))
+            .record(path)
+            .build()
+            .unwrap()
+    }
+
+    fn replay_client(replay: &ReplayTransport) -> RestClient {
+        RestClient::builder("other".to_string())
+            .api_url("http://localhost/openapi")
+            .transport(replay.clone())
+            .retry_policy(RetryPolicy::default().backoff(Duration::from_millis(1), Duration::from_millis(1)))
+            .build()
+            .unwrap()
+    }
+
+    fn instruments() -> serde_json::Value {
+        json!({"total": 0, "instruments": []})
+    }
+
+    #[test]
+    fn records_redacted_and_replays_once() {
+        let path = cassette_path("replay");
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "market/stocks", &instruments());
+        recording_client(&transport, path.clone()).stocks().unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("secret"));
+        assert!(content.contains(REDACTED));
+
+        let replay = ReplayTransport::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let client = replay_client(&replay);
+        client.stocks().unwrap();
+        assert_eq!(replay.remaining(), 0);
+
+        let error = client.stocks().unwrap_err();
+        assert!(matches!(error, Error::Cassette(_)), "{:?}", error);
+        assert!(client.bonds().is_err());
+    }
+
+    #[test]
+    fn save_failures_are_not_retried() {
+        let path = std::env::temp_dir().join("invest-cassette-missing-dir").join("cassette.json");
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "market/stocks", &instruments());
+
+        let error = recording_client(&transport, path).stocks().unwrap_err();
+        assert!(matches!(error, Error::Cassette(_)), "{:?}", error);
+        assert_eq!(transport.requests().len(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::EndpointGroup;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error returned by every `RestClient` method.
+#[derive(Debug)]
+pub enum Error {
+    /// The request never got a response: DNS, TLS, connection reset, timeout.
+    Transport(Box<dyn StdError + Send + Sync>),
+    /// Non-2xx response without a parsable API error envelope.
+    Status { status: u16, body: String },
+    /// Error reported by the API in its `{"trackingId", "status": "Error", "payload"}` envelope.
+    Api {
+        status: u16,
+        tracking_id: String,
+        code: String,
+        message: String,
+    },
+    /// A 2xx response whose body could not be decoded into the expected type.
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+    /// The request was rejected locally before being sent.
+    Validation(String),
+    /// The client-side rate limiter has no free slot for this endpoint group.
+    RateLimited {
+        group: EndpointGroup,
+        retry_after: Duration,
+    },
+    /// A cassette could not be written, or has no recorded response for the
+    /// request. Never retried: the request may already have been sent.
+    Cassette(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Status { status, .. } | Error::Api { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn tracking_id(&self) -> Option<&str> {
+        match self {
+            Error::Api { tracking_id, .. } => Some(tracking_id),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn transport<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Error::Transport(Box::new(error))
+    }
+
+    // Builds the error for a non-2xx response, preferring the API envelope when present.
+    pub(crate) fn from_response(status: u16, body: String) -> Self {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Envelope {
+            tracking_id: String,
+            #[serde(default)]
+            payload: Payload,
+        }
+        #[derive(Default, Deserialize)]
+        struct Payload {
+            #[serde(default)]
+            code: String,
+            #[serde(default)]
+            message: String,
+        }
+        match serde_json::from_str::<Envelope>(&body) {
+            Ok(envelope) => Error::Api {
+                status,
+                tracking_id: envelope.tracking_id,
+                code: envelope.payload.code,
+                message: envelope.payload.message,
+            },
+            Err(_) => Error::Status { status, body },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(error) => write!(f, "transport error: {}", error),
+            Error::Status { status, body } => write!(f, "http status {}: {}", status, body),
+            Error::Api {
+                status,
+                tracking_id,
+                code,
+                message,
+            } => write!(
+                f,
+                "api error {} (http status {}, tracking id {}): {}",
+                code, status, tracking_id, message
+            ),
+            Error::Decode { source, .. } => write!(f, "failed to decode response: {}", source),
+            Error::Validation(message) => write!(f, "invalid request: {}", message),
+            Error::RateLimited { group, retry_after } => write!(
+                f,
+                "rate limit for {:?} requests exceeded, retry after {:?}",
+                group, retry_after
+            ),
+            Error::Cassette(error) => write!(f, "cassette error: {}", error),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Transport(error) | Error::Cassette(error) => Some(error.as_ref()),
+            Error::Decode { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(error: url::ParseError) -> Self {
+        Error::Validation(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_envelope_becomes_api_error() {
+        let body = r#"{"trackingId": "abc", "status": "Error",
+            "payload": {"code": "VALIDATION_ERROR", "message": "bad"}}"#;
+        let error = Error::from_response(400, body.to_string());
+        match &error {
+            Error::Api {
+                status,
+                tracking_id,
+                code,
+                message,
+            } => {
+                assert_eq!(*status, 400);
+                assert_eq!(tracking_id, "abc");
+                assert_eq!(code, "VALIDATION_ERROR");
+                assert_eq!(message, "bad");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(error.status(), Some(400));
+        assert_eq!(error.tracking_id(), Some("abc"));
+        assert_eq!(error.to_string(), "api error VALIDATION_ERROR (http status 400, tracking id abc): bad");
+    }
+
+    #[test]
+    fn other_bodies_become_status_errors() {
+        let error = Error::from_response(502, "Bad Gateway".to_string());
+        assert!(matches!(error, Error::Status { status: 502, ref body } if body == "Bad Gateway"));
+        assert_eq!(error.tracking_id(), None);
+        assert_eq!(error.to_string(), "http status 502: Bad Gateway");
+
+        // an envelope without a payload still carries the tracking id
+        let error = Error::from_response(500, r#"{"trackingId": "t1"}"#.to_string());
+        assert_eq!(error.tracking_id(), Some("t1"));
+    }
+
+    #[test]
+    fn sources_are_kept() {
+        let source = serde_json::from_str::<u32>("x").unwrap_err();
+        let error = Error::Decode {
+            source,
+            body: "x".to_string(),
+        };
+        assert!(error.source().is_some());
+        assert_eq!(error.status(), None);
+        assert!(Error::Validation("bad".to_string()).source().is_none());
+    }
+}
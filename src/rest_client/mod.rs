@@ -1,81 +1,88 @@
 #![allow(dead_code)]
 
-use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use url::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
 
 use crate::*;
 
+#[cfg(feature = "async")]
+pub use self::async_client::AsyncRestClient;
+pub use self::builder::RestClientBuilder;
+pub use self::candles::CandleChunks;
+pub use self::cassette::{RecordingTransport, ReplayTransport};
+pub use self::error::{Error, Result};
+pub use self::rate_limit::{EndpointGroup, Quota, RateLimitMode, RateLimiter};
+pub use self::retry::RetryPolicy;
+pub use self::sandbox::SandboxEmulator;
+pub use self::transport::{AttohttpcTransport, HttpRequest, HttpResponse, Method, MockTransport, Transport};
+
+use self::request::{decode_response, ApiRequest, CandlesPayload, Requests};
+use self::retry::parse_retry_after;
+
+#[cfg(feature = "async")]
+mod async_client;
+mod builder;
+pub(crate) mod candles;
+mod cassette;
+mod error;
+mod rate_limit;
+mod request;
+mod retry;
+mod sandbox;
+mod transport;
+
 pub struct RestClient {
-    token: String,
-    api_url: Url,
+    transport: Box<dyn Transport>,
+    // Sent with every request: authorization and, if configured, the user agent.
+    headers: Vec<(String, String)>,
+    requests: Requests,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    retry_policy: RetryPolicy,
 }
 
 impl RestClient {
+    /// Client for the production API. Headers such as the token are checked
+    /// when a request is sent, so a malformed token fails that request with
+    /// `Error::Validation` instead of panicking here.
     pub fn new(token: String) -> Self {
-        Self {
-            token,
-            api_url: Url::parse("https://api-invest.tinkoff.ru/openapi/").unwrap(),
-        }
+        RestClientBuilder::new(token).build().expect("default client configuration is valid")
     }
 
     pub fn new_sandbox(token: String) -> Self {
-        Self {
-            token,
-            api_url: Url::parse("https://api-invest.tinkoff.ru/openapi/sandbox/").unwrap(),
-        }
+        RestClientBuilder::new(token).sandbox().build().expect("default client configuration is valid")
+    }
+
+    pub fn builder(token: String) -> RestClientBuilder {
+        RestClientBuilder::new(token)
     }
 
     pub fn instrument_by_figi(&self, figi: &str) -> Result<Instrument> {
-        let mut base = (&self).api_url.join("market/search/by-figi")?;
-        base.query_pairs_mut()
-            .clear()
-            .append_pair("figi", figi)
-            .finish();
-        let value = &self.get_request(&base)?;
-        let v: Instrument = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.instrument_by_figi(figi)?)
     }
 
     pub fn instrument_by_ticker(&self, ticker: &str) -> Result<Instruments> {
-        let mut base = (&self).api_url.join("market/search/by-ticker")?;
-        base.query_pairs_mut()
-            .clear()
-            .append_pair("ticker", ticker)
-            .finish();
-        let value = &self.get_request(&base)?;
-        let v: Instruments = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.instrument_by_ticker(ticker)?)
     }
 
     pub fn currencies(&self) -> Result<Instruments> {
-        let url = (&self).api_url.join("market/currencies")?;
-        let value = &self.get_request(&url)?;
-        let v: Instruments = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.currencies()?)
     }
 
     pub fn etfs(&self) -> Result<Instruments> {
-        let url = (&self).api_url.join("market/etfs")?;
-        let value = &self.get_request(&url)?;
-        let v: Instruments = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.etfs()?)
     }
 
     pub fn bonds(&self) -> Result<Instruments> {
-        let url = (&self).api_url.join("market/bonds")?;
-        let value = &self.get_request(&url)?;
-        let v: Instruments = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.bonds()?)
     }
 
     pub fn stocks(&self) -> Result<Instruments> {
-        let url = (&self).api_url.join("market/stocks")?;
-        let value = &self.get_request(&url)?;
-        let v: Instruments = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.stocks()?)
     }
 
     pub fn operations(
@@ -85,26 +92,12 @@ impl RestClient {
         to: DateTime<Utc>,
         figi: &str,
     ) -> Result<Operations> {
-        let mut url = (&self).api_url.join("operations")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("from", from.to_rfc3339().as_str())
-            .append_pair("to", to.to_rfc3339().as_str());
-        if figi != "" {
-            url.query_pairs_mut().append_pair("figi", figi);
-        }
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .append_pair("brokerAccountId", account_id);
-        }
-        let value = &self.get_request(&url)?;
-        let v: Operations = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.operations(account_id, from, to, figi)?)
     }
 
     pub fn portfolio(&self, account_id: &str) -> Result<Portfolio> {
-        let positions = (&self).positions_portfolio(account_id)?;
-        let currencies = (&self).currencies_portfolio(account_id)?;
+        let positions = self.positions_portfolio(account_id)?;
+        let currencies = self.currencies_portfolio(account_id)?;
         let portfolio = Portfolio {
             currencies,
             positions,
@@ -113,40 +106,15 @@ impl RestClient {
     }
 
     pub fn positions_portfolio(&self, account_id: &str) -> Result<PositionBalances> {
-        let mut url = (&self).api_url.join("portfolio")?;
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        let value = &self.get_request(&url)?;
-        let v: PositionBalances = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.positions_portfolio(account_id)?)
     }
 
     pub fn currencies_portfolio(&self, account_id: &str) -> Result<CurrencyBalances> {
-        let mut url = (&self).api_url.join("portfolio/currencies")?;
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        let value = &self.get_request(&url)?;
-        let v: CurrencyBalances = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.currencies_portfolio(account_id)?)
     }
 
     pub fn order_cancel(&self, account_id: &str, id: &str) -> Result<()> {
-        let mut url = (&self).api_url.join("orders/cancel")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("orderId", id);
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        (&self).post_request(url, "".to_string())?;
+        let _: Value = self.send(self.requests.order_cancel(account_id, id)?)?;
         Ok(())
     }
 
@@ -156,229 +124,300 @@ impl RestClient {
         figi: &str,
         lots: i64,
         operation: OperationType,
-        price: f64,
+        price: Decimal,
     ) -> Result<PlacedOrder> {
-        let mut url = (&self).api_url.join("orders/limit-order")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("figi", figi);
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        #[derive(Debug, Serialize, Deserialize)]
-        struct Body {
-            lots: i64,
-            operation: OperationType,
-            price: f64,
-        }
-        let body = Body {
-            lots,
-            operation,
-            price,
-        };
-        let body = serde_json::to_string(&body)?;
-        let response = (&self).post_request(url, body)?;
-        let result = serde_json::from_value(json!(response))?;
-        Ok(result)
+        self.send(self.requests.limit_order(account_id, figi, lots, operation, price)?)
     }
 
     pub fn market_order(&self, account_id: &str, figi: &str, lots: i64, operation: OperationType) -> Result<PlacedOrder> {
-        let mut url = (&self).api_url.join("orders/market-order")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("figi", figi);
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        #[derive(Debug, Serialize, Deserialize)]
-        struct Body {
-            lots: i64,
-            operation: OperationType,
-        }
-        let body = Body {
-            lots,
-            operation,
-        };
-        let body = serde_json::to_string(&body)?;
-        let response = (&self).post_request(url, body)?;
-        let v = serde_json::from_value(json!(response))?;
-        Ok(v)
+        self.send(self.requests.market_order(account_id, figi, lots, operation)?)
     }
 
     pub fn orders(&self, account_id: &str) -> Result<Orders> {
-        let mut url = (&self).api_url.join("orders")?;
-        if account_id != DEFAULT_ACCOUNT.as_str() {
-            url.query_pairs_mut()
-                .clear()
-                .append_pair("brokerAccountId", account_id);
-        }
-        let value = &self.get_request(&url)?;
-        let v: Orders = match serde_json::from_value(json!(value)) {
-            Ok(value) => value,
-            Err(_) => Orders { orders: vec![] }
-        };
-        Ok(v)
+        self.send(self.requests.orders(account_id)?)
     }
 
     pub fn candles(&self,
                    from: DateTime<Utc>,
                    to: DateTime<Utc>,
-                   interval: &str,
+                   interval: CandleInterval,
                    figi: &str) -> Result<Vec<Candle>> {
-        let mut url = (&self).api_url.join("market/candles")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("interval", interval)
-            .append_pair("from", from.to_rfc3339().as_str())
-            .append_pair("to", to.to_rfc3339().as_str());
-        if figi != "" {
-            url.query_pairs_mut().append_pair("figi", figi);
-        }
-        let value = &self.get_request(&url)?;
-        struct Payload {
-            figi:     String,
-            interval: CandleInterval,
-            candles:  Vec<Candle>
-        };
-        let v: Payload = serde_json::from_value(json!(value))?;
+        let v: CandlesPayload = self.send(self.requests.candles(from, to, interval, figi)?)?;
         Ok(v.candles)
     }
 
-    pub fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
-        if depth < 1 || depth > MaxOrderbookDepth {
-            return Ok(RestOrderBook{
-                figi: "".to_string(),
-                depth,
-                bids: vec![],
-                asks: vec![],
-                trade_status: "".to_string(),
-                min_price_increment: 0.0,
-                last_price: 0.0,
-                close_price: 0.0,
-                limit_up: 0.0,
-                limit_down: 0.0,
-                face_value: 0.0
-            })
-        }
-        let mut url = (&self).api_url.join("market/orderbook")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("depth", &*depth.to_string());
-        if figi != "" {
-            url.query_pairs_mut().append_pair("figi", figi);
+    /// Candles for a range of any length. The range is split into windows the
+    /// API accepts for `interval`, fetched one by one through the rate limiter,
+    /// and merged into a single list sorted by time.
+    pub fn candles_range(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<Vec<Candle>> {
+        let mut candles = vec![];
+        for chunk in self.candles_chunks(from, to, interval, figi) {
+            candles.extend(chunk?);
         }
-        let value = &self.get_request(&url)?;
-        let v: RestOrderBook = serde_json::from_value(json!(value))?;
-        Ok(v)
+        Ok(candles)
     }
 
-    pub fn accounts(&self) -> Result<Accounts> {
-        let mut url = (&self).api_url.join("user/accounts")?;
-        let value = &self.get_request(&url)?;
-        let v: Accounts = serde_json::from_value(json!(value))?;
-        Ok(v)
+    /// Same as `candles_range`, but yields each window's candles as soon as they arrive.
+    pub fn candles_chunks(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> CandleChunks<'_> {
+        CandleChunks::new(self, from, to, interval, figi)
+    }
 
+    pub fn orderbook(&self, depth: i64, figi: &str) -> Result<RestOrderBook> {
+        self.send(self.requests.orderbook(depth, figi)?)
+    }
+
+    pub fn accounts(&self) -> Result<Accounts> {
+        self.send(self.requests.accounts()?)
     }
 
     pub fn sandbox_register(&self) -> Result<Account> {
-        let mut url = (&self).api_url.join("sandbox/register")?;
-        let value = &self.post_request(url, "".to_string())?;
-        let v: Account = serde_json::from_value(json!(value))?;
-        Ok(v)
+        self.send(self.requests.sandbox_register()?)
     }
 
     pub fn sandbox_clear(&self, account_id: &str) -> Result<()> {
-        let mut url = (&self).api_url.join("sandbox/clear")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("brokerAccountId", account_id);
-        let _value = &self.post_request(url, "".to_string())?;
+        let _: Value = self.send(self.requests.sandbox_clear(account_id)?)?;
         Ok(())
     }
 
     pub fn sandbox_remove(&self, account_id: &str) -> Result<()> {
-        let mut url = (&self).api_url.join("sandbox/remove")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("brokerAccountId", account_id);
-        let _value = &self.post_request(url, "".to_string())?;
+        let _: Value = self.send(self.requests.sandbox_remove(account_id)?)?;
         Ok(())
     }
 
-    pub fn sandbox_set_currency_balance(&self, account_id: &str, currency: Currency, balance: f64) -> Result<()> {
-        let mut url = (&self).api_url.join("sandbox/currencies/balance")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("broker_account_id", account_id);
-        #[derive(Debug, Serialize, Deserialize)]
-        struct Body {
-            currency: Currency,
-            balance: f64,
-            #[serde(rename = "brokerAccountId")]
-            broker_account_id: String,
-        }
-        let body = Body {
-            currency,
-            balance,
-            broker_account_id: account_id.to_string()
-        };
-        let body = serde_json::to_string(&body)?;
-        let _value = &self.post_request(url, body)?;
+    pub fn sandbox_set_currency_balance(&self, account_id: &str, currency: Currency, balance: Decimal) -> Result<()> {
+        let request = self.requests.sandbox_set_currency_balance(account_id, currency, balance)?;
+        let _: Value = self.send(request)?;
+        Ok(())
+    }
+
+    pub fn sandbox_set_position_balance(&self, account_id: &str, figi: &str, balance: Decimal) -> Result<()> {
+        let request = self.requests.sandbox_set_position_balance(account_id, figi, balance)?;
+        let _: Value = self.send(request)?;
         Ok(())
     }
 
-    pub fn sandbox_set_position_balance(&self, account_id: &str, figi: &str, balance: f64) -> Result<()> {
-        let mut url = (&self).api_url.join("sandbox/positions/balance")?;
-        url.query_pairs_mut()
-            .clear()
-            .append_pair("broker_account_id", account_id);
-        #[derive(Debug, Serialize, Deserialize)]
-        struct Body {
-            figi: String,
-            balance: f64,
-            #[serde(rename = "brokerAccountId")]
-            broker_account_id: String,
+    fn send<T: DeserializeOwned>(&self, request: ApiRequest) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            let (result, retry_after) = self.send_once(&request);
+            let error = match result {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            match self.retry_policy.next_delay(&request, &error, attempt, retry_after) {
+                Some(delay) => {
+                    log::debug!("retrying {} in {:?} after: {}", request.url, delay, error);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    // Returns the decoded response along with the server's Retry-After delay, if any.
+    fn send_once<T: DeserializeOwned>(&self, request: &ApiRequest) -> (Result<T>, Option<Duration>) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            match rate_limiter.reserve(request.group) {
+                Ok(wait) if wait > Duration::from_secs(0) => thread::sleep(wait),
+                Ok(_) => {}
+                Err(error) => return (Err(error), None),
+            }
         }
-        let body = Body {
-            figi: figi.to_string(),
-            balance,
-            broker_account_id: account_id.to_string()
+        let mut headers = self.headers.clone();
+        headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        let http_request = HttpRequest {
+            method: request.method,
+            url: request.url.clone(),
+            headers,
+            body: request.body.clone(),
         };
-        let body = serde_json::to_string(&body)?;
-        let _value = &self.post_request(url, body)?;
-        Ok(())
+        let response = match self.transport.send(&http_request) {
+            Ok(response) => response,
+            Err(error) => return (Err(error), None),
+        };
+        let retry_after = response.header("Retry-After").and_then(parse_retry_after);
+        let result = decode_response(response.status, response.text());
+        (result, retry_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn client(transport: &MockTransport) -> RestClient {
+        RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::default().backoff(Duration::from_millis(1), Duration::from_millis(1)))
+            .build()
+            .unwrap()
+    }
+
+    fn placed_order() -> Value {
+        json!({
+            "orderId": "1",
+            "operation": "Buy",
+            "status": "New",
+            "requestedLots": 2,
+            "executedLots": 0,
+        })
     }
 
-    fn get_request(&self, url: &Url) -> Result<Value> {
-        let response = attohttpc::get(&url.as_str())
-            .header_append("Content-Type", "application/json")
-            .header_append("Authorization", format!("Bearer {}", &self.token))
-            .send()?;
-        match response.error_for_status() {
-            Ok(response) => {
-                let json: Value = serde_json::from_str(&*response.text()?)?;
-                Ok(json!(json.get("payload")))
+    #[test]
+    fn encodes_request_and_decodes_payload() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Post, "orders/limit-order", &placed_order());
+        let order = client(&transport)
+            .limit_order("acc", "BBG000B9XRY4", 2, OperationType::Buy, "100.5".parse().unwrap())
+            .unwrap();
+        assert_eq!(order.id, "1");
+        assert_eq!(order.status, OrderStatus::New);
+        assert_eq!(order.requested_lots, 2);
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.url.path(), "/openapi/orders/limit-order");
+        assert_eq!(request.url.query(), Some("figi=BBG000B9XRY4&brokerAccountId=acc"));
+        assert_eq!(request.header("authorization"), Some("Bearer token"));
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body, json!({"lots": 2, "operation": "Buy", "price": 100.5}));
+    }
+
+    #[test]
+    fn maps_error_responses() {
+        let transport = MockTransport::new();
+        let client = RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+
+        transport.respond(Method::Get, "market/stocks", HttpResponse::api_error(400, "VALIDATION_ERROR", "bad figi"));
+        match client.stocks() {
+            Err(Error::Api {
+                status,
+                tracking_id,
+                code,
+                message,
+            }) => {
+                assert_eq!(status, 400);
+                assert_eq!(tracking_id, "mock");
+                assert_eq!(code, "VALIDATION_ERROR");
+                assert_eq!(message, "bad figi");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        transport.respond(Method::Get, "market/bonds", HttpResponse::new(502, "Bad Gateway"));
+        match client.bonds() {
+            Err(Error::Status { status, body }) => {
+                assert_eq!(status, 502);
+                assert_eq!(body, "Bad Gateway");
             }
-            Err(e) => Err(anyhow!("{:?}", e))
+            other => panic!("unexpected result: {:?}", other),
         }
+
+        transport.respond(Method::Get, "market/etfs", HttpResponse::new(200, "{\"payload\": 1}"));
+        assert!(matches!(client.etfs(), Err(Error::Decode { .. })));
+    }
+
+    #[test]
+    fn retries_transient_reads() {
+        let transport = MockTransport::new();
+        let instruments = json!({"instruments": []});
+        transport.respond(Method::Get, "market/stocks", HttpResponse::new(503, "unavailable"));
+        transport.respond(
+            Method::Get,
+            "market/stocks",
+            HttpResponse::api_error(429, "TooManyRequests", "slow down").with_header("Retry-After", "0"),
+        );
+        transport.respond_ok(Method::Get, "market/stocks", &instruments);
+        let stocks = client(&transport).stocks().unwrap();
+        assert!(stocks.instruments.is_empty());
+        assert_eq!(transport.requests().len(), 3);
     }
 
-    fn post_request(&self, url: Url, body: String) -> Result<Value> {
-        let response = attohttpc::post(&url.as_str())
-            .header_append("Content-Type", "application/json")
-            .header_append("Authorization", format!("Bearer {}", &self.token))
-            .json(&body)?
-            .send()?;
-        match response.error_for_status() {
-            Ok(response) => {
-                let json: Value = serde_json::from_str(&*response.text()?)?;
-                Ok(json!(json.get("payload")))
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let transport = MockTransport::new();
+        transport.respond(Method::Get, "market/stocks", HttpResponse::new(500, "boom"));
+        assert_eq!(client(&transport).stocks().unwrap_err().status(), Some(500));
+        assert_eq!(transport.requests().len(), 3);
+    }
+
+    #[test]
+    fn does_not_retry_client_errors() {
+        let transport = MockTransport::new();
+        transport.respond(Method::Get, "market/stocks", HttpResponse::api_error(404, "NotFound", "no such"));
+        assert_eq!(client(&transport).stocks().unwrap_err().status(), Some(404));
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn retries_orders_only_when_enabled() {
+        let transport = MockTransport::new();
+        transport.respond(Method::Post, "orders/market-order", HttpResponse::new(503, "unavailable"));
+        transport.respond_ok(Method::Post, "orders/market-order", &placed_order());
+
+        let result = client(&transport).market_order("acc", "FIGI", 1, OperationType::Buy);
+        assert_eq!(result.unwrap_err().status(), Some(503));
+        assert_eq!(transport.requests().len(), 1);
+
+        let transport = MockTransport::new();
+        transport.respond(Method::Post, "orders/market-order", HttpResponse::new(503, "unavailable"));
+        transport.respond_ok(Method::Post, "orders/market-order", &placed_order());
+        let retrying = RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(
+                RetryPolicy::default()
+                    .backoff(Duration::from_millis(1), Duration::from_millis(1))
+                    .retry_orders(true),
+            )
+            .build()
+            .unwrap();
+        retrying.market_order("acc", "FIGI", 1, OperationType::Buy).unwrap();
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[test]
+    fn fail_fast_rate_limiter_rejects_without_sending() {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "market/stocks", &json!({"instruments": []}));
+        let limiter = RateLimiter::new(RateLimitMode::FailFast).quota(EndpointGroup::MarketData, Quota::per_minute(1));
+        let client = RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .rate_limiter(Arc::new(limiter))
+            .build()
+            .unwrap();
+
+        client.stocks().unwrap();
+        match client.stocks() {
+            Err(Error::RateLimited { group, retry_after }) => {
+                assert_eq!(group, EndpointGroup::MarketData);
+                assert!(retry_after > Duration::from_secs(0));
             }
-            Err(e) => Err(anyhow!("{:?}", e))
+            other => panic!("unexpected result: {:?}", other),
         }
+        assert_eq!(transport.requests().len(), 1);
+        // other groups have their own buckets
+        transport.respond_ok(Method::Get, "user/accounts", &json!({"accounts": []}));
+        client.accounts().unwrap();
     }
 }
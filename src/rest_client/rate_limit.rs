@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{Error, Result};
+
+/// Endpoint groups that the OpenAPI meters separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EndpointGroup {
+    /// Instruments, candles and order books (`/market/...`).
+    MarketData,
+    /// Order placement, cancellation and listing (`/orders/...`).
+    Orders,
+    /// Portfolio, operations and accounts.
+    Portfolio,
+    /// Sandbox account management (`/sandbox/...`).
+    Sandbox,
+}
+
+/// At most `requests` requests per `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn per_minute(requests: u32) -> Self {
+        Self {
+            requests,
+            period: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// Block the caller until the request fits into its quota.
+    Wait,
+    /// Return `Error::RateLimited` instead of waiting.
+    FailFast,
+}
+
+/// Token bucket per endpoint group. Share one limiter through an `Arc` between
+/// every client that uses the same token, so their requests are counted together.
+pub struct RateLimiter {
+    mode: RateLimitMode,
+    quotas: HashMap<EndpointGroup, Quota>,
+    buckets: Mutex<HashMap<EndpointGroup, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with the documented OpenAPI quotas. Groups can be
+    /// adjusted with `quota` or left unmetered with `unlimited`.
+    pub fn new(mode: RateLimitMode) -> Self {
+        let mut quotas = HashMap::new();
+        quotas.insert(EndpointGroup::MarketData, Quota::per_minute(240));
+        quotas.insert(EndpointGroup::Orders, Quota::per_minute(100));
+        quotas.insert(EndpointGroup::Portfolio, Quota::per_minute(120));
+        quotas.insert(EndpointGroup::Sandbox, Quota::per_minute(120));
+        Self {
+            mode,
+            quotas,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn quota(mut self, group: EndpointGroup, quota: Quota) -> Self {
+        self.quotas.insert(group, quota);
+        self
+    }
+
+    pub fn unlimited(mut self, group: EndpointGroup) -> Self {
+        self.quotas.remove(&group);
+        self
+    }
+
+    pub fn mode(&self) -> RateLimitMode {
+        self.mode
+    }
+
+    /// Takes a slot for one request of `group` and returns how long the caller
+    /// has to wait before sending it. Waiting callers are served in the order
+    /// they reserved.
+    pub(crate) fn reserve(&self, group: EndpointGroup) -> Result<Duration> {
+        let quota = match self.quotas.get(&group) {
+            Some(quota) if quota.requests > 0 => *quota,
+            _ => return Ok(Duration::from_secs(0)),
+        };
+        let capacity = f64::from(quota.requests);
+        let rate = capacity / quota.period.as_secs_f64();
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(group).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(Duration::from_secs(0));
+        }
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+        match self.mode {
+            RateLimitMode::FailFast => Err(Error::RateLimited {
+                group,
+                retry_after: wait,
+            }),
+            RateLimitMode::Wait => {
+                bucket.tokens -= 1.0;
+                Ok(wait)
+            }
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitMode::Wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn limiter(mode: RateLimitMode, requests: u32, period: Duration) -> RateLimiter {
+        RateLimiter::new(mode).quota(EndpointGroup::Orders, Quota { requests, period })
+    }
+
+    #[test]
+    fn fail_fast_allows_a_burst_then_rejects() {
+        let limiter = limiter(RateLimitMode::FailFast, 2, Duration::from_secs(60));
+        assert_eq!(limiter.reserve(EndpointGroup::Orders).unwrap(), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(EndpointGroup::Orders).unwrap(), Duration::from_secs(0));
+        match limiter.reserve(EndpointGroup::Orders) {
+            Err(Error::RateLimited { group, retry_after }) => {
+                assert_eq!(group, EndpointGroup::Orders);
+                // one token takes 30 seconds to refill
+                assert!(retry_after > Duration::from_secs(29) && retry_after <= Duration::from_secs(30));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // a rejected request does not take a slot
+        assert!(limiter.reserve(EndpointGroup::Orders).is_err());
+        assert_eq!(limiter.reserve(EndpointGroup::MarketData).unwrap(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn wait_mode_queues_callers() {
+        let limiter = limiter(RateLimitMode::Wait, 1, Duration::from_secs(60));
+        assert_eq!(limiter.reserve(EndpointGroup::Orders).unwrap(), Duration::from_secs(0));
+        let first = limiter.reserve(EndpointGroup::Orders).unwrap();
+        let second = limiter.reserve(EndpointGroup::Orders).unwrap();
+        assert!(first > Duration::from_secs(59) && first <= Duration::from_secs(60));
+        assert!(second > Duration::from_secs(119) && second <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = limiter(RateLimitMode::FailFast, 2, Duration::from_millis(100));
+        limiter.reserve(EndpointGroup::Orders).unwrap();
+        limiter.reserve(EndpointGroup::Orders).unwrap();
+        assert!(limiter.reserve(EndpointGroup::Orders).is_err());
+
+        thread::sleep(Duration::from_millis(150));
+        // refilled to capacity, not beyond
+        limiter.reserve(EndpointGroup::Orders).unwrap();
+        limiter.reserve(EndpointGroup::Orders).unwrap();
+        assert!(limiter.reserve(EndpointGroup::Orders).is_err());
+    }
+
+    #[test]
+    fn unlimited_groups_never_wait() {
+        let limiter = RateLimiter::new(RateLimitMode::FailFast)
+            .unlimited(EndpointGroup::Orders)
+            .quota(EndpointGroup::Portfolio, Quota::per_minute(0));
+        for _ in 0..1000 {
+            assert_eq!(limiter.reserve(EndpointGroup::Orders).unwrap(), Duration::from_secs(0));
+            assert_eq!(limiter.reserve(EndpointGroup::Portfolio).unwrap(), Duration::from_secs(0));
+        }
+    }
+}
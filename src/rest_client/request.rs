@@ -0,0 +1,285 @@
+// Request building and response decoding shared by RestClient and AsyncRestClient.
+// Every endpoint is described here once; the clients only differ in how they send it.
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use super::{EndpointGroup, Error, Method, Result};
+use crate::*;
+
+#[derive(Debug, Clone)]
+pub(crate) struct ApiRequest {
+    pub group: EndpointGroup,
+    pub method: Method,
+    pub url: Url,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CandlesPayload {
+    pub figi: String,
+    pub interval: CandleInterval,
+    pub candles: Vec<Candle>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Requests {
+    pub api_url: Url,
+    pub account_id: String,
+}
+
+impl Requests {
+    pub fn instrument_by_figi(&self, figi: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("market/search/by-figi")?;
+        url.query_pairs_mut().append_pair("figi", figi);
+        Ok(get(EndpointGroup::MarketData, url))
+    }
+
+    pub fn instrument_by_ticker(&self, ticker: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("market/search/by-ticker")?;
+        url.query_pairs_mut().append_pair("ticker", ticker);
+        Ok(get(EndpointGroup::MarketData, url))
+    }
+
+    pub fn currencies(&self) -> Result<ApiRequest> {
+        Ok(get(EndpointGroup::MarketData, self.api_url.join("market/currencies")?))
+    }
+
+    pub fn etfs(&self) -> Result<ApiRequest> {
+        Ok(get(EndpointGroup::MarketData, self.api_url.join("market/etfs")?))
+    }
+
+    pub fn bonds(&self) -> Result<ApiRequest> {
+        Ok(get(EndpointGroup::MarketData, self.api_url.join("market/bonds")?))
+    }
+
+    pub fn stocks(&self) -> Result<ApiRequest> {
+        Ok(get(EndpointGroup::MarketData, self.api_url.join("market/stocks")?))
+    }
+
+    pub fn operations(
+        &self,
+        account_id: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        figi: &str,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("operations")?;
+        url.query_pairs_mut()
+            .append_pair("from", from.to_rfc3339().as_str())
+            .append_pair("to", to.to_rfc3339().as_str());
+        if !figi.is_empty() {
+            url.query_pairs_mut().append_pair("figi", figi);
+        }
+        self.append_account(&mut url, account_id);
+        Ok(get(EndpointGroup::Portfolio, url))
+    }
+
+    pub fn positions_portfolio(&self, account_id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("portfolio")?;
+        self.append_account(&mut url, account_id);
+        Ok(get(EndpointGroup::Portfolio, url))
+    }
+
+    pub fn currencies_portfolio(&self, account_id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("portfolio/currencies")?;
+        self.append_account(&mut url, account_id);
+        Ok(get(EndpointGroup::Portfolio, url))
+    }
+
+    pub fn order_cancel(&self, account_id: &str, id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("orders/cancel")?;
+        url.query_pairs_mut().append_pair("orderId", id);
+        self.append_account(&mut url, account_id);
+        post(EndpointGroup::Orders, url, &json!({}))
+    }
+
+    pub fn limit_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+        price: Decimal,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("orders/limit-order")?;
+        url.query_pairs_mut().append_pair("figi", figi);
+        self.append_account(&mut url, account_id);
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Body {
+            lots: i64,
+            operation: OperationType,
+            price: Decimal,
+        }
+        let body = Body {
+            lots,
+            operation,
+            price,
+        };
+        post(EndpointGroup::Orders, url, &body)
+    }
+
+    pub fn market_order(
+        &self,
+        account_id: &str,
+        figi: &str,
+        lots: i64,
+        operation: OperationType,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("orders/market-order")?;
+        url.query_pairs_mut().append_pair("figi", figi);
+        self.append_account(&mut url, account_id);
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Body {
+            lots: i64,
+            operation: OperationType,
+        }
+        let body = Body { lots, operation };
+        post(EndpointGroup::Orders, url, &body)
+    }
+
+    pub fn orders(&self, account_id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("orders")?;
+        self.append_account(&mut url, account_id);
+        Ok(get(EndpointGroup::Orders, url))
+    }
+
+    pub fn candles(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        interval: CandleInterval,
+        figi: &str,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("market/candles")?;
+        url.query_pairs_mut()
+            .append_pair("interval", interval.as_str())
+            .append_pair("from", from.to_rfc3339().as_str())
+            .append_pair("to", to.to_rfc3339().as_str());
+        if !figi.is_empty() {
+            url.query_pairs_mut().append_pair("figi", figi);
+        }
+        Ok(get(EndpointGroup::MarketData, url))
+    }
+
+    pub fn orderbook(&self, depth: i64, figi: &str) -> Result<ApiRequest> {
+        if depth < 1 || depth > MAX_ORDERBOOK_DEPTH {
+            return Err(Error::Validation(format!(
+                "orderbook depth must be between 1 and {}, got {}",
+                MAX_ORDERBOOK_DEPTH, depth
+            )));
+        }
+        let mut url = self.api_url.join("market/orderbook")?;
+        url.query_pairs_mut().append_pair("depth", &depth.to_string());
+        if !figi.is_empty() {
+            url.query_pairs_mut().append_pair("figi", figi);
+        }
+        Ok(get(EndpointGroup::MarketData, url))
+    }
+
+    pub fn accounts(&self) -> Result<ApiRequest> {
+        Ok(get(EndpointGroup::Portfolio, self.api_url.join("user/accounts")?))
+    }
+
+    pub fn sandbox_register(&self) -> Result<ApiRequest> {
+        post(EndpointGroup::Sandbox, self.api_url.join("sandbox/register")?, &json!({}))
+    }
+
+    pub fn sandbox_clear(&self, account_id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("sandbox/clear")?;
+        self.append_account(&mut url, account_id);
+        post(EndpointGroup::Sandbox, url, &json!({}))
+    }
+
+    pub fn sandbox_remove(&self, account_id: &str) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("sandbox/remove")?;
+        self.append_account(&mut url, account_id);
+        post(EndpointGroup::Sandbox, url, &json!({}))
+    }
+
+    pub fn sandbox_set_currency_balance(
+        &self,
+        account_id: &str,
+        currency: Currency,
+        balance: Decimal,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("sandbox/currencies/balance")?;
+        self.append_account(&mut url, account_id);
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Body {
+            currency: Currency,
+            balance: Decimal,
+        }
+        post(EndpointGroup::Sandbox, url, &Body { currency, balance })
+    }
+
+    pub fn sandbox_set_position_balance(
+        &self,
+        account_id: &str,
+        figi: &str,
+        balance: Decimal,
+    ) -> Result<ApiRequest> {
+        let mut url = self.api_url.join("sandbox/positions/balance")?;
+        self.append_account(&mut url, account_id);
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Body {
+            figi: String,
+            balance: Decimal,
+        }
+        let body = Body {
+            figi: figi.to_string(),
+            balance,
+        };
+        post(EndpointGroup::Sandbox, url, &body)
+    }
+
+    // An empty account_id falls back to the account configured on the builder,
+    // and to the broker's default account when none was configured.
+    fn append_account(&self, url: &mut Url, account_id: &str) {
+        let account_id = if account_id == DEFAULT_ACCOUNT.as_str() {
+            self.account_id.as_str()
+        } else {
+            account_id
+        };
+        if !account_id.is_empty() {
+            url.query_pairs_mut().append_pair("brokerAccountId", account_id);
+        }
+    }
+}
+
+fn get(group: EndpointGroup, url: Url) -> ApiRequest {
+    ApiRequest {
+        group,
+        method: Method::Get,
+        url,
+        body: vec![],
+    }
+}
+
+fn post<B: Serialize>(group: EndpointGroup, url: Url, body: &B) -> Result<ApiRequest> {
+    let body = serde_json::to_vec(body).map_err(|e| Error::Validation(e.to_string()))?;
+    Ok(ApiRequest {
+        group,
+        method: Method::Post,
+        url,
+        body,
+    })
+}
+
+/// Unwraps the `payload` of a successful response, or maps an error response to `Error`.
+pub(crate) fn decode_response<T: DeserializeOwned>(status: u16, body: String) -> Result<T> {
+    #[derive(Deserialize)]
+    struct Envelope<T> {
+        payload: T,
+    }
+    if !(200..300).contains(&status) {
+        return Err(Error::from_response(status, body));
+    }
+    match serde_json::from_str::<Envelope<T>>(&body) {
+        Ok(envelope) => Ok(envelope.payload),
+        Err(source) => Err(Error::Decode { source, body }),
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use super::request::ApiRequest;
+use super::{EndpointGroup, Error, Method};
+
+/// Retries transient failures: transport errors, 5xx and 429 responses.
+///
+/// GET requests are retried automatically. Order placement and cancellation are
+/// only retried with `retry_orders`, since a request that timed out may still
+/// have reached the exchange. Sandbox management calls are never retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    pub retry_orders: bool,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn retry_orders(mut self, retry_orders: bool) -> Self {
+        self.retry_orders = retry_orders;
+        self
+    }
+
+    /// Returns how long to sleep before the next attempt, or `None` if `error`
+    /// after `attempt` attempts is final. `retry_after` is the server's
+    /// `Retry-After` header and takes precedence over the computed backoff,
+    /// up to `max_backoff`.
+    pub(crate) fn next_delay(
+        &self,
+        request: &ApiRequest,
+        error: &Error,
+        attempt: u32,
+        retry_after: Option<Duration>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.applies_to(request) || !is_transient(error) {
+            return None;
+        }
+        if let (Some(retry_after), Some(429)) = (retry_after, error.status()) {
+            return Some(retry_after.min(self.max_backoff));
+        }
+        let exponent = attempt.saturating_sub(1) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        // equal jitter: half of the backoff is fixed, the other half is random
+        Some(Duration::from_secs_f64(backoff / 2.0 + backoff / 2.0 * random_fraction()))
+    }
+
+    fn applies_to(&self, request: &ApiRequest) -> bool {
+        match (request.method, request.group) {
+            (Method::Get, _) => true,
+            (Method::Post, EndpointGroup::Orders) => self.retry_orders,
+            (Method::Post, _) => false,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            retry_orders: false,
+        }
+    }
+}
+
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Transport(_) => true,
+        Error::Status { status, .. } | Error::Api { status, .. } => *status == 429 || *status >= 500,
+        _ => false,
+    }
+}
+
+/// Parses the delay-seconds form of a `Retry-After` header.
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    fn request(method: Method, group: EndpointGroup) -> ApiRequest {
+        ApiRequest {
+            group,
+            method,
+            url: Url::parse("https://localhost/openapi/market/stocks").unwrap(),
+            body: vec![],
+        }
+    }
+
+    fn unavailable() -> Error {
+        Error::Status {
+            status: 503,
+            body: String::new(),
+        }
+    }
+
+    fn too_many_requests() -> Error {
+        Error::Status {
+            status: 429,
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter_up_to_the_maximum() {
+        let policy = RetryPolicy::default().max_attempts(10);
+        let read = request(Method::Get, EndpointGroup::MarketData);
+        for _ in 0..100 {
+            // 200ms, 400ms and 800ms, then capped at 5s; half of each is random
+            for (attempt, backoff) in [(1, 200), (2, 400), (3, 800), (9, 5000)] {
+                let delay = policy.next_delay(&read, &unavailable(), attempt, None).unwrap();
+                assert!(delay >= Duration::from_millis(backoff / 2), "{:?}", delay);
+                assert!(delay <= Duration::from_millis(backoff), "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn retry_after_is_honored_up_to_the_maximum_backoff() {
+        let policy = RetryPolicy::default();
+        let read = request(Method::Get, EndpointGroup::MarketData);
+        let delay = policy.next_delay(&read, &too_many_requests(), 1, Some(Duration::from_secs(2)));
+        assert_eq!(delay, Some(Duration::from_secs(2)));
+        let delay = policy.next_delay(&read, &too_many_requests(), 1, Some(Duration::from_secs(86400)));
+        assert_eq!(delay, Some(Duration::from_secs(5)));
+        // only 429 responses carry a meaningful Retry-After
+        let delay = policy.next_delay(&read, &unavailable(), 1, Some(Duration::from_secs(2))).unwrap();
+        assert!(delay <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn final_errors_are_not_retried() {
+        let policy = RetryPolicy::default();
+        let read = request(Method::Get, EndpointGroup::MarketData);
+        assert!(policy.next_delay(&read, &unavailable(), 3, None).is_none());
+        assert!(policy.next_delay(&read, &Error::Validation(String::new()), 1, None).is_none());
+        let not_found = Error::Status {
+            status: 404,
+            body: String::new(),
+        };
+        assert!(policy.next_delay(&read, &not_found, 1, None).is_none());
+        assert!(policy.next_delay(&read, &Error::Transport("reset".into()), 1, None).is_some());
+    }
+
+    #[test]
+    fn orders_are_retried_only_when_enabled() {
+        let order = request(Method::Post, EndpointGroup::Orders);
+        let sandbox = request(Method::Post, EndpointGroup::Sandbox);
+        assert!(RetryPolicy::default().next_delay(&order, &unavailable(), 1, None).is_none());
+        let policy = RetryPolicy::default().retry_orders(true);
+        assert!(policy.next_delay(&order, &unavailable(), 1, None).is_some());
+        assert!(policy.next_delay(&sandbox, &unavailable(), 1, None).is_none());
+    }
+
+    #[test]
+    fn parses_delay_seconds() {
+        assert_eq!(parse_retry_after(" 3 "), Some(Duration::from_secs(3)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
@@ -0,0 +1,1038 @@
+// In-process emulation of the OpenAPI sandbox: every request RestClient can make
+// is answered from an in-memory ledger instead of the remote server.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::Url;
+
+use super::{HttpRequest, HttpResponse, Method, RestClient, RestClientBuilder, Result, RetryPolicy, Transport};
+use crate::book::{LocalOrderBook, Side};
+use crate::*;
+
+/// Offline replacement for the OpenAPI sandbox, used as a `Transport`.
+///
+/// Accounts, balances, positions, orders and operations live in memory, so
+/// tests run without network access and always get the same answers. Market
+/// data comes from what the test registers with `add_instrument`, `set_price`
+/// and `add_candles`.
+///
+/// Market orders fill at once at the last price, moved against the order by
+/// the slippage if one is set. Limit orders that cross the
+/// last price fill at it immediately; the rest block funds and stay active
+/// until `set_price` reaches their limit, then fill at the limit price.
+/// Instruments given an order book with `set_orderbook` trade against its
+/// levels instead: an order takes at most the visible quantity, which is used
+/// up until the next snapshot. The unfilled rest of a limit order stays
+/// active and the rest of a market order is cancelled.
+/// Clones share the same ledger.
+#[derive(Clone, Default)]
+pub struct SandboxEmulator {
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+#[derive(Default)]
+struct Ledger {
+    now: Option<DateTime<Utc>>,
+    next_id: u64,
+    commission_rate: Decimal,
+    slippage: Decimal,
+    instruments: Vec<Instrument>,
+    prices: HashMap<String, Decimal>,
+    books: HashMap<String, LocalOrderBook>,
+    candles: Vec<Candle>,
+    accounts: Vec<SandboxAccount>,
+}
+
+struct SandboxAccount {
+    id: String,
+    r#type: AccountType,
+    currencies: Vec<CurrencyBalance>,
+    positions: Vec<Position>,
+    orders: Vec<ActiveOrder>,
+    operations: Vec<Operation>,
+}
+
+struct Position {
+    figi: String,
+    balance: Decimal,
+    blocked: Decimal,
+    average_price: Option<Decimal>,
+}
+
+struct ActiveOrder {
+    order: Order,
+    // currency or securities held back until the order fills or is cancelled,
+    // for `blocked_lots` lots
+    blocked: Decimal,
+    blocked_lots: i64,
+}
+
+type Reply = std::result::Result<HttpResponse, HttpResponse>;
+
+impl SandboxEmulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A sandbox `RestClient` that sends every request to this emulator.
+    /// Retries are disabled since the emulator never fails transiently.
+    pub fn client(&self) -> RestClient {
+        RestClientBuilder::new("sandbox-emulator".to_string())
+            .sandbox()
+            .transport(self.clone())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    /// Makes an instrument available to market data requests and orders.
+    pub fn add_instrument(&self, instrument: Instrument) {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.instruments.retain(|i| i.figi != instrument.figi);
+        ledger.instruments.push(instrument);
+    }
+
+    /// Sets the last price of `figi` and fills the active limit orders it reaches.
+    pub fn set_price(&self, figi: &str, price: Decimal) {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.prices.insert(figi.to_string(), price);
+        ledger.fill_crossed_orders(figi, Some(price), Some(price));
+    }
+
+    /// Sets the order book of `book.figi`. Orders for the instrument then trade
+    /// against its levels instead of the last price, active limit orders fill
+    /// at their limit up to the quantity of the opposite levels that reach
+    /// them, and the mid price becomes the last price.
+    pub fn set_orderbook(&self, book: LocalOrderBook) {
+        let mut ledger = self.ledger.lock().unwrap();
+        let figi = book.figi.clone();
+        if let Some(mid) = book.mid_price() {
+            ledger.prices.insert(figi.clone(), mid);
+        }
+        ledger.books.insert(figi.clone(), book);
+        ledger.fill_from_book(&figi);
+    }
+
+    /// Candles served by `RestClient::candles`, matched by figi, interval and time.
+    pub fn add_candles(&self, candles: Vec<Candle>) {
+        let mut ledger = self.ledger.lock().unwrap();
+        ledger.candles.extend(candles);
+        ledger.candles.sort_by_key(|c| c.ts);
+    }
+
+    /// Freezes the clock used for order and operation times. Without it the
+    /// current time is used.
+    pub fn set_time(&self, now: DateTime<Utc>) {
+        self.ledger.lock().unwrap().now = Some(now);
+    }
+
+    /// Broker commission as a fraction of the trade value, e.g. `0.003` for 0.3%.
+    /// Defaults to zero.
+    pub fn set_commission(&self, rate: Decimal) {
+        self.ledger.lock().unwrap().commission_rate = rate;
+    }
+
+    /// Price move against market orders as a fraction of the last price, e.g.
+    /// `0.001` fills a buy at 100 for 100.1. Defaults to zero.
+    pub fn set_slippage(&self, rate: Decimal) {
+        self.ledger.lock().unwrap().slippage = rate;
+    }
+}
+
+impl Transport for SandboxEmulator {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let mut ledger = self.ledger.lock().unwrap();
+        let reply = ledger.handle(request);
+        Ok(reply.unwrap_or_else(|response| response))
+    }
+}
+
+// Routes relative to the API root, longest first where one is a suffix of another.
+const ROUTES: &[(Method, &str)] = &[
+    (Method::Post, "sandbox/register"),
+    (Method::Post, "sandbox/currencies/balance"),
+    (Method::Post, "sandbox/positions/balance"),
+    (Method::Post, "sandbox/clear"),
+    (Method::Post, "sandbox/remove"),
+    (Method::Get, "user/accounts"),
+    (Method::Get, "portfolio/currencies"),
+    (Method::Get, "portfolio"),
+    (Method::Get, "operations"),
+    (Method::Post, "orders/limit-order"),
+    (Method::Post, "orders/market-order"),
+    (Method::Post, "orders/cancel"),
+    (Method::Get, "orders"),
+    (Method::Get, "market/stocks"),
+    (Method::Get, "market/bonds"),
+    (Method::Get, "market/etfs"),
+    (Method::Get, "market/currencies"),
+    (Method::Get, "market/search/by-figi"),
+    (Method::Get, "market/search/by-ticker"),
+    (Method::Get, "market/candles"),
+    (Method::Get, "market/orderbook"),
+];
+
+impl Ledger {
+    fn handle(&mut self, request: &HttpRequest) -> Reply {
+        let path = request.url.path().trim_end_matches('/');
+        let route = ROUTES
+            .iter()
+            .find(|(method, route)| *method == request.method && path.ends_with(&format!("/{}", route)))
+            .map(|(_, route)| *route)
+            .ok_or_else(|| {
+                HttpResponse::api_error(404, "NOT_FOUND", &format!("unknown endpoint {:?} {}", request.method, path))
+            })?;
+        let query = Query::new(&request.url);
+
+        match route {
+            "sandbox/register" => self.register(request),
+            "sandbox/currencies/balance" => self.set_currency_balance(&query, request),
+            "sandbox/positions/balance" => self.set_position_balance(&query, request),
+            "sandbox/clear" => self.clear(&query),
+            "sandbox/remove" => self.remove(&query),
+            "user/accounts" => self.accounts(),
+            "portfolio/currencies" => self.currencies_portfolio(&query),
+            "portfolio" => self.positions_portfolio(&query),
+            "operations" => self.operations(&query),
+            "orders/limit-order" => self.limit_order(&query, request),
+            "orders/market-order" => self.market_order(&query, request),
+            "orders/cancel" => self.order_cancel(&query),
+            "orders" => self.orders(&query),
+            "market/stocks" => self.instruments_of_type(InstrumentType::Stock),
+            "market/bonds" => self.instruments_of_type(InstrumentType::Bond),
+            "market/etfs" => self.instruments_of_type(InstrumentType::Etf),
+            "market/currencies" => self.instruments_of_type(InstrumentType::Currency),
+            "market/search/by-figi" => {
+                let instrument = self.instrument(query.required("figi")?)?;
+                Ok(HttpResponse::ok(instrument))
+            }
+            "market/search/by-ticker" => {
+                let ticker = query.required("ticker")?;
+                let instruments: Vec<&Instrument> = self.instruments.iter().filter(|i| i.ticker == ticker).collect();
+                Ok(HttpResponse::ok(&json!({ "instruments": instruments })))
+            }
+            "market/candles" => self.candles(&query),
+            "market/orderbook" => self.orderbook(&query),
+            _ => unreachable!(),
+        }
+    }
+
+    fn register(&mut self, request: &HttpRequest) -> Reply {
+        #[derive(Default, Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Body {
+            broker_account_type: Option<AccountType>,
+        }
+        let body: Body = serde_json::from_slice(&request.body).unwrap_or_default();
+        let account = SandboxAccount {
+            id: format!("SB{:07}", self.next_id()),
+            r#type: body.broker_account_type.unwrap_or(AccountType::Tinkoff),
+            currencies: vec![],
+            positions: vec![],
+            orders: vec![],
+            operations: vec![],
+        };
+        let payload = Account {
+            r#type: account.r#type.clone(),
+            id: account.id.clone(),
+        };
+        self.accounts.push(account);
+        Ok(HttpResponse::ok(&payload))
+    }
+
+    fn set_currency_balance(&mut self, query: &Query, request: &HttpRequest) -> Reply {
+        #[derive(Deserialize)]
+        struct Body {
+            currency: Currency,
+            balance: Decimal,
+        }
+        let body: Body = parse_body(request)?;
+        let account = self.account_mut(query)?;
+        match account.currencies.iter_mut().find(|c| c.currency == body.currency) {
+            Some(currency) => currency.balance = body.balance,
+            None => account.currencies.push(CurrencyBalance {
+                currency: body.currency,
+                balance: body.balance,
+                blocked: Decimal::ZERO,
+            }),
+        }
+        Ok(empty())
+    }
+
+    fn set_position_balance(&mut self, query: &Query, request: &HttpRequest) -> Reply {
+        #[derive(Deserialize)]
+        struct Body {
+            figi: String,
+            balance: Decimal,
+        }
+        let body: Body = parse_body(request)?;
+        let average_price = self.prices.get(&body.figi).copied();
+        let account = self.account_mut(query)?;
+        account.positions.retain(|p| p.figi != body.figi);
+        if !body.balance.is_zero() {
+            account.positions.push(Position {
+                figi: body.figi,
+                balance: body.balance,
+                blocked: Decimal::ZERO,
+                average_price,
+            });
+        }
+        Ok(empty())
+    }
+
+    fn clear(&mut self, query: &Query) -> Reply {
+        let account = self.account_mut(query)?;
+        account.currencies.clear();
+        account.positions.clear();
+        account.orders.clear();
+        account.operations.clear();
+        Ok(empty())
+    }
+
+    fn remove(&mut self, query: &Query) -> Reply {
+        let id = self.account_mut(query)?.id.clone();
+        self.accounts.retain(|a| a.id != id);
+        Ok(empty())
+    }
+
+    fn accounts(&self) -> Reply {
+        let accounts: Vec<Account> = self
+            .accounts
+            .iter()
+            .map(|a| Account {
+                r#type: a.r#type.clone(),
+                id: a.id.clone(),
+            })
+            .collect();
+        Ok(HttpResponse::ok(&Accounts { accounts }))
+    }
+
+    fn currencies_portfolio(&mut self, query: &Query) -> Reply {
+        let currencies = self.account_mut(query)?.currencies.clone();
+        Ok(HttpResponse::ok(&CurrencyBalances { currencies }))
+    }
+
+    fn positions_portfolio(&mut self, query: &Query) -> Reply {
+        let account_index = self.account_index(query)?;
+        let account = &self.accounts[account_index];
+        let positions = account
+            .positions
+            .iter()
+            .map(|p| {
+                let instrument = self.instruments.iter().find(|i| i.figi == p.figi);
+                let currency = instrument.map_or(Currency::Rub, |i| i.currency.clone());
+                let lot = instrument.map_or(1, |i| i.lot.max(1));
+                let money = |value: Decimal| MoneyAmount {
+                    currency: currency.clone(),
+                    value,
+                };
+                let expected_yield = match (p.average_price, self.prices.get(&p.figi)) {
+                    (Some(average), Some(last)) => Some(money((*last - average) * p.balance)),
+                    _ => None,
+                };
+                PositionBalance {
+                    figi: p.figi.clone(),
+                    ticker: instrument.map_or_else(|| p.figi.clone(), |i| i.ticker.clone()),
+                    isin: instrument.map(|i| i.isin.clone()).unwrap_or_default(),
+                    instrument_type: instrument.map_or(InstrumentType::Stock, |i| i.r#type.clone()),
+                    balance: p.balance,
+                    blocked: p.blocked,
+                    lots: (p.balance / Decimal::from(lot)).trunc().to_i64().unwrap_or(0),
+                    expected_yield,
+                    average_position_price: p.average_price.map(money),
+                    average_position_price_no_nkd: None,
+                    name: instrument.map(|i| i.name.clone()).unwrap_or_default(),
+                }
+            })
+            .collect();
+        Ok(HttpResponse::ok(&PositionBalances { positions }))
+    }
+
+    fn operations(&mut self, query: &Query) -> Reply {
+        let from = query.time("from")?;
+        let to = query.time("to")?;
+        let figi = query.get("figi").unwrap_or_default();
+        let account = self.account_mut(query)?;
+        let operations: Vec<&Operation> = account
+            .operations
+            .iter()
+            .filter(|o| o.date_time >= from && o.date_time < to)
+            .filter(|o| figi.is_empty() || o.figi == figi)
+            .collect();
+        Ok(HttpResponse::ok(&json!({ "operations": operations })))
+    }
+
+    fn orders(&mut self, query: &Query) -> Reply {
+        let orders: Vec<Order> = self.account_mut(query)?.orders.iter().map(|o| o.order.clone()).collect();
+        Ok(HttpResponse::ok(&Orders { orders }))
+    }
+
+    fn limit_order(&mut self, query: &Query, request: &HttpRequest) -> Reply {
+        #[derive(Deserialize)]
+        struct Body {
+            lots: i64,
+            operation: OperationType,
+            price: Decimal,
+        }
+        let body: Body = parse_body(request)?;
+        self.place_order(query, body.lots, body.operation, OrderType::Limit, Some(body.price))
+    }
+
+    fn market_order(&mut self, query: &Query, request: &HttpRequest) -> Reply {
+        #[derive(Deserialize)]
+        struct Body {
+            lots: i64,
+            operation: OperationType,
+        }
+        let body: Body = parse_body(request)?;
+        self.place_order(query, body.lots, body.operation, OrderType::Market, None)
+    }
+
+    fn place_order(
+        &mut self,
+        query: &Query,
+        lots: i64,
+        operation: OperationType,
+        order_type: OrderType,
+        limit_price: Option<Decimal>,
+    ) -> Reply {
+        let figi = query.required("figi")?;
+        let account_index = self.account_index(query)?;
+        if lots <= 0 {
+            return Err(validation_error(&format!("lots must be positive, got {}", lots)));
+        }
+        if operation != OperationType::Buy && operation != OperationType::Sell {
+            return Err(validation_error(&format!("unsupported order operation {}", operation)));
+        }
+        if let Some(limit) = limit_price.filter(|limit| *limit <= Decimal::ZERO) {
+            return Err(validation_error(&format!("price must be positive, got {}", limit)));
+        }
+        let instrument = self.instrument(figi)?.clone();
+        let currency = instrument.currency.clone();
+
+        // lots that trade at once, by price
+        let fills = match self.books.get(figi) {
+            Some(book) => walk_book(book, &operation, lots, limit_price),
+            None => {
+                let mut last_price = self.prices.get(figi).copied();
+                if order_type == OrderType::Market {
+                    let slippage = if operation == OperationType::Buy { self.slippage } else { -self.slippage };
+                    last_price = last_price.map(|last| last + last * slippage);
+                }
+                let crosses = |last: Decimal| match limit_price {
+                    None => true,
+                    Some(limit) if operation == OperationType::Buy => last <= limit,
+                    Some(limit) => last >= limit,
+                };
+                match last_price {
+                    Some(last) if crosses(last) => vec![(last, lots)],
+                    Some(_) => vec![],
+                    None if limit_price.is_some() => vec![],
+                    None => return Err(reject("NO_MARKET_PRICE", &format!("no market price for {}", figi))),
+                }
+            }
+        };
+        // a market order blocks funds at the worst price it reaches, and only
+        // for the lots it gets since the rest is cancelled
+        let (limit, blocked_lots) = match (limit_price, fills.last()) {
+            (Some(limit), _) => (limit, lots),
+            (None, Some((price, _))) => (*price, fills.iter().map(|(_, lots)| lots).sum()),
+            (None, None) => return Err(reject("NO_MARKET_PRICE", &format!("no liquidity for {}", figi))),
+        };
+
+        // block what the order needs at its worst price; fills release it
+        let quantity = Decimal::from(blocked_lots * instrument.lot.max(1));
+        let blocked = if operation == OperationType::Buy {
+            let amount = limit * quantity + self.commission(limit * quantity);
+            let balance = self.accounts[account_index].currency_mut(&currency);
+            if balance.balance - balance.blocked < amount {
+                return Err(reject("NOT_ENOUGH_BALANCE", &format!("not enough {} to buy {}", currency, figi)));
+            }
+            balance.blocked += amount;
+            amount
+        } else {
+            let position = self.accounts[account_index].positions.iter_mut().find(|p| p.figi == figi);
+            match position {
+                Some(position) if position.balance - position.blocked >= quantity => {
+                    position.blocked += quantity;
+                    quantity
+                }
+                _ => return Err(reject("NOT_ENOUGH_POSITIONS", &format!("not enough {} to sell", figi))),
+            }
+        };
+
+        let order = Order {
+            id: self.next_id().to_string(),
+            figi: figi.to_string(),
+            operation: operation.clone(),
+            status: OrderStatus::New,
+            requested_lots: lots,
+            executed_lots: 0,
+            r#type: order_type.clone(),
+            price: limit,
+        };
+        let mut active = ActiveOrder {
+            order,
+            blocked,
+            blocked_lots,
+        };
+        if let Some(book) = self.books.get_mut(figi) {
+            take_from_book(book, &operation, &fills);
+        }
+        let mut commission = Decimal::ZERO;
+        for (price, fill_lots) in fills {
+            commission += self.fill(account_index, &mut active, &instrument, fill_lots, price);
+        }
+
+        let order = &active.order;
+        let mut placed = PlacedOrder {
+            id: order.id.clone(),
+            operation,
+            status: order.status.clone(),
+            reject_reason: String::new(),
+            requested_lots: lots,
+            executed_lots: order.executed_lots,
+            commission: None,
+            message: String::new(),
+        };
+        if order.executed_lots > 0 {
+            placed.commission = Some(MoneyAmount {
+                currency,
+                value: commission,
+            });
+        }
+        if order.executed_lots < lots {
+            if order_type == OrderType::Market {
+                // the book had too little; like on the exchange the rest is cancelled
+                placed.message = format!("{} lots cancelled for lack of liquidity", lots - order.executed_lots);
+                let remaining = active.blocked;
+                self.release(account_index, &active.order, remaining);
+            } else {
+                self.accounts[account_index].orders.push(active);
+            }
+        }
+        Ok(HttpResponse::ok(&placed))
+    }
+
+    fn order_cancel(&mut self, query: &Query) -> Reply {
+        let id = query.required("orderId")?;
+        let account_index = self.account_index(query)?;
+        let orders = &mut self.accounts[account_index].orders;
+        let index = orders
+            .iter()
+            .position(|o| o.order.id == id)
+            .ok_or_else(|| not_found("ORDER_NOT_FOUND", &format!("order {} not found", id)))?;
+        let active = orders.remove(index);
+        self.release(account_index, &active.order, active.blocked);
+        Ok(empty())
+    }
+
+    fn instruments_of_type(&self, r#type: InstrumentType) -> Reply {
+        let instruments: Vec<&Instrument> = self.instruments.iter().filter(|i| i.r#type == r#type).collect();
+        Ok(HttpResponse::ok(&json!({ "instruments": instruments })))
+    }
+
+    fn candles(&self, query: &Query) -> Reply {
+        let figi = query.required("figi")?;
+        let interval = CandleInterval::from(query.required("interval")?);
+        let from = query.time("from")?;
+        let to = query.time("to")?;
+        let candles: Vec<&Candle> = self
+            .candles
+            .iter()
+            .filter(|c| c.figi == figi && c.interval == interval && c.ts >= from && c.ts < to)
+            .collect();
+        Ok(HttpResponse::ok(&json!({"figi": figi, "interval": interval, "candles": candles})))
+    }
+
+    // Levels come from `set_orderbook`; without one the book is empty and only
+    // carries the last price.
+    fn orderbook(&self, query: &Query) -> Reply {
+        let figi = query.required("figi")?;
+        let depth = query.required("depth")?.parse().unwrap_or(0);
+        let instrument = self.instrument(figi)?;
+        let last_price = self.prices.get(figi).copied().unwrap_or_default();
+        let levels = |side: Side| -> Vec<RestPriceQuantity> {
+            let levels = self.books.get(figi).map_or(&[][..], |book| book.levels(side));
+            levels
+                .iter()
+                .take(depth.max(0) as usize)
+                .map(|l| RestPriceQuantity {
+                    price: l.price,
+                    quantity: l.quantity.to_f64().unwrap_or_default(),
+                })
+                .collect()
+        };
+        let orderbook = RestOrderBook {
+            figi: figi.to_string(),
+            depth,
+            bids: levels(Side::Bid),
+            asks: levels(Side::Ask),
+            trade_status: TradingStatus::NormalTrading,
+            min_price_increment: instrument.min_price_increment,
+            last_price,
+            close_price: last_price,
+            limit_up: Decimal::ZERO,
+            limit_down: Decimal::ZERO,
+            face_value: Decimal::ZERO,
+        };
+        Ok(HttpResponse::ok(&orderbook))
+    }
+
+    // Fills the active limit orders that buys at `buy_price` or sells at
+    // `sell_price` reach, at their limit price.
+    fn fill_crossed_orders(&mut self, figi: &str, buy_price: Option<Decimal>, sell_price: Option<Decimal>) {
+        // orders are only placed for known instruments
+        let instrument = match self.instrument(figi) {
+            Ok(instrument) => instrument.clone(),
+            Err(_) => return,
+        };
+        for account_index in 0..self.accounts.len() {
+            let orders = std::mem::take(&mut self.accounts[account_index].orders);
+            let (crossed, active): (Vec<_>, Vec<_>) = orders.into_iter().partition(|o| {
+                o.order.figi == figi
+                    && match o.order.operation {
+                        OperationType::Buy => buy_price.is_some_and(|price| price <= o.order.price),
+                        _ => sell_price.is_some_and(|price| price >= o.order.price),
+                    }
+            });
+            self.accounts[account_index].orders = active;
+            for mut order in crossed {
+                let limit = order.order.price;
+                let lots = order.order.requested_lots - order.order.executed_lots;
+                self.fill(account_index, &mut order, &instrument, lots, limit);
+            }
+        }
+    }
+
+    // Fills the active limit orders that the order book of `figi` crosses, at
+    // their limit price and up to the quantity of the crossing levels, which
+    // is then taken out of the book. Earlier orders are served first.
+    fn fill_from_book(&mut self, figi: &str) {
+        let instrument = match self.instrument(figi) {
+            Ok(instrument) => instrument.clone(),
+            Err(_) => return,
+        };
+        for account_index in 0..self.accounts.len() {
+            let orders = std::mem::take(&mut self.accounts[account_index].orders);
+            let mut active = vec![];
+            for mut order in orders {
+                let book = match self.books.get_mut(figi) {
+                    Some(book) if order.order.figi == figi => book,
+                    _ => {
+                        active.push(order);
+                        continue;
+                    }
+                };
+                let remaining = order.order.requested_lots - order.order.executed_lots;
+                let fills = walk_book(book, &order.order.operation, remaining, Some(order.order.price));
+                take_from_book(book, &order.order.operation, &fills);
+                let lots: i64 = fills.iter().map(|(_, lots)| lots).sum();
+                if lots > 0 {
+                    let limit = order.order.price;
+                    self.fill(account_index, &mut order, &instrument, lots, limit);
+                }
+                if order.order.executed_lots < order.order.requested_lots {
+                    active.push(order);
+                }
+            }
+            self.accounts[account_index].orders = active;
+        }
+    }
+
+    // Executes `lots` of an order at `price`, adds the trade to the order's
+    // operation and returns the commission.
+    fn fill(
+        &mut self,
+        account_index: usize,
+        active: &mut ActiveOrder,
+        instrument: &Instrument,
+        lots: i64,
+        price: Decimal,
+    ) -> Decimal {
+        let lot = instrument.lot.max(1);
+        // release the share of the block that these lots held, all of it on the last fill
+        let released = if lots >= active.blocked_lots {
+            active.blocked
+        } else {
+            active.blocked * Decimal::from(lots) / Decimal::from(active.blocked_lots)
+        };
+        active.blocked -= released;
+        active.blocked_lots -= lots;
+        self.release(account_index, &active.order, released);
+        active.order.executed_lots += lots;
+        active.order.status = if active.order.executed_lots >= active.order.requested_lots {
+            OrderStatus::Fill
+        } else {
+            OrderStatus::PartiallyFill
+        };
+
+        let order = &active.order;
+        let currency = instrument.currency.clone();
+        let quantity = lots * lot;
+        let value = price * Decimal::from(quantity);
+        let commission = self.commission(value);
+        let now = self.now();
+        let trade = Trade {
+            id: self.next_id().to_string(),
+            date_time: now,
+            price,
+            quantity,
+        };
+
+        let account = &mut self.accounts[account_index];
+        let payment = if order.operation == OperationType::Buy {
+            account.currency_mut(&currency).balance -= value + commission;
+            let position = account.position_mut(&order.figi);
+            let held = position.balance;
+            let average = position.average_price.unwrap_or(price);
+            position.average_price =
+                Some((average * held + value) / (held + Decimal::from(quantity)));
+            position.balance += Decimal::from(quantity);
+            -value
+        } else {
+            account.currency_mut(&currency).balance += value - commission;
+            account.position_mut(&order.figi).balance -= Decimal::from(quantity);
+            account.positions.retain(|p| !p.balance.is_zero());
+            value
+        };
+        match account.operations.iter_mut().find(|o| o.id == order.id) {
+            Some(operation) => {
+                operation.trades.push(trade);
+                operation.payment += payment;
+                operation.quantity_executed += quantity;
+                operation.price = (operation.payment.abs() / Decimal::from(operation.quantity_executed)).round_dp(4);
+                if let Some(booked) = operation.commission.as_mut() {
+                    booked.value -= commission;
+                }
+            }
+            None => account.operations.push(Operation {
+                id: order.id.clone(),
+                status: OperationStatus::Done,
+                trades: vec![trade],
+                commission: Some(MoneyAmount {
+                    currency: currency.clone(),
+                    value: -commission,
+                }),
+                currency,
+                payment,
+                price,
+                quantity: order.requested_lots * lot,
+                quantity_executed: quantity,
+                figi: order.figi.clone(),
+                instrument_type: Some(instrument.r#type.clone()),
+                is_margin_call: false,
+                date_time: now,
+                operation_type: order.operation.clone(),
+            }),
+        }
+        commission
+    }
+
+    // Returns `amount` of the funds or securities an order was holding back.
+    fn release(&mut self, account_index: usize, order: &Order, amount: Decimal) {
+        let currency = self
+            .instruments
+            .iter()
+            .find(|i| i.figi == order.figi)
+            .map_or(Currency::Rub, |i| i.currency.clone());
+        let account = &mut self.accounts[account_index];
+        if order.operation == OperationType::Buy {
+            account.currency_mut(&currency).blocked -= amount;
+        } else {
+            account.position_mut(&order.figi).blocked -= amount;
+        }
+    }
+
+    fn commission(&self, value: Decimal) -> Decimal {
+        (value * self.commission_rate).round_dp(2)
+    }
+
+    fn instrument(&self, figi: &str) -> std::result::Result<&Instrument, HttpResponse> {
+        self.instruments
+            .iter()
+            .find(|i| i.figi == figi)
+            .ok_or_else(|| not_found("INSTRUMENT_NOT_FOUND", &format!("instrument {} not found", figi)))
+    }
+
+    // Without brokerAccountId requests go to the first registered account, like
+    // the default broker account of the real API.
+    fn account_index(&self, query: &Query) -> std::result::Result<usize, HttpResponse> {
+        let id = query.get("brokerAccountId");
+        self.accounts
+            .iter()
+            .position(|a| id.is_none_or(|id| a.id == id))
+            .ok_or_else(|| {
+                not_found(
+                    "BROKER_ACCOUNT_NOT_FOUND",
+                    &format!("broker account {} not found", id.unwrap_or("(default)")),
+                )
+            })
+    }
+
+    fn account_mut(&mut self, query: &Query) -> std::result::Result<&mut SandboxAccount, HttpResponse> {
+        let index = self.account_index(query)?;
+        Ok(&mut self.accounts[index])
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.now.unwrap_or_else(Utc::now)
+    }
+}
+
+impl SandboxAccount {
+    fn currency_mut(&mut self, currency: &Currency) -> &mut CurrencyBalance {
+        if let Some(index) = self.currencies.iter().position(|c| &c.currency == currency) {
+            return &mut self.currencies[index];
+        }
+        self.currencies.push(CurrencyBalance {
+            currency: currency.clone(),
+            balance: Decimal::ZERO,
+            blocked: Decimal::ZERO,
+        });
+        self.currencies.last_mut().unwrap()
+    }
+
+    fn position_mut(&mut self, figi: &str) -> &mut Position {
+        if let Some(index) = self.positions.iter().position(|p| p.figi == figi) {
+            return &mut self.positions[index];
+        }
+        self.positions.push(Position {
+            figi: figi.to_string(),
+            balance: Decimal::ZERO,
+            blocked: Decimal::ZERO,
+            average_price: None,
+        });
+        self.positions.last_mut().unwrap()
+    }
+}
+
+// Lots taken from the side of `book` that `operation` trades against, by
+// price, best level first and using only levels within `limit`. At most the
+// visible quantity is taken, so the result may cover fewer than `lots` lots.
+fn walk_book(
+    book: &LocalOrderBook,
+    operation: &OperationType,
+    lots: i64,
+    limit: Option<Decimal>,
+) -> Vec<(Decimal, i64)> {
+    let buy = *operation == OperationType::Buy;
+    let levels = book.levels(if buy { Side::Ask } else { Side::Bid });
+    let within = |price: Decimal| limit.is_none_or(|limit| if buy { price <= limit } else { price >= limit });
+    let mut remaining = lots;
+    let mut fills = vec![];
+    for level in levels.iter().take_while(|l| within(l.price)) {
+        if remaining == 0 {
+            break;
+        }
+        let taken = remaining.min(level.quantity.trunc().to_i64().unwrap_or(0));
+        if taken > 0 {
+            fills.push((level.price, taken));
+            remaining -= taken;
+        }
+    }
+    fills
+}
+
+// Takes the lots in `fills` out of the levels they came from, so the next
+// order does not trade against the same liquidity.
+fn take_from_book(book: &mut LocalOrderBook, operation: &OperationType, fills: &[(Decimal, i64)]) {
+    let levels = if *operation == OperationType::Buy {
+        &mut book.asks
+    } else {
+        &mut book.bids
+    };
+    for (price, lots) in fills {
+        if let Some(level) = levels.iter_mut().find(|l| l.price == *price) {
+            level.quantity -= Decimal::from(*lots);
+        }
+    }
+    levels.retain(|l| l.quantity > Decimal::ZERO);
+}
+
+struct Query(HashMap<String, String>);
+
+impl Query {
+    fn new(url: &Url) -> Self {
+        Query(url.query_pairs().into_owned().collect())
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> std::result::Result<&str, HttpResponse> {
+        self.get(name)
+            .ok_or_else(|| validation_error(&format!("missing query parameter {}", name)))
+    }
+
+    fn time(&self, name: &str) -> std::result::Result<DateTime<Utc>, HttpResponse> {
+        let value = self.required(name)?;
+        DateTime::parse_from_rfc3339(value)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|e| validation_error(&format!("invalid {}: {}", name, e)))
+    }
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(request: &HttpRequest) -> std::result::Result<T, HttpResponse> {
+    serde_json::from_slice(&request.body).map_err(|e| validation_error(&e.to_string()))
+}
+
+fn empty() -> HttpResponse {
+    #[derive(Serialize)]
+    struct Empty {}
+    HttpResponse::ok(&Empty {})
+}
+
+// Business rejections are client errors, so retry policies leave them alone.
+fn reject(code: &str, message: &str) -> HttpResponse {
+    HttpResponse::api_error(400, code, message)
+}
+
+fn not_found(code: &str, message: &str) -> HttpResponse {
+    HttpResponse::api_error(404, code, message)
+}
+
+fn validation_error(message: &str) -> HttpResponse {
+    HttpResponse::api_error(400, "VALIDATION_ERROR", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::Level;
+
+    fn stock(figi: &str, lot: i64) -> Instrument {
+        Instrument {
+            figi: figi.to_string(),
+            ticker: figi.to_string(),
+            isin: String::new(),
+            name: figi.to_string(),
+            min_price_increment: Decimal::new(1, 2),
+            lot,
+            currency: Currency::Rub,
+            r#type: InstrumentType::Stock,
+        }
+    }
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn rejections_are_client_errors() {
+        let emulator = SandboxEmulator::new();
+        emulator.add_instrument(stock("FIGI", 1));
+        emulator.set_price("FIGI", dec("100"));
+        let client = emulator.client();
+        let account = client.sandbox_register().unwrap().id;
+
+        let error = client.market_order(&account, "FIGI", 1, OperationType::Buy).unwrap_err();
+        assert_eq!(error.status(), Some(400));
+        assert!(matches!(error, crate::rest_client::Error::Api { ref code, .. } if code == "NOT_ENOUGH_BALANCE"));
+
+        let error = client.market_order(&account, "OTHER", 1, OperationType::Buy).unwrap_err();
+        assert_eq!(error.status(), Some(404));
+
+        let error = client.order_cancel(&account, "42").unwrap_err();
+        assert_eq!(error.status(), Some(404));
+    }
+
+    fn book(figi: &str, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> LocalOrderBook {
+        let levels = |levels: &[(&str, &str)]| {
+            levels
+                .iter()
+                .map(|(price, quantity)| Level {
+                    price: dec(price),
+                    quantity: dec(quantity),
+                })
+                .collect()
+        };
+        LocalOrderBook {
+            figi: figi.to_string(),
+            depth: 20,
+            bids: levels(bids),
+            asks: levels(asks),
+            time: None,
+        }
+    }
+
+    fn rub(client: &RestClient, account: &str) -> CurrencyBalance {
+        let currencies = client.currencies_portfolio(account).unwrap().currencies;
+        currencies.into_iter().find(|c| c.currency == Currency::Rub).unwrap()
+    }
+
+    #[test]
+    fn market_orders_fill_only_visible_depth_and_consume_it() {
+        let emulator = SandboxEmulator::new();
+        emulator.add_instrument(stock("FIGI", 1));
+        emulator.set_orderbook(book("FIGI", &[("99", "10")], &[("100", "2"), ("101", "3")]));
+        let client = emulator.client();
+        let account = client.sandbox_register().unwrap().id;
+        client.sandbox_set_currency_balance(&account, Currency::Rub, dec("10000")).unwrap();
+
+        let placed = client.market_order(&account, "FIGI", 3, OperationType::Buy).unwrap();
+        assert_eq!(placed.status, OrderStatus::Fill);
+        assert_eq!(placed.executed_lots, 3);
+
+        // two lots at 101 are left, the rest of the order is cancelled
+        let placed = client.market_order(&account, "FIGI", 4, OperationType::Buy).unwrap();
+        assert_eq!(placed.status, OrderStatus::PartiallyFill);
+        assert_eq!(placed.executed_lots, 2);
+        assert!(client.orders(&account).unwrap().orders.is_empty());
+
+        let balance = rub(&client, &account);
+        assert_eq!(balance.balance, dec("10000") - dec("301") - dec("202"));
+        assert_eq!(balance.blocked, Decimal::ZERO);
+
+        let error = client.market_order(&account, "FIGI", 1, OperationType::Buy).unwrap_err();
+        assert!(matches!(error, crate::rest_client::Error::Api { ref code, .. } if code == "NO_MARKET_PRICE"));
+
+        let to = Utc::now() + chrono::Duration::days(1);
+        let operations = client.operations(&account, to - chrono::Duration::days(2), to, "FIGI").unwrap().operations;
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[0].trades.len(), 2);
+        assert_eq!(operations[0].payment, dec("-301"));
+        assert_eq!(operations[1].quantity, 4);
+        assert_eq!(operations[1].quantity_executed, 2);
+    }
+
+    #[test]
+    fn limit_orders_rest_and_fill_from_later_books() {
+        let emulator = SandboxEmulator::new();
+        emulator.add_instrument(stock("FIGI", 1));
+        emulator.set_orderbook(book("FIGI", &[("99", "10")], &[("100", "2"), ("102", "5")]));
+        let client = emulator.client();
+        let account = client.sandbox_register().unwrap().id;
+        client.sandbox_set_currency_balance(&account, Currency::Rub, dec("10000")).unwrap();
+
+        let placed = client.limit_order(&account, "FIGI", 5, OperationType::Buy, dec("101")).unwrap();
+        assert_eq!(placed.status, OrderStatus::PartiallyFill);
+        assert_eq!(placed.executed_lots, 2);
+        let orders = client.orders(&account).unwrap().orders;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].executed_lots, 2);
+        assert_eq!(rub(&client, &account).blocked, dec("303"));
+
+        // only one lot is offered within the limit
+        emulator.set_orderbook(book("FIGI", &[("99", "10")], &[("101", "1"), ("102", "5")]));
+        assert_eq!(client.orders(&account).unwrap().orders[0].executed_lots, 3);
+        assert_eq!(rub(&client, &account).blocked, dec("202"));
+
+        emulator.set_orderbook(book("FIGI", &[("99", "10")], &[("100", "9")]));
+        assert!(client.orders(&account).unwrap().orders.is_empty());
+        let balance = rub(&client, &account);
+        assert_eq!(balance.balance, dec("10000") - dec("200") - dec("101") - dec("202"));
+        assert_eq!(balance.blocked, Decimal::ZERO);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use attohttpc::header::HeaderName;
+use attohttpc::Session;
+use serde::Serialize;
+use serde_json::json;
+use url::Url;
+
+use super::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// A fully prepared request, including the `Authorization` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    /// A 200 response carrying `payload` in the API's `{"trackingId", "status", "payload"}` envelope.
+    pub fn ok<T: Serialize>(payload: &T) -> Self {
+        let body = json!({"trackingId": "mock", "status": "Ok", "payload": payload});
+        Self::new(200, body.to_string())
+    }
+
+    /// An error response in the API's envelope, decoded by the client as `Error::Api`.
+    pub fn api_error(status: u16, code: &str, message: &str) -> Self {
+        let body = json!({
+            "trackingId": "mock",
+            "status": "Error",
+            "payload": {"code": code, "message": message},
+        });
+        Self::new(status, body.to_string())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Value of the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Sends requests for `RestClient`. Implement it to route requests through a
+/// different HTTP stack or to serve them without a network at all.
+///
+/// Only transport failures should be returned as errors; non-2xx responses are
+/// returned as they are and decoded by the client.
+pub trait Transport: Send + Sync {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse>;
+}
+
+/// The default transport, backed by an attohttpc `Session`.
+pub struct AttohttpcTransport {
+    session: Session,
+}
+
+impl AttohttpcTransport {
+    pub fn new(session: Session) -> Self {
+        Self { session }
+    }
+}
+
+impl Default for AttohttpcTransport {
+    fn default() -> Self {
+        Self::new(Session::new())
+    }
+}
+
+impl Transport for AttohttpcTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let mut builder = match request.method {
+            Method::Get => self.session.get(request.url.as_str()),
+            Method::Post => self.session.post(request.url.as_str()),
+        };
+        for (name, value) in &request.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| Error::Validation(e.to_string()))?;
+            builder = builder
+                .try_header_append(name, value.as_str())
+                .map_err(|e| Error::Validation(e.to_string()))?;
+        }
+        let response = builder.bytes(&request.body).send().map_err(Error::transport)?;
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().map_err(Error::transport)?;
+        Ok(HttpResponse { status, headers, body })
+    }
+}
+
+/// In-memory transport for tests. Serves canned responses by method and path
+/// and records every request it receives. Clones share the same state, so keep
+/// one clone to inspect what the client sent.
+///
+/// A path matches when the request path ends with it, e.g. `"market/stocks"`.
+/// Responses registered for the same route are served in order and the last
+/// one is repeated. Requests without a route get a 404 API error.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    routes: Vec<Route>,
+    requests: Vec<HttpRequest>,
+}
+
+struct Route {
+    method: Method,
+    path: String,
+    responses: VecDeque<HttpResponse>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn respond(&self, method: Method, path: &str, response: HttpResponse) {
+        let path = path.trim_matches('/').to_string();
+        let mut state = self.state.lock().unwrap();
+        match state.routes.iter_mut().find(|r| r.method == method && r.path == path) {
+            Some(route) => route.responses.push_back(response),
+            None => state.routes.push(Route {
+                method,
+                path,
+                responses: vec![response].into(),
+            }),
+        }
+    }
+
+    /// Shortcut for `respond` with `HttpResponse::ok(payload)`.
+    pub fn respond_ok<T: Serialize>(&self, method: Method, path: &str, payload: &T) {
+        self.respond(method, path, HttpResponse::ok(payload));
+    }
+
+    /// Requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request.clone());
+        let path = request.url.path().trim_end_matches('/');
+        let route = state.routes.iter_mut().find(|r| {
+            r.method == request.method && (path == r.path || path.ends_with(&format!("/{}", r.path)))
+        });
+        let response = match route {
+            Some(route) if route.responses.len() > 1 => route.responses.pop_front().unwrap(),
+            Some(route) => route.responses[0].clone(),
+            None => HttpResponse::api_error(
+                404,
+                "MockNotFound",
+                &format!("no mock response for {:?} {}", request.method, path),
+            ),
+        };
+        Ok(response)
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
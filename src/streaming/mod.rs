@@ -0,0 +1,295 @@
+use anyhow::{anyhow, Result};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tungstenite::client::{AutoStream, IntoClientRequest};
+use tungstenite::http::HeaderValue;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+use crate::*;
+
+const STREAMING_URL: &str = "wss://api-invest.tinkoff.ru/openapi/md/v1/md-openapi/ws";
+
+/// Decoded message received from the streaming API.
+#[derive(Debug)]
+pub enum StreamingEvent {
+    Candle(CandleEvent),
+    OrderBook(OrderBookEvent),
+    InstrumentInfo(InstrumentInfoEvent),
+    Error(ErrorEvent),
+}
+
+/// Blocking client for the OpenAPI market data websocket.
+///
+/// Every subscribe/unsubscribe call returns the `request_id` it was sent with,
+/// which is echoed back in the payload of an `ErrorEvent` if the server rejects it.
+pub struct StreamingClient {
+    socket: WebSocket<AutoStream>,
+    last_request_id: u64,
+    closed: bool,
+}
+
+impl StreamingClient {
+    pub fn new(token: String) -> Result<Self> {
+        Self::connect(Url::parse(STREAMING_URL)?, &token)
+    }
+
+    pub fn connect(url: Url, token: &str) -> Result<Self> {
+        let mut request = url.into_client_request()?;
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+        let (socket, _response) = tungstenite::connect(request)?;
+        Ok(Self {
+            socket,
+            last_request_id: 0,
+            closed: false,
+        })
+    }
+
+    pub fn subscribe_candles(&mut self, figi: &str, interval: CandleInterval) -> Result<String> {
+        self.send_candles("candle:subscribe", figi, interval)
+    }
+
+    pub fn unsubscribe_candles(&mut self, figi: &str, interval: CandleInterval) -> Result<String> {
+        self.send_candles("candle:unsubscribe", figi, interval)
+    }
+
+    pub fn subscribe_orderbook(&mut self, figi: &str, depth: i64) -> Result<String> {
+        self.send_orderbook("orderbook:subscribe", figi, depth)
+    }
+
+    pub fn unsubscribe_orderbook(&mut self, figi: &str, depth: i64) -> Result<String> {
+        self.send_orderbook("orderbook:unsubscribe", figi, depth)
+    }
+
+    pub fn subscribe_instrument_info(&mut self, figi: &str) -> Result<String> {
+        self.send_instrument_info("instrument_info:subscribe", figi)
+    }
+
+    pub fn unsubscribe_instrument_info(&mut self, figi: &str) -> Result<String> {
+        self.send_instrument_info("instrument_info:unsubscribe", figi)
+    }
+
+    /// Blocks until the next event arrives. Pings are answered and unknown
+    /// event names are skipped. A read error closes the client, so iteration
+    /// ends after yielding it.
+    pub fn next_event(&mut self) -> Result<StreamingEvent> {
+        loop {
+            if self.closed {
+                return Err(anyhow!("streaming connection is closed"));
+            }
+            let message = match self.socket.read_message() {
+                Ok(message) => message,
+                Err(error) => {
+                    // the socket cannot be read after an error, so end the stream
+                    self.closed = true;
+                    return Err(error.into());
+                }
+            };
+            match message {
+                Message::Text(text) => {
+                    if let Some(event) = decode_event(&text)? {
+                        return Ok(event);
+                    }
+                }
+                Message::Close(frame) => {
+                    self.closed = true;
+                    return Err(anyhow!("streaming connection closed by server: {:?}", frame));
+                }
+                message => debug!("skipping streaming message {:?}", message),
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if !self.closed {
+            self.closed = true;
+            self.socket.close(None)?;
+        }
+        Ok(())
+    }
+
+    fn send_candles(&mut self, event: &str, figi: &str, interval: CandleInterval) -> Result<String> {
+        let request_id = self.next_request_id();
+        self.send(json!({
+            "event": event,
+            "figi": figi,
+            "interval": interval.as_str(),
+            "request_id": request_id,
+        }))?;
+        Ok(request_id)
+    }
+
+    fn send_orderbook(&mut self, event: &str, figi: &str, depth: i64) -> Result<String> {
+        if depth < 1 || depth > MAX_ORDERBOOK_DEPTH {
+            return Err(anyhow!(
+                "orderbook depth must be between 1 and {}, got {}",
+                MAX_ORDERBOOK_DEPTH,
+                depth
+            ));
+        }
+        let request_id = self.next_request_id();
+        self.send(json!({
+            "event": event,
+            "figi": figi,
+            "depth": depth,
+            "request_id": request_id,
+        }))?;
+        Ok(request_id)
+    }
+
+    fn send_instrument_info(&mut self, event: &str, figi: &str) -> Result<String> {
+        let request_id = self.next_request_id();
+        self.send(json!({
+            "event": event,
+            "figi": figi,
+            "request_id": request_id,
+        }))?;
+        Ok(request_id)
+    }
+
+    fn send(&mut self, request: Value) -> Result<()> {
+        self.socket.write_message(Message::Text(request.to_string()))?;
+        Ok(())
+    }
+
+    fn next_request_id(&mut self) -> String {
+        self.last_request_id += 1;
+        self.last_request_id.to_string()
+    }
+}
+
+impl Iterator for StreamingClient {
+    type Item = Result<StreamingEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.closed {
+            return None;
+        }
+        Some(self.next_event())
+    }
+}
+
+impl Drop for StreamingClient {
+    fn drop(&mut self) {
+        if !self.closed {
+            let _ = self.socket.close(None);
+        }
+    }
+}
+
+fn decode_event(text: &str) -> Result<Option<StreamingEvent>> {
+    let value: Value = serde_json::from_str(text)?;
+    let event: Event = serde_json::from_value(value.clone())?;
+    let event = match event.name.as_str() {
+        "candle" => StreamingEvent::Candle(serde_json::from_value(value)?),
+        "orderbook" => StreamingEvent::OrderBook(serde_json::from_value(value)?),
+        "instrument_info" => StreamingEvent::InstrumentInfo(serde_json::from_value(value)?),
+        "error" => StreamingEvent::Error(serde_json::from_value(value)?),
+        name => {
+            warn!("skipping unknown streaming event {}", name);
+            return Ok(None);
+        }
+    };
+    Ok(Some(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn decodes_candle() {
+        let text = r#"{"event": "candle", "time": "2021-03-05T10:00:01Z", "payload": {"figi": "BBG000B9XRY4",
+            "interval": "1min", "o": 120.5, "c": 121.25, "h": 121.5, "l": 120.0, "v": 1500,
+            "time": "2021-03-05T10:00:00Z"}}"#;
+        let candle = match decode_event(text).unwrap() {
+            Some(StreamingEvent::Candle(event)) => event.candle,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(candle.figi, "BBG000B9XRY4");
+        assert_eq!(candle.interval, CandleInterval::Min1);
+        assert_eq!(candle.open_price, dec("120.5"));
+        assert_eq!(candle.close_price, dec("121.25"));
+        assert_eq!(candle.volume, 1500.0);
+        assert_eq!(candle.ts.to_rfc3339(), "2021-03-05T10:00:00+00:00");
+    }
+
+    #[test]
+    fn decodes_orderbook_levels_from_pairs() {
+        let text = r#"{"event": "orderbook", "time": "2021-03-05T10:00:01Z", "payload": {"figi": "BBG000B9XRY4",
+            "depth": 2, "bids": [[120.5, 10], [120.25, 3]], "asks": [[121, 7]]}}"#;
+        let book = match decode_event(text).unwrap() {
+            Some(StreamingEvent::OrderBook(event)) => event.order_book,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(book.depth, 2);
+        assert_eq!(book.bids.len(), 2);
+        assert_eq!(book.bids[1].price, dec("120.25"));
+        assert_eq!(book.bids[1].quantity, 3.0);
+        assert_eq!(book.asks[0].price, dec("121"));
+        assert_eq!(book.asks[0].quantity, 7.0);
+    }
+
+    #[test]
+    fn decodes_instrument_info() {
+        let text = r#"{"event": "instrument_info", "time": "2021-03-05T10:00:01Z", "payload": {"figi": "BBG000B9XRY4",
+            "trade_status": "normal_trading", "min_price_increment": 0.01, "lot": 1}}"#;
+        let info = match decode_event(text).unwrap() {
+            Some(StreamingEvent::InstrumentInfo(event)) => event.info,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(info.trade_status, TradingStatus::NormalTrading);
+        assert_eq!(info.min_price_increment, dec("0.01"));
+        assert_eq!(info.lot, 1.0);
+        assert_eq!(info.limit_up, Decimal::ZERO);
+    }
+
+    #[test]
+    fn decodes_error() {
+        let text = r#"{"event": "error", "time": "2021-03-05T10:00:01Z",
+            "payload": {"request_id": "3", "error": "Subscription instrument_info:subscribe. FIGI NOPE not found"}}"#;
+        let error = match decode_event(text).unwrap() {
+            Some(StreamingEvent::Error(event)) => event.error,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(error.request_id, "3");
+        assert!(error.error.contains("NOPE"));
+    }
+
+    #[test]
+    fn skips_unknown_events_and_rejects_malformed_json() {
+        let text = r#"{"event": "news", "time": "2021-03-05T10:00:01Z", "payload": {}}"#;
+        assert!(decode_event(text).unwrap().is_none());
+        assert!(decode_event("{\"event\": ").is_err());
+        assert!(decode_event(r#"{"event": "candle", "time": "2021-03-05T10:00:01Z", "payload": {}}"#).is_err());
+    }
+
+    #[test]
+    fn iteration_ends_after_a_dropped_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            socket.write_message(Message::Text(r#"{"event": "news", "payload": {}}"#.to_string())).unwrap();
+            // dropped without a close frame
+        });
+
+        let url = Url::parse(&format!("ws://{}", address)).unwrap();
+        let mut client = StreamingClient::connect(url, "token").unwrap();
+        server.join().unwrap();
+        assert!(matches!(client.next(), Some(Err(_))));
+        assert!(client.next().is_none());
+        assert!(client.next_event().is_err());
+    }
+}
@@ -0,0 +1,466 @@
+// Data for the Russian personal income tax (NDFL) return, in rubles.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, Write};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::pnl::{CostMethod, PnlCalculator};
+use crate::resample::MOSCOW_OFFSET_HOURS;
+use crate::*;
+
+/// Ruble exchange rates, such as the Central Bank's official ones.
+pub trait RateSource {
+    /// Rubles per unit of `currency` on `date`.
+    fn rate(&self, currency: &Currency, date: NaiveDate) -> Option<Decimal>;
+}
+
+/// Rates kept in memory. A date without a rate uses the latest earlier one,
+/// since the Central Bank publishes no rates for weekends and holidays.
+#[derive(Debug, Clone, Default)]
+pub struct RateTable {
+    rates: HashMap<Currency, BTreeMap<NaiveDate, Decimal>>,
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, currency: Currency, date: NaiveDate, rate: Decimal) {
+        self.rates.entry(currency).or_default().insert(date, rate);
+    }
+}
+
+impl RateSource for RateTable {
+    fn rate(&self, currency: &Currency, date: NaiveDate) -> Option<Decimal> {
+        if *currency == Currency::Rub {
+            return Some(Decimal::ONE);
+        }
+        self.rates.get(currency)?.range(..=date).next_back().map(|(_, rate)| *rate)
+    }
+}
+
+/// No rate is known for a currency on a date the report needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingRate {
+    pub currency: Currency,
+    pub date: NaiveDate,
+}
+
+impl fmt::Display for MissingRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no {} rate for {}", self.currency, self.date)
+    }
+}
+
+impl StdError for MissingRate {}
+
+/// A sale (or the purchase closing a short sale) of securities bought in one lot.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Disposal {
+    pub figi: String,
+    pub currency: Currency,
+    pub quantity: i64,
+    pub opened: NaiveDate,
+    pub closed: NaiveDate,
+    pub proceeds_rub: Decimal,
+    pub expenses_rub: Decimal,
+    pub gain_rub: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SecurityGain {
+    pub figi: String,
+    pub currency: Currency,
+    pub proceeds_rub: Decimal,
+    pub expenses_rub: Decimal,
+    pub gain_rub: Decimal,
+    pub disposals: Vec<Disposal>,
+}
+
+/// A dividend or coupon, or a tax operation: `TaxDividend`, `TaxCoupon`,
+/// `TaxLucre`, `Tax` or `TaxBack`. Taxes withheld are negative, refunds positive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CashEntry {
+    pub date: NaiveDate,
+    pub figi: String,
+    pub operation_type: OperationType,
+    pub currency: Currency,
+    pub amount: Decimal,
+    pub rate: Decimal,
+    pub amount_rub: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaxTotals {
+    pub gain_rub: Decimal,
+    pub income_rub: Decimal,
+    /// Taxes withheld by the broker net of refunds, as a positive amount.
+    pub withheld_rub: Decimal,
+    /// Money paid into an individual investment account (IIS) during the year.
+    pub contributions_rub: Decimal,
+}
+
+/// Tax data of one account for one calendar year.
+///
+/// Gains are matched FIFO over the whole operation history. Each side of a
+/// trade is converted at the rate of its own Moscow date, and trade
+/// commissions are added to expenses at the same rate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaxReport {
+    pub year: i32,
+    pub account_type: AccountType,
+    pub securities: Vec<SecurityGain>,
+    /// Dividends and coupons received.
+    pub income: Vec<CashEntry>,
+    pub taxes: Vec<CashEntry>,
+    /// `PayIn` operations, only listed for `TinkoffIis` accounts.
+    pub contributions: Vec<CashEntry>,
+    pub totals: TaxTotals,
+}
+
+impl TaxReport {
+    /// Builds the report for `year`. `operations` should go back to the
+    /// purchase of everything sold during the year, so gains can be matched.
+    pub fn build(
+        account_type: AccountType,
+        year: i32,
+        operations: &[Operation],
+        rates: &dyn RateSource,
+    ) -> Result<TaxReport, MissingRate> {
+        let convert = |currency: &Currency, time: DateTime<Utc>, amount: Decimal| {
+            let date = moscow_date(time);
+            rates
+                .rate(currency, date)
+                .map(|rate| (rate, amount * rate))
+                .ok_or_else(|| MissingRate {
+                    currency: currency.clone(),
+                    date,
+                })
+        };
+
+        let pnl = PnlCalculator::new(CostMethod::Fifo)
+            .trade_commission(true)
+            .replay(operations);
+        let mut securities = vec![];
+        for position in pnl.positions.values() {
+            let mut disposals = vec![];
+            for closed in position.closed_lots.iter().filter(|c| !c.transfer) {
+                if moscow_date(closed.closed).year() != year {
+                    continue;
+                }
+                let quantity = Decimal::from(closed.quantity.abs());
+                let (_, open_rub) = convert(&position.currency, closed.opened, closed.open_price * quantity)?;
+                let (_, close_rub) = convert(&position.currency, closed.closed, closed.close_price * quantity)?;
+                let (proceeds_rub, expenses_rub) = if closed.quantity > 0 {
+                    (close_rub, open_rub)
+                } else {
+                    (open_rub, close_rub)
+                };
+                disposals.push(Disposal {
+                    figi: position.figi.clone(),
+                    currency: position.currency.clone(),
+                    quantity: closed.quantity,
+                    opened: moscow_date(closed.opened),
+                    closed: moscow_date(closed.closed),
+                    proceeds_rub,
+                    expenses_rub,
+                    gain_rub: proceeds_rub - expenses_rub,
+                });
+            }
+            if disposals.is_empty() {
+                continue;
+            }
+            securities.push(SecurityGain {
+                figi: position.figi.clone(),
+                currency: position.currency.clone(),
+                proceeds_rub: disposals.iter().map(|d| d.proceeds_rub).sum(),
+                expenses_rub: disposals.iter().map(|d| d.expenses_rub).sum(),
+                gain_rub: disposals.iter().map(|d| d.gain_rub).sum(),
+                disposals,
+            });
+        }
+
+        let mut income = vec![];
+        let mut taxes = vec![];
+        let mut contributions = vec![];
+        let mut in_year: Vec<&Operation> = operations
+            .iter()
+            .filter(|o| o.status == OperationStatus::Done && moscow_date(o.date_time).year() == year)
+            .collect();
+        in_year.sort_by_key(|o| o.date_time);
+        for operation in in_year {
+            let entries = match operation.operation_type {
+                OperationType::Dividend | OperationType::Coupon => &mut income,
+                OperationType::TaxDividend
+                | OperationType::TaxCoupon
+                | OperationType::TaxLucre
+                | OperationType::Tax
+                | OperationType::TaxBack => &mut taxes,
+                OperationType::PayIn if account_type == AccountType::TinkoffIis => &mut contributions,
+                _ => continue,
+            };
+            let (rate, amount_rub) = convert(&operation.currency, operation.date_time, operation.payment)?;
+            entries.push(CashEntry {
+                date: moscow_date(operation.date_time),
+                figi: operation.figi.clone(),
+                operation_type: operation.operation_type.clone(),
+                currency: operation.currency.clone(),
+                amount: operation.payment,
+                rate,
+                amount_rub,
+            });
+        }
+
+        let totals = TaxTotals {
+            gain_rub: securities.iter().map(|s| s.gain_rub).sum(),
+            income_rub: income.iter().map(|e| e.amount_rub).sum(),
+            withheld_rub: -taxes.iter().map(|e| e.amount_rub).sum::<Decimal>(),
+            contributions_rub: contributions.iter().map(|e| e.amount_rub).sum(),
+        };
+        Ok(TaxReport {
+            year,
+            account_type,
+            securities,
+            income,
+            taxes,
+            contributions,
+            totals,
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Writes one row per disposal, income, tax and contribution entry, with
+    /// columns that do not apply to a row left empty.
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "section,date,opened,figi,type,currency,quantity,amount,rate,amount_rub,expenses_rub,gain_rub"
+        )?;
+        for disposal in self.securities.iter().flat_map(|s| &s.disposals) {
+            writeln!(
+                writer,
+                "disposal,{},{},{},{},{},{},,,{},{},{}",
+                disposal.closed,
+                disposal.opened,
+                disposal.figi,
+                // a short is disposed of by the purchase that closes it
+                if disposal.quantity > 0 { "Sell" } else { "Buy" },
+                disposal.currency,
+                disposal.quantity,
+                disposal.proceeds_rub,
+                disposal.expenses_rub,
+                disposal.gain_rub
+            )?;
+        }
+        let sections = [
+            ("income", &self.income),
+            ("tax", &self.taxes),
+            ("contribution", &self.contributions),
+        ];
+        for (section, entries) in sections.iter() {
+            for entry in entries.iter() {
+                writeln!(
+                    writer,
+                    "{},{},,{},{},{},,{},{},{},,",
+                    section,
+                    entry.date,
+                    entry.figi,
+                    entry.operation_type,
+                    entry.currency,
+                    entry.amount,
+                    entry.rate,
+                    entry.amount_rub
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = vec![];
+        self.write_csv(&mut csv).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+}
+
+fn moscow_date(time: DateTime<Utc>) -> NaiveDate {
+    (time + Duration::hours(MOSCOW_OFFSET_HOURS)).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn trade(time: &str, operation_type: OperationType, quantity: i64, price: &str, commission: &str) -> Operation {
+        let date_time: DateTime<Utc> = time.parse().unwrap();
+        let price = dec(price);
+        let sign = if operation_type == OperationType::Buy { -1 } else { 1 };
+        Operation {
+            id: time.to_string(),
+            status: OperationStatus::Done,
+            trades: vec![Trade {
+                id: time.to_string(),
+                date_time,
+                price,
+                quantity,
+            }],
+            commission: Some(MoneyAmount {
+                currency: Currency::Usd,
+                value: -dec(commission),
+            }),
+            currency: Currency::Usd,
+            payment: price * Decimal::from(quantity * sign),
+            price,
+            quantity,
+            quantity_executed: quantity,
+            figi: "AAPL".to_string(),
+            instrument_type: Some(InstrumentType::Stock),
+            is_margin_call: false,
+            date_time,
+            operation_type,
+        }
+    }
+
+    fn cash(time: &str, operation_type: OperationType, currency: Currency, amount: &str) -> Operation {
+        Operation {
+            trades: vec![],
+            commission: None,
+            currency,
+            payment: dec(amount),
+            quantity: 0,
+            quantity_executed: 0,
+            ..trade(time, operation_type, 0, "0", "0")
+        }
+    }
+
+    fn rates() -> RateTable {
+        let mut rates = RateTable::new();
+        rates.insert(Currency::Usd, date("2021-03-01"), dec("70"));
+        rates.insert(Currency::Usd, date("2021-06-01"), dec("80"));
+        rates.insert(Currency::Usd, date("2022-01-01"), dec("90"));
+        rates
+    }
+
+    #[test]
+    fn converts_each_side_at_its_own_rate() {
+        let operations = [
+            trade("2021-03-01T10:00:00Z", OperationType::Buy, 10, "10", "1"),
+            // a weekend date uses the latest earlier rate
+            trade("2021-06-05T10:00:00Z", OperationType::Sell, 10, "12", "1"),
+        ];
+        let report = TaxReport::build(AccountType::Tinkoff, 2021, &operations, &rates()).unwrap();
+        let disposal = &report.securities[0].disposals[0];
+        // bought for 101 USD at 70, sold for 119 USD at 80
+        assert_eq!(disposal.expenses_rub, dec("7070"));
+        assert_eq!(disposal.proceeds_rub, dec("9520"));
+        assert_eq!(disposal.gain_rub, dec("2450"));
+        assert_eq!((disposal.opened, disposal.closed), (date("2021-03-01"), date("2021-06-05")));
+        assert_eq!(report.totals.gain_rub, dec("2450"));
+    }
+
+    #[test]
+    fn short_disposals_swap_proceeds_and_expenses() {
+        let operations = [
+            trade("2021-03-01T10:00:00Z", OperationType::Sell, 10, "12", "0"),
+            trade("2021-06-01T10:00:00Z", OperationType::Buy, 10, "10", "0"),
+        ];
+        let report = TaxReport::build(AccountType::Tinkoff, 2021, &operations, &rates()).unwrap();
+        let disposal = &report.securities[0].disposals[0];
+        assert_eq!(disposal.quantity, -10);
+        assert_eq!(disposal.proceeds_rub, dec("8400"));
+        assert_eq!(disposal.expenses_rub, dec("8000"));
+        assert_eq!(disposal.gain_rub, dec("400"));
+        assert!(report.to_csv().contains("disposal,2021-06-01,2021-03-01,AAPL,Buy,USD,-10,,,8400,8000,400"));
+    }
+
+    #[test]
+    fn years_follow_moscow_dates() {
+        let operations = [
+            trade("2021-03-01T10:00:00Z", OperationType::Buy, 10, "10", "0"),
+            // 22:00 UTC on December 31 is already January 1 in Moscow
+            trade("2021-12-31T22:00:00Z", OperationType::Sell, 10, "12", "0"),
+            Operation {
+                figi: "SBER".to_string(),
+                ..cash("2020-12-31T21:30:00Z", OperationType::Dividend, Currency::Rub, "100")
+            },
+            Operation {
+                figi: "SBER".to_string(),
+                ..cash("2021-12-31T20:59:00Z", OperationType::Dividend, Currency::Rub, "200")
+            },
+        ];
+        let report = TaxReport::build(AccountType::Tinkoff, 2021, &operations, &rates()).unwrap();
+        assert!(report.securities.is_empty());
+        assert_eq!(report.income.len(), 2);
+        assert_eq!(report.totals.income_rub, dec("300"));
+
+        let next = TaxReport::build(AccountType::Tinkoff, 2022, &operations, &rates()).unwrap();
+        assert_eq!(next.securities[0].disposals[0].closed, date("2022-01-01"));
+        assert_eq!(next.securities[0].proceeds_rub, dec("10800"));
+        assert!(next.income.is_empty());
+    }
+
+    #[test]
+    fn taxes_contributions_and_missing_rates() {
+        let operations = [
+            cash("2021-03-01T10:00:00Z", OperationType::Dividend, Currency::Usd, "10"),
+            cash("2021-03-01T10:00:00Z", OperationType::TaxDividend, Currency::Usd, "-1"),
+            cash("2021-04-01T10:00:00Z", OperationType::TaxBack, Currency::Rub, "20"),
+            cash("2021-05-01T10:00:00Z", OperationType::PayIn, Currency::Rub, "1000"),
+        ];
+        let iis = TaxReport::build(AccountType::TinkoffIis, 2021, &operations, &rates()).unwrap();
+        assert_eq!(iis.totals.income_rub, dec("700"));
+        assert_eq!(iis.totals.withheld_rub, dec("50"));
+        assert_eq!(iis.totals.contributions_rub, dec("1000"));
+        let broker = TaxReport::build(AccountType::Tinkoff, 2021, &operations, &rates()).unwrap();
+        assert!(broker.contributions.is_empty());
+
+        let early = [cash("2021-01-10T10:00:00Z", OperationType::Coupon, Currency::Eur, "5")];
+        let error = TaxReport::build(AccountType::Tinkoff, 2021, &early, &rates()).unwrap_err();
+        assert_eq!(
+            error,
+            MissingRate {
+                currency: Currency::Eur,
+                date: date("2021-01-10"),
+            }
+        );
+    }
+
+    #[test]
+    fn csv_and_json_output() {
+        let operations = [
+            trade("2021-03-01T10:00:00Z", OperationType::Buy, 10, "10", "0"),
+            trade("2021-06-01T10:00:00Z", OperationType::Sell, 10, "12", "0"),
+            cash("2021-06-01T10:00:00Z", OperationType::Dividend, Currency::Usd, "2"),
+        ];
+        let report = TaxReport::build(AccountType::Tinkoff, 2021, &operations, &rates()).unwrap();
+        let expected = "\
+section,date,opened,figi,type,currency,quantity,amount,rate,amount_rub,expenses_rub,gain_rub
+disposal,2021-06-01,2021-03-01,AAPL,Sell,USD,10,,,9600,7000,2600
+income,2021-06-01,,AAPL,Dividend,USD,,2,80,160,,
+";
+        assert_eq!(report.to_csv(), expected);
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["year"], 2021);
+        assert_eq!(json["account_type"], "Tinkoff");
+        assert_eq!(json["securities"][0]["disposals"][0]["opened"], "2021-03-01");
+        assert_eq!(json["totals"]["gain_rub"].to_string(), "2600");
+        assert_eq!(json["income"][0]["operation_type"], "Dividend");
+    }
+}
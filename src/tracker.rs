@@ -0,0 +1,427 @@
+// Follows placed orders until they are filled, cancelled or rejected.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver};
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::rest_client::Result;
+use crate::*;
+
+/// A change in the state of a tracked order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderUpdate {
+    pub order_id: String,
+    pub figi: String,
+    pub operation: OperationType,
+    pub previous_status: Option<OrderStatus>,
+    pub status: OrderStatus,
+    pub requested_lots: i64,
+    pub executed_lots: i64,
+    /// Average price of the trades booked for the order so far.
+    pub average_price: Option<Decimal>,
+    /// Reject reason or broker message, if any.
+    pub message: String,
+}
+
+/// What the tracker knows about an order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedOrder {
+    pub order_id: String,
+    pub figi: String,
+    pub operation: OperationType,
+    pub status: OrderStatus,
+    pub requested_lots: i64,
+    pub executed_lots: i64,
+    pub average_price: Option<Decimal>,
+    pub message: String,
+    // polls in a row the order was neither active nor booked as fully executed
+    missing_polls: u32,
+    // cancelled because it went missing, but still checked for late trades
+    unconfirmed: bool,
+}
+
+impl TrackedOrder {
+    /// Filled, cancelled and rejected orders are no longer polled, except
+    /// orders presumed cancelled that are still in their grace period.
+    pub fn is_final(&self) -> bool {
+        match self.status {
+            OrderStatus::Fill | OrderStatus::Rejected => true,
+            OrderStatus::Cancelled => !self.unconfirmed,
+            _ => false,
+        }
+    }
+}
+
+type Listener = Box<dyn FnMut(&OrderUpdate)>;
+
+/// Tracks orders of one account by polling `orders` and `operations`.
+///
+/// Trades are matched to orders through the operation id, which the broker sets
+/// to the id of the order that produced it. An order that disappears from the
+/// active orders is reported as filled once its operations cover the requested
+/// lots. If they never do, it is reported as cancelled after it has been
+/// missing for `missing_polls` polls in a row, which gives the broker time to
+/// book the last trades. Trades booked even later are still picked up for
+/// `grace_polls` more polls and move the order from cancelled to filled.
+pub struct OrderTracker {
+    account_id: String,
+    since: DateTime<Utc>,
+    missing_polls: u32,
+    grace_polls: u32,
+    orders: HashMap<String, TrackedOrder>,
+    lots: HashMap<String, i64>,
+    listeners: Vec<Listener>,
+}
+
+impl OrderTracker {
+    pub fn new(account_id: &str) -> Self {
+        Self {
+            account_id: account_id.to_string(),
+            since: Utc::now() - Duration::days(1),
+            missing_polls: 2,
+            grace_polls: 10,
+            orders: HashMap::new(),
+            lots: HashMap::new(),
+            listeners: vec![],
+        }
+    }
+
+    /// Start of the operations window requested on each poll. Defaults to one
+    /// day before the tracker was created.
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = since;
+        self
+    }
+
+    pub fn missing_polls(mut self, missing_polls: u32) -> Self {
+        self.missing_polls = missing_polls.max(1);
+        self
+    }
+
+    /// Polls an order presumed cancelled is still checked for late trades.
+    /// Defaults to 10.
+    pub fn grace_polls(mut self, grace_polls: u32) -> Self {
+        self.grace_polls = grace_polls;
+        self
+    }
+
+    /// Calls `listener` with every update.
+    pub fn on_update<F: FnMut(&OrderUpdate) + 'static>(mut self, listener: F) -> Self {
+        self.listeners.push(Box::new(listener));
+        self
+    }
+
+    /// Returns a receiver that gets every update from now on.
+    pub fn subscribe(&mut self) -> Receiver<OrderUpdate> {
+        let (sender, receiver) = channel();
+        self.listeners.push(Box::new(move |update| {
+            let _ = sender.send(update.clone());
+        }));
+        receiver
+    }
+
+    /// Starts tracking an order returned by `limit_order` or `market_order`.
+    pub fn track(&mut self, figi: &str, placed: &PlacedOrder) -> OrderUpdate {
+        let order = TrackedOrder {
+            order_id: placed.id.clone(),
+            figi: figi.to_string(),
+            operation: placed.operation.clone(),
+            status: placed.status.clone(),
+            requested_lots: placed.requested_lots,
+            executed_lots: placed.executed_lots,
+            average_price: None,
+            message: if placed.reject_reason.is_empty() {
+                placed.message.clone()
+            } else {
+                placed.reject_reason.clone()
+            },
+            missing_polls: 0,
+            unconfirmed: false,
+        };
+        let update = update(None, &order);
+        self.orders.insert(order.order_id.clone(), order);
+        self.emit(&update);
+        update
+    }
+
+    pub fn order(&self, order_id: &str) -> Option<&TrackedOrder> {
+        self.orders.get(order_id)
+    }
+
+    pub fn orders(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.orders.values()
+    }
+
+    /// Orders that are not final yet.
+    pub fn pending(&self) -> impl Iterator<Item = &TrackedOrder> {
+        self.orders.values().filter(|o| !o.is_final())
+    }
+
+    /// Stops tracking final orders.
+    pub fn forget_final(&mut self) {
+        self.orders.retain(|_, o| !o.is_final());
+    }
+
+    /// Polls the broker once and returns the updates it produced. Filled orders
+    /// stay watched until their trades are booked, to pick up the average price.
+    pub fn poll<B: BrokerApi + ?Sized>(&mut self, broker: &B) -> Result<Vec<OrderUpdate>> {
+        let watched: Vec<String> = self
+            .orders
+            .values()
+            .filter(|o| !o.is_final() || (o.status == OrderStatus::Fill && o.average_price.is_none()))
+            .map(|o| o.order_id.clone())
+            .collect();
+        if watched.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let active: HashMap<String, Order> = broker
+            .orders(&self.account_id)?
+            .orders
+            .into_iter()
+            .map(|o| (o.id.clone(), o))
+            .collect();
+        let operations = broker.operations(&self.account_id, self.since, Utc::now() + Duration::days(1), "")?;
+        let mut executions: HashMap<&str, Execution> = HashMap::new();
+        for operation in &operations.operations {
+            if operation.operation_type == OperationType::Buy || operation.operation_type == OperationType::Sell {
+                executions.entry(operation.id.as_str()).or_default().add(operation);
+            }
+        }
+
+        let mut updates = vec![];
+        for order_id in watched {
+            let lot = self.lot(broker, &order_id)?;
+            let order = self.orders.get_mut(&order_id).unwrap();
+            let previous = (order.status.clone(), order.executed_lots, order.average_price);
+            let execution = executions.get(order_id.as_str());
+            if let Some(execution) = execution {
+                order.average_price = execution.average_price();
+                order.executed_lots = order.executed_lots.max(execution.quantity / lot);
+                if execution.declined {
+                    order.status = OrderStatus::Rejected;
+                }
+            }
+            match active.get(&order_id) {
+                _ if order.status == OrderStatus::Rejected => {}
+                Some(active) => {
+                    order.missing_polls = 0;
+                    order.status = active.status.clone();
+                    order.executed_lots = order.executed_lots.max(active.executed_lots);
+                }
+                None if order.executed_lots >= order.requested_lots => {
+                    order.status = OrderStatus::Fill;
+                    order.unconfirmed = false;
+                }
+                None => {
+                    order.missing_polls += 1;
+                    if order.missing_polls >= self.missing_polls {
+                        order.status = OrderStatus::Cancelled;
+                        order.unconfirmed = order.missing_polls < self.missing_polls + self.grace_polls;
+                    }
+                }
+            }
+            if (order.status.clone(), order.executed_lots, order.average_price) != previous {
+                updates.push(update(Some(previous.0), order));
+            }
+        }
+        for update in &updates {
+            self.emit(update);
+        }
+        Ok(updates)
+    }
+
+    // Lot size of the order's instrument, needed to turn traded pieces into lots.
+    fn lot<B: BrokerApi + ?Sized>(&mut self, broker: &B, order_id: &str) -> Result<i64> {
+        let figi = &self.orders[order_id].figi;
+        if let Some(lot) = self.lots.get(figi) {
+            return Ok(*lot);
+        }
+        let lot = broker.instrument_by_figi(figi)?.lot.max(1);
+        self.lots.insert(figi.clone(), lot);
+        Ok(lot)
+    }
+
+    fn emit(&mut self, update: &OrderUpdate) {
+        for listener in &mut self.listeners {
+            listener(update);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Execution {
+    quantity: i64,
+    cost: Decimal,
+    declined: bool,
+}
+
+impl Execution {
+    fn add(&mut self, operation: &Operation) {
+        if operation.status == OperationStatus::Decline {
+            self.declined = true;
+        }
+        for trade in &operation.trades {
+            self.quantity += trade.quantity;
+            self.cost += trade.price * Decimal::from(trade.quantity);
+        }
+    }
+
+    fn average_price(&self) -> Option<Decimal> {
+        if self.quantity == 0 {
+            return None;
+        }
+        Some(self.cost / Decimal::from(self.quantity))
+    }
+}
+
+fn update(previous_status: Option<OrderStatus>, order: &TrackedOrder) -> OrderUpdate {
+    OrderUpdate {
+        order_id: order.order_id.clone(),
+        figi: order.figi.clone(),
+        operation: order.operation.clone(),
+        previous_status,
+        status: order.status.clone(),
+        requested_lots: order.requested_lots,
+        executed_lots: order.executed_lots,
+        average_price: order.average_price,
+        message: order.message.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::rest_client::{Method, MockTransport, RestClient, RetryPolicy};
+
+    // A broker whose active orders and operations are fixed for one poll.
+    fn broker(orders: Value, operations: Value) -> (RestClient, MockTransport) {
+        let transport = MockTransport::new();
+        transport.respond_ok(Method::Get, "orders", &orders);
+        transport.respond_ok(Method::Get, "operations", &json!({ "operations": operations }));
+        transport.respond_ok(
+            Method::Get,
+            "market/search/by-figi",
+            &json!({
+                "figi": "FIGI",
+                "ticker": "TICK",
+                "name": "Stock",
+                "lot": 10,
+                "currency": "RUB",
+                "type": "Stock",
+            }),
+        );
+        let client = RestClient::builder("token".to_string())
+            .transport(transport.clone())
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap();
+        (client, transport)
+    }
+
+    fn buy(id: &str, trades: &[(&str, i64)]) -> Value {
+        let trades: Vec<Value> = trades
+            .iter()
+            .map(|(price, quantity)| {
+                let price: f64 = price.parse().unwrap();
+                json!({"tradeId": "t", "date": "2021-03-01T10:00:00Z", "price": price, "quantity": quantity})
+            })
+            .collect();
+        json!({
+            "id": id,
+            "status": "Done",
+            "trades": trades,
+            "currency": "RUB",
+            "payment": 0,
+            "figi": "FIGI",
+            "isMarginCall": false,
+            "date": "2021-03-01T10:00:00Z",
+            "operationType": "Buy",
+        })
+    }
+
+    fn tracker() -> OrderTracker {
+        let mut tracker = OrderTracker::new("acc");
+        tracker.track(
+            "FIGI",
+            &PlacedOrder {
+                id: "1".to_string(),
+                operation: OperationType::Buy,
+                status: OrderStatus::New,
+                reject_reason: String::new(),
+                requested_lots: 2,
+                executed_lots: 0,
+                commission: None,
+                message: String::new(),
+            },
+        );
+        tracker
+    }
+
+    #[test]
+    fn fills_from_active_orders_and_trades() {
+        let mut tracker = tracker();
+        let active = json!([{
+            "orderId": "1",
+            "figi": "FIGI",
+            "operation": "Buy",
+            "status": "PartiallyFill",
+            "requestedLots": 2,
+            "executedLots": 1,
+            "type": "Limit",
+            "price": 100,
+        }]);
+        let (client, _) = broker(active, json!([buy("1", &[("100", 10)])]));
+        let updates = tracker.poll(&client).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].status, OrderStatus::PartiallyFill);
+        assert_eq!(updates[0].executed_lots, 1);
+
+        let (client, _) = broker(json!([]), json!([buy("1", &[("100", 10), ("101", 10)])]));
+        let updates = tracker.poll(&client).unwrap();
+        assert_eq!(updates[0].previous_status, Some(OrderStatus::PartiallyFill));
+        assert_eq!(updates[0].status, OrderStatus::Fill);
+        assert_eq!(updates[0].average_price, Some("100.5".parse().unwrap()));
+        assert!(tracker.order("1").unwrap().is_final());
+    }
+
+    #[test]
+    fn late_trades_turn_a_presumed_cancel_into_a_fill() {
+        let mut tracker = tracker();
+        let (client, _) = broker(json!([]), json!([]));
+        assert!(tracker.poll(&client).unwrap().is_empty());
+        let updates = tracker.poll(&client).unwrap();
+        assert_eq!(updates[0].status, OrderStatus::Cancelled);
+        assert!(!tracker.order("1").unwrap().is_final());
+        assert_eq!(tracker.pending().count(), 1);
+
+        let (client, _) = broker(json!([]), json!([buy("1", &[("100", 20)])]));
+        let updates = tracker.poll(&client).unwrap();
+        assert_eq!(updates[0].previous_status, Some(OrderStatus::Cancelled));
+        assert_eq!(updates[0].status, OrderStatus::Fill);
+        assert_eq!(updates[0].executed_lots, 2);
+        assert!(tracker.order("1").unwrap().is_final());
+    }
+
+    #[test]
+    fn cancel_becomes_final_after_the_grace_period() {
+        let mut tracker = tracker().missing_polls(1).grace_polls(2);
+        let (client, transport) = broker(json!([]), json!([]));
+        assert_eq!(tracker.poll(&client).unwrap()[0].status, OrderStatus::Cancelled);
+        for _ in 0..2 {
+            assert!(!tracker.order("1").unwrap().is_final());
+            assert!(tracker.poll(&client).unwrap().is_empty());
+        }
+        assert!(tracker.order("1").unwrap().is_final());
+
+        // nothing is watched any more, so the next poll sends no requests
+        transport.clear_requests();
+        assert!(tracker.poll(&client).unwrap().is_empty());
+        assert!(transport.requests().is_empty());
+        tracker.forget_final();
+        assert!(tracker.order("1").is_none());
+    }
+}
@@ -0,0 +1,363 @@
+// Local checks for orders that the broker would otherwise reject after a round trip.
+
+use std::fmt;
+
+use crate::*;
+
+/// A reason an order would be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    NonPositiveLots(i64),
+    /// Only `Buy` and `Sell` orders can be placed.
+    UnsupportedOperation(OperationType),
+    NonPositivePrice(Decimal),
+    /// The price is not a multiple of the instrument's minimum price increment.
+    PriceNotOnTick { price: Decimal, increment: Decimal },
+    AboveLimitUp { price: Decimal, limit_up: Decimal },
+    BelowLimitDown { price: Decimal, limit_down: Decimal },
+    /// The instrument does not accept this kind of order in its current trading status.
+    TradingUnavailable(TradingStatus),
+    InsufficientFunds {
+        currency: Currency,
+        required: Decimal,
+        available: Decimal,
+    },
+    /// Not enough securities to sell; quantities are in pieces, not lots.
+    InsufficientPosition { required: Decimal, available: Decimal },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::NonPositiveLots(lots) => write!(f, "lots must be positive, got {}", lots),
+            Violation::UnsupportedOperation(operation) => write!(f, "unsupported order operation {}", operation),
+            Violation::NonPositivePrice(price) => write!(f, "price must be positive, got {}", price),
+            Violation::PriceNotOnTick { price, increment } => {
+                write!(f, "price {} is not a multiple of the price increment {}", price, increment)
+            }
+            Violation::AboveLimitUp { price, limit_up } => write!(f, "price {} is above the upper limit {}", price, limit_up),
+            Violation::BelowLimitDown { price, limit_down } => {
+                write!(f, "price {} is below the lower limit {}", price, limit_down)
+            }
+            Violation::TradingUnavailable(status) => write!(f, "order not accepted in trading status {}", status),
+            Violation::InsufficientFunds {
+                currency,
+                required,
+                available,
+            } => write!(f, "order needs {} {}, only {} available", required, currency, available),
+            Violation::InsufficientPosition { required, available } => {
+                write!(f, "order sells {} pieces, only {} available", required, available)
+            }
+        }
+    }
+}
+
+/// Checks orders against what is known about the instrument and the account
+/// before they are sent. Only the checks for which data was supplied are run:
+/// without `instrument_info` or `orderbook` there are no price limit and
+/// trading status checks, without `balances` or `positions` no funds checks.
+///
+/// Funds checks ignore commission. Limit orders are accepted during the main
+/// session and auctions, market orders only during the main session; statuses
+/// the API does not document pass both checks.
+pub struct OrderValidator<'a> {
+    instrument: &'a Instrument,
+    min_price_increment: Decimal,
+    trade_status: Option<TradingStatus>,
+    limit_up: Option<Decimal>,
+    limit_down: Option<Decimal>,
+    // best ask, the price a market buy is checked against
+    market_price: Option<Decimal>,
+    available_funds: Option<Decimal>,
+    available_position: Option<Decimal>,
+}
+
+impl<'a> OrderValidator<'a> {
+    pub fn new(instrument: &'a Instrument) -> Self {
+        Self {
+            instrument,
+            min_price_increment: instrument.min_price_increment,
+            trade_status: None,
+            limit_up: None,
+            limit_down: None,
+            market_price: None,
+            available_funds: None,
+            available_position: None,
+        }
+    }
+
+    /// Uses the trading status, price limits and price increment of a streaming
+    /// `InstrumentInfo`.
+    pub fn instrument_info(mut self, info: &InstrumentInfo) -> Self {
+        self.trade_status = Some(info.trade_status.clone());
+        self.limit_up = non_zero(info.limit_up);
+        self.limit_down = non_zero(info.limit_down);
+        if !info.min_price_increment.is_zero() {
+            self.min_price_increment = info.min_price_increment;
+        }
+        self
+    }
+
+    /// Uses the trading status, price limits and best ask of an order book.
+    pub fn orderbook(mut self, book: &RestOrderBook) -> Self {
+        self.trade_status = Some(book.trade_status.clone());
+        self.limit_up = non_zero(book.limit_up);
+        self.limit_down = non_zero(book.limit_down);
+        if !book.min_price_increment.is_zero() {
+            self.min_price_increment = book.min_price_increment;
+        }
+        self.market_price = Some(book.asks.first().map_or(book.last_price, |l| l.price));
+        self
+    }
+
+    /// Uses the free balance, balance minus blocked, in the instrument's currency.
+    pub fn balances(mut self, balances: &CurrencyBalances) -> Self {
+        let free = balances
+            .currencies
+            .iter()
+            .find(|c| c.currency == self.instrument.currency)
+            .map_or(Decimal::ZERO, |c| c.balance - c.blocked);
+        self.available_funds = Some(free);
+        self
+    }
+
+    /// Uses the free quantity of the instrument in the portfolio for sells.
+    pub fn positions(mut self, positions: &PositionBalances) -> Self {
+        let free = positions
+            .positions
+            .iter()
+            .find(|p| p.figi == self.instrument.figi)
+            .map_or(Decimal::ZERO, |p| p.balance - p.blocked);
+        self.available_position = Some(free);
+        self
+    }
+
+    pub fn check_limit_order(&self, operation: &OperationType, lots: i64, price: Decimal) -> Vec<Violation> {
+        let mut violations = self.check_common(operation, lots);
+        if price <= Decimal::ZERO {
+            violations.push(Violation::NonPositivePrice(price));
+            return violations;
+        }
+        if !self.min_price_increment.is_zero() && !(price % self.min_price_increment).is_zero() {
+            violations.push(Violation::PriceNotOnTick {
+                price,
+                increment: self.min_price_increment,
+            });
+        }
+        if let Some(limit_up) = self.limit_up.filter(|limit_up| price > *limit_up) {
+            violations.push(Violation::AboveLimitUp { price, limit_up });
+        }
+        if let Some(limit_down) = self.limit_down.filter(|limit_down| price < *limit_down) {
+            violations.push(Violation::BelowLimitDown { price, limit_down });
+        }
+        if let Some(status) = self.trade_status.as_ref().filter(|s| !accepts_limit_orders(s)) {
+            violations.push(Violation::TradingUnavailable(status.clone()));
+        }
+        self.check_funds(operation, lots, Some(price), &mut violations);
+        violations
+    }
+
+    /// The funds check for buys needs an order book to estimate the price.
+    pub fn check_market_order(&self, operation: &OperationType, lots: i64) -> Vec<Violation> {
+        let mut violations = self.check_common(operation, lots);
+        if let Some(status) = self.trade_status.as_ref().filter(|s| !accepts_market_orders(s)) {
+            violations.push(Violation::TradingUnavailable(status.clone()));
+        }
+        let price = self.market_price.filter(|price| !price.is_zero());
+        self.check_funds(operation, lots, price, &mut violations);
+        violations
+    }
+
+    /// Rounds `price` to the instrument's price grid in the direction that never
+    /// makes the order worse: down for buys, up for sells.
+    pub fn round_price(&self, operation: &OperationType, price: Decimal) -> Decimal {
+        match operation {
+            OperationType::Sell => ceil_to_tick(price, self.min_price_increment),
+            _ => floor_to_tick(price, self.min_price_increment),
+        }
+    }
+
+    fn check_common(&self, operation: &OperationType, lots: i64) -> Vec<Violation> {
+        let mut violations = vec![];
+        if *operation != OperationType::Buy && *operation != OperationType::Sell {
+            violations.push(Violation::UnsupportedOperation(operation.clone()));
+        }
+        if lots <= 0 {
+            violations.push(Violation::NonPositiveLots(lots));
+        }
+        violations
+    }
+
+    fn check_funds(&self, operation: &OperationType, lots: i64, price: Option<Decimal>, violations: &mut Vec<Violation>) {
+        if lots <= 0 {
+            return;
+        }
+        let quantity = Decimal::from(lots * self.instrument.lot.max(1));
+        match operation {
+            OperationType::Buy => {
+                if let (Some(available), Some(price)) = (self.available_funds, price) {
+                    let required = price * quantity;
+                    if required > available {
+                        violations.push(Violation::InsufficientFunds {
+                            currency: self.instrument.currency.clone(),
+                            required,
+                            available,
+                        });
+                    }
+                }
+            }
+            OperationType::Sell => {
+                if let Some(available) = self.available_position.filter(|available| quantity > *available) {
+                    violations.push(Violation::InsufficientPosition {
+                        required: quantity,
+                        available,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Largest multiple of `increment` not above `price`. Returns `price` unchanged
+/// for a zero increment.
+pub fn floor_to_tick(price: Decimal, increment: Decimal) -> Decimal {
+    if increment.is_zero() {
+        return price;
+    }
+    (price / increment).floor() * increment
+}
+
+/// Smallest multiple of `increment` not below `price`. Returns `price` unchanged
+/// for a zero increment.
+pub fn ceil_to_tick(price: Decimal, increment: Decimal) -> Decimal {
+    if increment.is_zero() {
+        return price;
+    }
+    (price / increment).ceil() * increment
+}
+
+// Statuses the API does not document are given the benefit of the doubt, so
+// the broker rather than the validator rejects what they do not allow.
+fn accepts_limit_orders(status: &TradingStatus) -> bool {
+    matches!(
+        status,
+        TradingStatus::NormalTrading
+            | TradingStatus::OpeningAuctionPeriod
+            | TradingStatus::ClosingAuction
+            | TradingStatus::DiscreteAuction
+            | TradingStatus::TradingAtClosingAuctionPrice
+            | TradingStatus::Unknown(_)
+    )
+}
+
+fn accepts_market_orders(status: &TradingStatus) -> bool {
+    matches!(status, TradingStatus::NormalTrading | TradingStatus::Unknown(_))
+}
+
+fn non_zero(value: Decimal) -> Option<Decimal> {
+    Some(value).filter(|value| !value.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn instrument() -> Instrument {
+        Instrument {
+            figi: "FIGI".to_string(),
+            ticker: "TICK".to_string(),
+            isin: String::new(),
+            name: "Stock".to_string(),
+            min_price_increment: dec("0.05"),
+            lot: 10,
+            currency: Currency::Rub,
+            r#type: InstrumentType::Stock,
+        }
+    }
+
+    fn info(status: TradingStatus) -> InstrumentInfo {
+        InstrumentInfo {
+            figi: "FIGI".to_string(),
+            trade_status: status,
+            min_price_increment: dec("0.05"),
+            lot: 10.0,
+            accrued_interest: Decimal::ZERO,
+            limit_up: dec("110"),
+            limit_down: dec("90"),
+        }
+    }
+
+    #[test]
+    fn trading_status_policy_is_shared() {
+        let instrument = instrument();
+        let check = |status: TradingStatus| {
+            let validator = OrderValidator::new(&instrument).instrument_info(&info(status));
+            let limit = validator.check_limit_order(&OperationType::Buy, 1, dec("100")).is_empty();
+            let market = validator.check_market_order(&OperationType::Buy, 1).is_empty();
+            (limit, market)
+        };
+        assert_eq!(check(TradingStatus::NormalTrading), (true, true));
+        assert_eq!(check(TradingStatus::Unknown("new_status".to_string())), (true, true));
+        assert_eq!(check(TradingStatus::OpeningAuctionPeriod), (true, false));
+        assert_eq!(check(TradingStatus::BreakInTrading), (false, false));
+    }
+
+    #[test]
+    fn limit_order_price_checks() {
+        let instrument = instrument();
+        let validator = OrderValidator::new(&instrument).instrument_info(&info(TradingStatus::NormalTrading));
+        assert_eq!(
+            validator.check_limit_order(&OperationType::Buy, 1, dec("100.02")),
+            vec![Violation::PriceNotOnTick {
+                price: dec("100.02"),
+                increment: dec("0.05"),
+            }]
+        );
+        assert_eq!(
+            validator.check_limit_order(&OperationType::Sell, 0, dec("111")),
+            vec![
+                Violation::NonPositiveLots(0),
+                Violation::AboveLimitUp {
+                    price: dec("111"),
+                    limit_up: dec("110"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn funds_checks_use_pieces() {
+        let instrument = instrument();
+        let balances = CurrencyBalances {
+            currencies: vec![CurrencyBalance {
+                currency: Currency::Rub,
+                balance: dec("1500"),
+                blocked: dec("500"),
+            }],
+        };
+        let validator = OrderValidator::new(&instrument).balances(&balances);
+        assert!(validator.check_limit_order(&OperationType::Buy, 1, dec("100")).is_empty());
+        assert_eq!(
+            validator.check_limit_order(&OperationType::Buy, 2, dec("100")),
+            vec![Violation::InsufficientFunds {
+                currency: Currency::Rub,
+                required: dec("2000"),
+                available: dec("1000"),
+            }]
+        );
+    }
+
+    #[test]
+    fn rounds_prices_in_the_safe_direction() {
+        let instrument = instrument();
+        let validator = OrderValidator::new(&instrument);
+        assert_eq!(validator.round_price(&OperationType::Buy, dec("100.07")), dec("100.05"));
+        assert_eq!(validator.round_price(&OperationType::Sell, dec("100.07")), dec("100.10"));
+        assert_eq!(floor_to_tick(dec("1.23"), Decimal::ZERO), dec("1.23"));
+    }
+}
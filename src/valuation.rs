@@ -0,0 +1,427 @@
+// What a portfolio is worth in one currency.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+use chrono::{Duration, Utc};
+
+use crate::*;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Neither the order book nor recent candles give a price for the instrument.
+    NoPrice(String),
+    /// No currency instrument quotes the currency against the ruble.
+    NoRate(Currency),
+    Fetch(rest_client::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoPrice(figi) => write!(f, "no price for {}", figi),
+            Error::NoRate(currency) => write!(f, "no exchange rate for {}", currency),
+            Error::Fetch(error) => write!(f, "failed to fetch market data: {}", error),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Fetch(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<rest_client::Error> for Error {
+    fn from(error: rest_client::Error) -> Self {
+        Error::Fetch(error)
+    }
+}
+
+/// A security position priced in its own currency and in the base currency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionValue {
+    pub figi: String,
+    pub ticker: String,
+    pub instrument_type: InstrumentType,
+    pub currency: Currency,
+    /// Pieces held, including blocked ones.
+    pub quantity: Decimal,
+    /// Price per piece; for bonds the percentage quote times the face value.
+    pub price: Decimal,
+    pub value: Decimal,
+    pub base_value: Decimal,
+    /// Share of the total value, between 0 and 1 for long positions.
+    pub weight: Decimal,
+}
+
+/// Everything held in one currency: cash plus securities priced in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyExposure {
+    pub currency: Currency,
+    pub cash: Decimal,
+    pub securities: Decimal,
+    /// Base currency units per unit of `currency`.
+    pub rate: Decimal,
+    pub base_value: Decimal,
+    pub weight: Decimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Valuation {
+    pub base: Currency,
+    pub total: Decimal,
+    pub positions: Vec<PositionValue>,
+    /// Sorted by base value, largest first.
+    pub exposures: Vec<CurrencyExposure>,
+}
+
+impl Valuation {
+    pub fn position(&self, figi: &str) -> Option<&PositionValue> {
+        self.positions.iter().find(|p| p.figi == figi)
+    }
+
+    pub fn exposure(&self, currency: &Currency) -> Option<&CurrencyExposure> {
+        self.exposures.iter().find(|e| e.currency == *currency)
+    }
+}
+
+/// Values a `Portfolio` in a base currency.
+///
+/// Prices are the last price from a depth 1 order book, falling back to the
+/// close of the latest daily candle of the past two weeks. Exchange rates are
+/// the prices of the ruble currency instruments from `currencies`, such as
+/// `USD000UTSTOM`; rates set with `rate` take precedence.
+///
+/// Currency positions (`InstrumentType::Currency`) repeat the currency
+/// balances and are skipped; cash is valued from the balances. Accrued bond
+/// interest is not included.
+pub struct PortfolioValuer {
+    base: Currency,
+    rates: HashMap<Currency, Decimal>,
+}
+
+impl PortfolioValuer {
+    pub fn new(base: Currency) -> Self {
+        Self {
+            base,
+            rates: HashMap::new(),
+        }
+    }
+
+    /// Sets the ruble price of one unit of `currency` instead of looking it up.
+    pub fn rate(mut self, currency: Currency, rub: Decimal) -> Self {
+        self.rates.insert(currency, rub);
+        self
+    }
+
+    pub fn value<B: BrokerApi + ?Sized>(&self, broker: &B, portfolio: &Portfolio) -> Result<Valuation> {
+        let mut rates = self.rates.clone();
+        rates.insert(Currency::Rub, Decimal::ONE);
+
+        let mut positions = vec![];
+        for balance in &portfolio.positions.positions {
+            if balance.instrument_type == InstrumentType::Currency {
+                continue;
+            }
+            let currency = match balance.average_position_price.as_ref() {
+                Some(average) => average.currency.clone(),
+                None => broker.instrument_by_figi(&balance.figi)?.currency,
+            };
+            let price = price(broker, &balance.figi, &balance.instrument_type)?;
+            positions.push(PositionValue {
+                figi: balance.figi.clone(),
+                ticker: balance.ticker.clone(),
+                instrument_type: balance.instrument_type.clone(),
+                currency,
+                quantity: balance.balance,
+                price,
+                value: price * balance.balance,
+                base_value: Decimal::ZERO,
+                weight: Decimal::ZERO,
+            });
+        }
+
+        let mut exposures: Vec<CurrencyExposure> = vec![];
+        let cash = portfolio.currencies.currencies.iter().map(|c| (&c.currency, c.balance, Decimal::ZERO));
+        let securities = positions.iter().map(|p| (&p.currency, Decimal::ZERO, p.value));
+        for (currency, cash, securities) in cash.chain(securities) {
+            match exposures.iter_mut().find(|e| e.currency == *currency) {
+                Some(exposure) => {
+                    exposure.cash += cash;
+                    exposure.securities += securities;
+                }
+                None => exposures.push(CurrencyExposure {
+                    currency: currency.clone(),
+                    cash,
+                    securities,
+                    rate: Decimal::ZERO,
+                    base_value: Decimal::ZERO,
+                    weight: Decimal::ZERO,
+                }),
+            }
+        }
+
+        let missing: Vec<Currency> = exposures
+            .iter()
+            .map(|e| e.currency.clone())
+            .chain(std::iter::once(self.base.clone()))
+            .filter(|c| !rates.contains_key(c))
+            .collect();
+        if !missing.is_empty() {
+            for instrument in broker.currencies()?.instruments {
+                let currency = currency_of(&instrument);
+                if missing.contains(&currency) && !rates.contains_key(&currency) {
+                    let rub = price(broker, &instrument.figi, &instrument.r#type)?;
+                    rates.insert(currency, rub);
+                }
+            }
+        }
+        let base_rate = *rates.get(&self.base).ok_or_else(|| Error::NoRate(self.base.clone()))?;
+        let to_base = |currency: &Currency| {
+            rates
+                .get(currency)
+                .map(|rub| *rub / base_rate)
+                .ok_or_else(|| Error::NoRate(currency.clone()))
+        };
+
+        for exposure in &mut exposures {
+            exposure.rate = to_base(&exposure.currency)?;
+            exposure.base_value = (exposure.cash + exposure.securities) * exposure.rate;
+        }
+        for position in &mut positions {
+            position.base_value = position.value * to_base(&position.currency)?;
+        }
+        let total: Decimal = exposures.iter().map(|e| e.base_value).sum();
+        if !total.is_zero() {
+            for exposure in &mut exposures {
+                exposure.weight = exposure.base_value / total;
+            }
+            for position in &mut positions {
+                position.weight = position.base_value / total;
+            }
+        }
+        exposures.sort_by_key(|e| std::cmp::Reverse(e.base_value));
+
+        Ok(Valuation {
+            base: self.base.clone(),
+            total,
+            positions,
+            exposures,
+        })
+    }
+}
+
+// Price of one piece in the instrument's currency.
+fn price<B: BrokerApi + ?Sized>(broker: &B, figi: &str, instrument_type: &InstrumentType) -> Result<Decimal> {
+    let book = broker.orderbook(1, figi)?;
+    let mut price = book.last_price;
+    if price.is_zero() {
+        let to = Utc::now();
+        let candles = broker.candles(to - Duration::days(14), to, CandleInterval::Day1, figi)?;
+        price = candles
+            .iter()
+            .max_by_key(|c| c.ts)
+            .map(|c| c.close_price)
+            .ok_or_else(|| Error::NoPrice(figi.to_string()))?;
+    }
+    if *instrument_type == InstrumentType::Bond && !book.face_value.is_zero() {
+        // bonds are quoted in percent of the face value
+        price = price * book.face_value / Decimal::ONE_HUNDRED;
+    }
+    Ok(price)
+}
+
+// Currency instruments are tickered after the currency they buy, e.g.
+// USD000UTSTOM or EUR_RUB__TOM.
+fn currency_of(instrument: &Instrument) -> Currency {
+    Currency::from(instrument.ticker.get(..3).unwrap_or(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use url::Url;
+
+    use super::*;
+    use crate::rest_client::{HttpRequest, HttpResponse, RestClient, RetryPolicy, Transport};
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    // Serves order books, with last price and face value, and daily closes by figi.
+    struct Market {
+        books: Vec<(&'static str, &'static str, &'static str)>,
+        closes: Vec<(&'static str, &'static str)>,
+    }
+
+    impl Transport for Market {
+        fn send(&self, request: &HttpRequest) -> rest_client::Result<HttpResponse> {
+            let figi = query(&request.url, "figi");
+            let path = request.url.path();
+            let response = if path.ends_with("market/orderbook") {
+                match self.books.iter().find(|(f, _, _)| *f == figi) {
+                    Some((_, last, face)) => HttpResponse::ok(&json!({
+                        "figi": figi,
+                        "depth": 1,
+                        "bids": [],
+                        "asks": [],
+                        "tradeStatus": "normal_trading",
+                        "minPriceIncrement": 0.01,
+                        "lastPrice": dec(last),
+                        "faceValue": dec(face),
+                    })),
+                    None => HttpResponse::api_error(404, "NOT_FOUND", "no book"),
+                }
+            } else if path.ends_with("market/candles") {
+                let candles: Vec<_> = self
+                    .closes
+                    .iter()
+                    .filter(|(f, _)| *f == figi)
+                    .map(|(_, close)| {
+                        let close = dec(close);
+                        json!({"figi": figi, "interval": "day", "o": close, "c": close, "h": close, "l": close,
+                               "v": 1, "time": Utc::now() - Duration::days(1)})
+                    })
+                    .collect();
+                HttpResponse::ok(&json!({"figi": figi, "interval": "day", "candles": candles}))
+            } else if path.ends_with("market/currencies") {
+                HttpResponse::ok(&json!({"instruments": [{
+                    "figi": "USDFIGI",
+                    "ticker": "USD000UTSTOM",
+                    "name": "Dollar",
+                    "lot": 1000,
+                    "currency": "RUB",
+                    "type": "currency",
+                }]}))
+            } else {
+                HttpResponse::api_error(404, "NOT_FOUND", path)
+            };
+            Ok(response)
+        }
+    }
+
+    fn query(url: &Url, name: &str) -> String {
+        url.query_pairs().find(|(n, _)| n == name).map(|(_, v)| v.into_owned()).unwrap_or_default()
+    }
+
+    fn broker() -> RestClient {
+        let market = Market {
+            books: vec![
+                ("SBER", "250", "0"),
+                ("GAZP", "0", "0"),
+                ("BOND", "101.5", "1000"),
+                ("USDFIGI", "75", "0"),
+                ("HALTED", "0", "0"),
+            ],
+            closes: vec![("GAZP", "200")],
+        };
+        RestClient::builder("token".to_string())
+            .transport(market)
+            .retry_policy(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn position(figi: &str, instrument_type: InstrumentType, currency: Currency, balance: i64) -> PositionBalance {
+        PositionBalance {
+            figi: figi.to_string(),
+            ticker: figi.to_string(),
+            isin: String::new(),
+            instrument_type,
+            balance: Decimal::from(balance),
+            blocked: Decimal::ZERO,
+            lots: balance,
+            expected_yield: None,
+            average_position_price: Some(MoneyAmount {
+                currency,
+                value: Decimal::ONE,
+            }),
+            average_position_price_no_nkd: None,
+            name: figi.to_string(),
+        }
+    }
+
+    fn portfolio(cash: &[(Currency, &str)]) -> Portfolio {
+        Portfolio {
+            positions: PositionBalances {
+                positions: vec![
+                    position("SBER", InstrumentType::Stock, Currency::Rub, 10),
+                    position("GAZP", InstrumentType::Stock, Currency::Rub, 5),
+                    position("BOND", InstrumentType::Bond, Currency::Usd, 2),
+                    position("USDFIGI", InstrumentType::Currency, Currency::Rub, 100),
+                ],
+            },
+            currencies: CurrencyBalances {
+                currencies: cash
+                    .iter()
+                    .map(|(currency, balance)| CurrencyBalance {
+                        currency: currency.clone(),
+                        balance: dec(balance),
+                        blocked: Decimal::ZERO,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    #[test]
+    fn values_positions_and_cash_in_rubles() {
+        let portfolio = portfolio(&[(Currency::Rub, "1500"), (Currency::Usd, "100")]);
+        let valuation = PortfolioValuer::new(Currency::Rub).value(&broker(), &portfolio).unwrap();
+
+        // bonds are quoted in percent of the face value
+        let bond = valuation.position("BOND").unwrap();
+        assert_eq!((bond.price, bond.value, bond.base_value), (dec("1015"), dec("2030"), dec("152250")));
+        // no last price, so the latest daily close is used
+        assert_eq!(valuation.position("GAZP").unwrap().value, dec("1000"));
+        assert!(valuation.position("USDFIGI").is_none());
+
+        assert_eq!(valuation.total, dec("164750"));
+        let usd = &valuation.exposures[0];
+        assert_eq!((usd.currency.clone(), usd.cash, usd.securities), (Currency::Usd, dec("100"), dec("2030")));
+        assert_eq!((usd.rate, usd.base_value), (dec("75"), dec("159750")));
+        let rub = valuation.exposure(&Currency::Rub).unwrap();
+        assert_eq!((rub.cash, rub.securities, rub.base_value), (dec("1500"), dec("3500"), dec("5000")));
+        let weights: Decimal = valuation.exposures.iter().map(|e| e.weight).sum();
+        assert_eq!(weights.round_dp(10), Decimal::ONE);
+    }
+
+    #[test]
+    fn values_in_another_base_with_set_rates() {
+        let portfolio = portfolio(&[(Currency::Usd, "100")]);
+        let valuation = PortfolioValuer::new(Currency::Usd)
+            .rate(Currency::Usd, dec("80"))
+            .value(&broker(), &portfolio)
+            .unwrap();
+        // 3500 RUB of stocks at 80 plus 2130 USD
+        assert_eq!(valuation.total, dec("2173.75"));
+        assert_eq!(valuation.exposure(&Currency::Rub).unwrap().rate, dec("0.0125"));
+        assert_eq!(valuation.position("SBER").unwrap().base_value, dec("31.25"));
+    }
+
+    #[test]
+    fn missing_rates_and_prices_are_errors() {
+        let portfolio = portfolio(&[(Currency::Eur, "10")]);
+        let error = PortfolioValuer::new(Currency::Rub).value(&broker(), &portfolio).unwrap_err();
+        assert!(matches!(error, Error::NoRate(Currency::Eur)));
+
+        let mut portfolio = portfolio;
+        portfolio.currencies.currencies.clear();
+        portfolio.positions.positions.push(position("HALTED", InstrumentType::Stock, Currency::Rub, 1));
+        let error = PortfolioValuer::new(Currency::Rub).value(&broker(), &portfolio).unwrap_err();
+        assert!(matches!(error, Error::NoPrice(ref figi) if figi == "HALTED"));
+
+        portfolio.positions.positions.pop();
+        portfolio.positions.positions.push(position("NOBOOK", InstrumentType::Stock, Currency::Rub, 1));
+        let error = PortfolioValuer::new(Currency::Rub).value(&broker(), &portfolio).unwrap_err();
+        assert!(matches!(error, Error::Fetch(_)));
+    }
+}